@@ -1,14 +1,29 @@
-use std::{collections::HashMap, env::current_dir, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    env::current_dir,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use clap::{Parser, Subcommand};
 use eyre::eyre;
 use inquire::Confirm;
-use kenchiku_common::Context;
+use kenchiku_common::{
+    ConflictStrategy, Context, Limits, PromptValueFn, ValidatorFn,
+    meta::{ValueMeta, find_value_meta, join_list, split_list},
+    values::read_values_file,
+};
 use kenchiku_scaffold::{
-    Scaffold,
+    PatchStatus, Scaffold,
+    changes::ChangePlan,
     discovery::{discover_scaffold, find_all_scaffolds},
+    manifest::{MANIFEST_PATH, Manifest},
+    patch_set::PatchSet,
+    replay::{Replay, was_prompted},
 };
-use tracing::info;
+use tracing::{info, warn};
 use tracing_subscriber::{EnvFilter, FmtSubscriber};
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -19,6 +34,17 @@ struct Cli {
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 
+    /// Maximum number of Lua function calls and loop iterations a scaffold may run, 0 disables
+    /// the limit.
+    #[arg(long, global = true, value_name = "COUNT")]
+    instruction_limit: Option<u64>,
+    /// Maximum memory a scaffold may use in MiB, 0 disables the limit.
+    #[arg(long, global = true, value_name = "MIB")]
+    memory_limit: Option<usize>,
+    /// Maximum time in seconds a scaffold may run (including prompts), 0 disables the limit.
+    #[arg(long, global = true, value_name = "SECONDS")]
+    timeout: Option<u64>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -48,26 +74,107 @@ pub enum Commands {
         /// Auto confirm actions, use multiple times to auto confirm more dangerous actions.
         #[arg(short, long, action = clap::ArgAction::Count)]
         confirm_all: u8,
+        /// Never prompt for values, fail with a list of every missing value instead. Implied if
+        /// stdin is not a terminal.
+        #[arg(long)]
+        no_input: bool,
         /// Force will overwrite existing files in the output path.
         #[arg(short, long)]
         force: bool,
+        /// What to do with files which already exist in the output path: ask, overwrite, keep,
+        /// new (write as <file>.kenchiku-new) or merge (add conflict markers). Defaults to ask
+        /// in interactive terminals and keep otherwise (or with --no-input).
+        #[arg(long, value_name = "STRATEGY")]
+        on_conflict: Option<ConflictStrategy>,
         /// Values to set before running. Can be repeated.
         #[arg(short('s'), long("set"), value_name = "VALUE")]
         values: Vec<String>,
+        /// JSON, YAML or TOML file with values to set before running. `--set` takes precedence
+        /// over it, it over `KENCHIKU_VAL_*` environment variables.
+        #[arg(long, value_name = "PATH")]
+        values_file: Option<PathBuf>,
+        /// Replay file saved after an earlier construct, to run it again with the same answers.
+        /// Environment variables, `--values-file` and `--set` take precedence over it.
+        #[arg(long, value_name = "PATH")]
+        replay: Option<PathBuf>,
+        /// Ask for all values up front and confirm them before running, like scaffolds with
+        /// `eager = true`.
+        #[arg(long)]
+        eager: bool,
+        /// Only show which files would be created or modified, without touching the output path.
+        #[arg(long)]
+        dry_run: bool,
+        /// Output the dry run changes in JSON format
+        #[arg(long, requires = "dry_run")]
+        json: bool,
     },
     /// Runs a patch of a scaffold
     Patch {
-        /// Patch to run, in the format of "<scaffold>:<patch_name>", for example
-        /// "utils:add_logging"
-        patch: String,
-        /// The path where the patch will run. Defaults to the current directory.
+        /// Patches to run, in the format of "<scaffold>:<patch_name>", for example
+        /// "utils:add_logging". Patches they require run first, all of them are applied at once.
+        #[arg(required_unless_present = "list_applicable")]
+        patches: Vec<String>,
+        /// The path where the patches will run. Defaults to the current directory.
+        #[arg(short, long)]
         output: Option<String>,
         /// Auto confirm actions, use multiple times to auto confirm more dangerous actions.
         #[arg(short, long, action = clap::ArgAction::Count)]
         confirm_all: u8,
+        /// Never prompt for values, fail with a list of every missing value instead. Implied if
+        /// stdin is not a terminal.
+        #[arg(long)]
+        no_input: bool,
         /// Values to set before running. Can be repeated.
         #[arg(short('s'), long("set"), value_name = "VALUE")]
         values: Vec<String>,
+        /// JSON, YAML or TOML file with values to set before running. `--set` takes precedence
+        /// over it, it over `KENCHIKU_VAL_*` environment variables.
+        #[arg(long, value_name = "PATH")]
+        values_file: Option<PathBuf>,
+        /// Ask for all values up front and confirm them before running, like scaffolds with
+        /// `eager = true`.
+        #[arg(long)]
+        eager: bool,
+        /// Only show which files would be created, modified or deleted, without touching the
+        /// project.
+        #[arg(long)]
+        dry_run: bool,
+        /// Output the dry run changes in JSON format
+        #[arg(long, requires = "dry_run")]
+        json: bool,
+        /// List the patches of all scaffolds which apply to the project in the output path,
+        /// instead of running one.
+        #[arg(long, conflicts_with = "patches")]
+        list_applicable: bool,
+    },
+    /// Updates a project to the current version of the scaffold which generated it
+    ///
+    /// Runs the construct function again with the values recorded in the manifest and merges the
+    /// results into the project.
+    Update {
+        /// The project to update. Defaults to the current directory.
+        output: Option<String>,
+        /// Scaffold to use instead of the one recorded in the manifest, either name or path.
+        #[arg(long)]
+        scaffold: Option<String>,
+        /// Auto confirm actions, use multiple times to auto confirm more dangerous actions.
+        #[arg(short, long, action = clap::ArgAction::Count)]
+        confirm_all: u8,
+        /// Never prompt for values, fail with a list of every missing value instead. Implied if
+        /// stdin is not a terminal.
+        #[arg(long)]
+        no_input: bool,
+        /// Values to set before running, overriding the ones recorded in the manifest. Can be
+        /// repeated.
+        #[arg(short('s'), long("set"), value_name = "VALUE")]
+        values: Vec<String>,
+        /// Only show which files would be created, modified or deleted, without touching the
+        /// project.
+        #[arg(long)]
+        dry_run: bool,
+        /// Output the dry run changes in JSON format
+        #[arg(long, requires = "dry_run")]
+        json: bool,
     },
     /// Starts the MCP server (stdio)
     Mcp,
@@ -102,19 +209,23 @@ fn main() -> eyre::Result<()> {
 
     info!(VERSION, "Kenchiku running");
 
-    let prompt_value = Arc::new(
+    let load_limits = cli.limits(Limits::LOAD);
+    let run_limits = cli.limits(Limits::RUN);
+
+    let prompt_value: PromptValueFn = Arc::new(
         |_name: String,
          value_type: String,
          description: String,
          choices: Option<Vec<String>>,
          default: Option<String>,
-         validator: Option<Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync + 'static>>|
+         validator: Option<ValidatorFn>|
          -> eyre::Result<String> {
             Ok(match value_type.as_str() {
                 "enum" => {
                     let choices =
                         choices.ok_or_else(|| eyre::eyre!("choices required for enum"))?;
-                    let mut select = inquire::Select::new(&description, choices.clone());
+                    let mut select =
+                        inquire::Select::new(print_details(&description), choices.clone());
                     if let Some(def) = &default {
                         let cursor = choices
                             .iter()
@@ -124,6 +235,49 @@ fn main() -> eyre::Result<()> {
                     }
                     select.prompt()?
                 }
+                "multiselect" => {
+                    let choices =
+                        choices.ok_or_else(|| eyre::eyre!("choices required for multiselect"))?;
+                    let defaults: Vec<usize> = default
+                        .as_deref()
+                        .map(split_list)
+                        .unwrap_or_default()
+                        .iter()
+                        .filter_map(|def| choices.iter().position(|choice| choice == def))
+                        .collect();
+                    let mut select =
+                        inquire::MultiSelect::new(print_details(&description), choices)
+                            .with_default(&defaults);
+                    if let Some(validator) = validator {
+                        select = select.with_validator(
+                            move |selected: &[inquire::list_option::ListOption<&String>]| {
+                                let joined = join_list(selected.iter().map(|option| option.value));
+                                Ok(match validator(&joined) {
+                                    Ok(_) => inquire::validator::Validation::Valid,
+                                    Err(e) => inquire::validator::Validation::Invalid(
+                                        inquire::validator::ErrorMessage::Custom(e),
+                                    ),
+                                })
+                            },
+                        );
+                    }
+                    join_list(select.prompt()?)
+                }
+                "secret" => {
+                    let mut password = inquire::Password::new(&description)
+                        .with_display_mode(inquire::PasswordDisplayMode::Masked)
+                        .without_confirmation();
+                    if let Some(validator) = validator {
+                        password =
+                            password.with_validator(move |input: &str| match validator(input) {
+                                Ok(_) => Ok(inquire::validator::Validation::Valid),
+                                Err(e) => Ok(inquire::validator::Validation::Invalid(
+                                    inquire::validator::ErrorMessage::Custom(e),
+                                )),
+                            });
+                    }
+                    password.prompt()?
+                }
                 "bool" => {
                     let msg = format!("{} (y/n)", description);
                     let mut query = inquire::Confirm::new(&msg);
@@ -137,7 +291,10 @@ fn main() -> eyre::Result<()> {
                 _ => {
                     let mut text = inquire::Text::new(&description);
                     if let Some(def) = &default {
-                        text = text.with_default(&def).with_placeholder(&def);
+                        text = text.with_default(def).with_placeholder(def);
+                    }
+                    if value_type == "list" {
+                        text = text.with_help_message("separate multiple entries with commas");
                     }
 
                     if let Some(validator) = validator {
@@ -161,7 +318,7 @@ fn main() -> eyre::Result<()> {
         } => {
             let scaffold_path =
                 discover_scaffold(scaffold_name).ok_or(eyre!("Scaffold not found"))?;
-            let scaffold = Scaffold::load(scaffold_path)?;
+            let scaffold = Scaffold::load_with_limits(scaffold_path, load_limits)?;
             if json {
                 println!("{}", serde_json::to_string_pretty(&scaffold)?);
             } else {
@@ -172,7 +329,7 @@ fn main() -> eyre::Result<()> {
         Commands::List { json } => {
             let found_scaffolds = find_all_scaffolds()
                 .iter()
-                .map(|path| Scaffold::load(path.to_path_buf()))
+                .map(|path| Scaffold::load_with_limits(path.to_path_buf(), load_limits))
                 .collect::<eyre::Result<Vec<Scaffold>>>()?;
             if json {
                 println!("{}", serde_json::to_string_pretty(&found_scaffolds)?);
@@ -190,96 +347,190 @@ fn main() -> eyre::Result<()> {
             scaffold: scaffold_name,
             output,
             confirm_all,
+            no_input,
             force,
+            on_conflict,
             values,
+            values_file,
+            replay,
+            eager,
+            dry_run,
+            json,
         } => {
-            info!(scaffold_name, ?values, "Starting construction...");
+            info!(scaffold_name, values = ?value_names(&values), "Starting construction...");
             let scaffold_path =
                 discover_scaffold(scaffold_name).ok_or(eyre!("Scaffold not found"))?;
-            let scaffold = Scaffold::load(scaffold_path)?;
+            let scaffold = Scaffold::load_with_limits(scaffold_path, load_limits)?;
             let out_path = output.map(PathBuf::from).unwrap_or(current_dir()?);
+            let replayed_values = match replay {
+                Some(path) => {
+                    let replay = Replay::load(&path)?;
+                    if replay.scaffold != scaffold.name {
+                        warn!(
+                            replay = replay.scaffold,
+                            scaffold = scaffold.name,
+                            "Replay file was recorded for another scaffold"
+                        );
+                    }
+                    replay.values
+                }
+                None => Default::default(),
+            };
+            let no_input = no_input || !std::io::stdin().is_terminal();
             let mut temp_dir = tempfile::tempdir()?;
             let context = Context {
                 working_dir: temp_dir.path().to_path_buf(),
                 confirm_all,
                 output: out_path,
                 scaffold_dir: scaffold.path.clone(),
-                confirm_fn: Arc::new(|message: String| {
-                    // TODO: handle ctrl c
-                    Ok(Confirm::new(&message).with_default(false).prompt()?)
-                }),
+                confirm_fn: confirm_unless(no_input),
+                select_fn: if no_input {
+                    Arc::new(|_, _| Err(eyre!("Can't ask what to do, input is disabled")))
+                } else {
+                    Arc::new(select)
+                },
                 allow_overwrite: force,
-                values_meta: scaffold.meta.values.clone(),
-                values: kenchiku_common::get_env_values()
+                on_conflict: on_conflict.unwrap_or(if no_input {
+                    ConflictStrategy::Keep
+                } else {
+                    ConflictStrategy::Ask
+                }),
+                values: replayed_values
                     .into_iter()
-                    .chain(
-                        values
-                            .iter()
-                            .map(|val| {
-                                val.split_once("=")
-                                    .map(|vals| (vals.0.to_string(), vals.1.to_string()))
-                                    .ok_or_else(|| eyre!("Invalid value format: {}", val))
-                            })
-                            .collect::<Result<HashMap<String, String>, _>>()?,
-                    )
+                    .chain(parse_values(
+                        &values,
+                        values_file.as_deref(),
+                        &scaffold.meta.values,
+                    )?)
                     .collect(),
-                prompt_value,
+                values_meta: scaffold.meta.values.clone(),
+                prompt_value: prompt_value_unless(prompt_value, no_input),
+                no_input,
+                limits: run_limits,
+                eager,
+                ..Default::default()
             };
-            scaffold.construct(context)?;
+            if dry_run {
+                let plan = scaffold.plan_construct(context)?;
+                print_plan(&plan, json)?;
+                return Ok(());
+            }
+            let name = scaffold.name.clone();
+            scaffold.construct(context.clone())?;
             // only disable cleanup if we constructed successfully
             temp_dir.disable_cleanup(true);
+            if !context.no_input && was_prompted(&context) {
+                offer_replay(&name, &context)?;
+            }
         }
         Commands::Patch {
-            patch,
+            patches,
+            list_applicable,
             output,
             confirm_all,
+            no_input,
             values,
+            values_file,
+            eager,
+            dry_run,
+            json,
+        } => {
+            if list_applicable {
+                let project_dir = output.map(PathBuf::from).unwrap_or(current_dir()?);
+                return print_applicable_patches(&project_dir, load_limits);
+            }
+            let patch_set = PatchSet::resolve(&patches, |name| {
+                let scaffold_path = discover_scaffold(name.to_string())
+                    .ok_or(eyre!("Scaffold '{name}' not found"))?;
+                Scaffold::load_with_limits(scaffold_path, load_limits)
+            })?;
+            info!(
+                patches = ?patch_set.ids(),
+                values = ?value_names(&values),
+                "Starting patching..."
+            );
+            let out_path = output.map(PathBuf::from).unwrap_or(current_dir()?);
+            let values_meta = patch_set.values_meta();
+            let no_input = no_input || !std::io::stdin().is_terminal();
+            let context = Context {
+                // patches run in a staging copy of the output, see PatchSet::apply
+                working_dir: out_path.clone(),
+                confirm_all,
+                output: out_path,
+                confirm_fn: confirm_unless(no_input),
+                values: parse_values(&values, values_file.as_deref(), &values_meta)?,
+                values_meta,
+                prompt_value: prompt_value_unless(prompt_value, no_input),
+                no_input,
+                limits: run_limits,
+                eager,
+                ..Default::default()
+            };
+            if dry_run {
+                let plan = patch_set.plan(context)?;
+                print_plan(&plan, json)?;
+                return Ok(());
+            }
+            let plan = patch_set.apply(context)?;
+            plan.print(&mut std::io::stdout())?;
+        }
+        Commands::Update {
+            output,
+            scaffold: scaffold_name,
+            confirm_all,
+            no_input,
+            values,
+            dry_run,
+            json,
         } => {
-            let mut split = patch.split(":");
-            let scaffold_name = split
-                .next()
-                .ok_or(eyre!("no scaffold name found in {}", patch))?;
-            let patch_name = split.next().ok_or(eyre!(
-                "no patch name found in {}, did you use the format '<scaffold>:<patch>'?",
-                patch
-            ))?;
-            info!(scaffold_name, patch_name, ?values, "Starting patching...");
-            let scaffold_path =
-                discover_scaffold(scaffold_name.to_string()).ok_or(eyre!("Scaffold not found"))?;
-            let scaffold = Scaffold::load(scaffold_path)?;
             let out_path = output.map(PathBuf::from).unwrap_or(current_dir()?);
+            let manifest = Manifest::load(&out_path)?;
+            let generation = manifest.last_generation().ok_or(eyre!(
+                "No construct recorded in {}, was this project generated by Kenchiku?",
+                out_path.join(MANIFEST_PATH).display()
+            ))?;
+            info!(
+                scaffold = generation.scaffold,
+                values = ?value_names(&values),
+                "Starting update..."
+            );
+            let scaffold_path = match scaffold_name {
+                Some(name) => discover_scaffold(name),
+                None if generation.source.exists() => Some(generation.source.clone()),
+                None => discover_scaffold(generation.scaffold.clone()),
+            }
+            .ok_or(eyre!("Scaffold not found"))?;
+            let scaffold = Scaffold::load_with_limits(scaffold_path, load_limits)?;
+            let no_input = no_input || !std::io::stdin().is_terminal();
+            let temp_dir = tempfile::tempdir()?;
             let context = Context {
-                working_dir: current_dir()?,
+                working_dir: temp_dir.path().to_path_buf(),
                 confirm_all,
                 output: out_path,
                 scaffold_dir: scaffold.path.clone(),
-                confirm_fn: Arc::new(|message: String| {
-                    Ok(Confirm::new(&message).with_default(false).prompt()?)
-                }),
-                values_meta: scaffold
-                    .meta
-                    .patches
-                    .get(patch_name)
-                    .expect("patch to exist here")
+                confirm_fn: confirm_unless(no_input),
+                values_meta: scaffold.meta.values.clone(),
+                values: generation
                     .values
-                    .clone(),
-                values: kenchiku_common::get_env_values()
+                    .clone()
                     .into_iter()
-                    .chain(
-                        values
-                            .iter()
-                            .map(|val| {
-                                val.split_once("=")
-                                    .map(|vals| (vals.0.to_string(), vals.1.to_string()))
-                                    .ok_or_else(|| eyre!("Invalid value format: {}", val))
-                            })
-                            .collect::<Result<HashMap<String, String>, _>>()?,
-                    )
+                    .chain(parse_values(&values, None, &scaffold.meta.values)?)
                     .collect(),
-                prompt_value,
+                prompt_value: prompt_value_unless(prompt_value, no_input),
+                no_input,
+                limits: run_limits,
                 ..Default::default()
             };
-            scaffold.call_patch(patch_name, context)?;
+            let update = if dry_run {
+                scaffold.plan_update(context)?
+            } else {
+                scaffold.update(context)?
+            };
+            if json {
+                println!("{}", serde_json::to_string_pretty(&update)?);
+            } else {
+                update.print(&mut std::io::stdout())?;
+            }
         }
         Commands::Mcp => {
             kenchiku_mcp::server::run_blocking()?;
@@ -288,7 +539,7 @@ fn main() -> eyre::Result<()> {
             if scaffolds {
                 let found_scaffolds = find_all_scaffolds();
                 for path in found_scaffolds {
-                    if let Ok(scaffold) = Scaffold::load(path) {
+                    if let Ok(scaffold) = Scaffold::load_with_limits(path, load_limits) {
                         println!("{}\t{}", scaffold.name, scaffold.meta.description);
                     }
                 }
@@ -296,7 +547,7 @@ fn main() -> eyre::Result<()> {
             if patches {
                 let found_scaffolds = find_all_scaffolds();
                 for path in found_scaffolds {
-                    if let Ok(scaffold) = Scaffold::load(path) {
+                    if let Ok(scaffold) = Scaffold::load_with_limits(path, load_limits) {
                         for patch in scaffold.meta.patches {
                             println!("{}:{}\t{}", scaffold.name, patch.0, patch.1.description);
                         }
@@ -308,3 +559,193 @@ fn main() -> eyre::Result<()> {
 
     Ok(())
 }
+
+impl Cli {
+    /// Overrides `defaults` with the limits passed on the command line.
+    fn limits(&self, defaults: Limits) -> Limits {
+        Limits {
+            instructions: override_limit(self.instruction_limit, defaults.instructions),
+            memory: override_limit(
+                self.memory_limit.map(|mib| mib * 1024 * 1024),
+                defaults.memory,
+            ),
+            timeout: override_limit(self.timeout, defaults.timeout.map(|t| t.as_secs()))
+                .map(Duration::from_secs),
+        }
+    }
+}
+
+/// A limit of 0 disables it.
+fn override_limit<T: PartialEq + Default>(value: Option<T>, default: Option<T>) -> Option<T> {
+    match value {
+        Some(value) if value == T::default() => None,
+        Some(value) => Some(value),
+        None => default,
+    }
+}
+
+/// Prints which patches of all discovered scaffolds apply to the project in `project_dir`.
+/// Scaffolds which fail to load and patches whose checks fail are reported and skipped.
+fn print_applicable_patches(project_dir: &Path, load_limits: Limits) -> eyre::Result<()> {
+    let context = Context {
+        output: project_dir.to_path_buf(),
+        limits: load_limits,
+        ..Default::default()
+    };
+    let mut applicable = Vec::new();
+    let mut applied = Vec::new();
+    for path in find_all_scaffolds() {
+        let scaffold = match Scaffold::load_with_limits(path.clone(), load_limits) {
+            Ok(scaffold) => scaffold,
+            Err(err) => {
+                warn!(?path, "Skipping scaffold which failed to load: {err:#}");
+                continue;
+            }
+        };
+        for (name, status) in scaffold.patch_statuses(context.clone()) {
+            let description = &scaffold.meta.patches[&name].description;
+            let line = format!("- {}:{name}: {description}", scaffold.name);
+            match status {
+                Ok(PatchStatus::Applicable) => applicable.push(line),
+                Ok(PatchStatus::AlreadyApplied) => applied.push(line),
+                Ok(PatchStatus::NotApplicable) => {}
+                Err(err) => warn!(
+                    scaffold = scaffold.name,
+                    patch = name,
+                    "Skipping patch whose check failed: {err:#}"
+                ),
+            }
+        }
+    }
+    println!("Applicable patches:");
+    for line in applicable {
+        println!("{line}");
+    }
+    if !applied.is_empty() {
+        println!("Already applied:");
+        for line in applied {
+            println!("{line}");
+        }
+    }
+    Ok(())
+}
+
+/// Asks whether the answers should be saved, so the run can be repeated with `--replay`.
+fn offer_replay(scaffold_name: &str, context: &Context) -> eyre::Result<()> {
+    let save = Confirm::new("Save your answers to a replay file? (secrets are not saved)")
+        .with_default(false)
+        .prompt()?;
+    if !save {
+        return Ok(());
+    }
+    let path = inquire::Text::new("Replay file:")
+        .with_default("kenchiku-replay.json")
+        .prompt()?;
+    Replay::new(scaffold_name, context).save(Path::new(&path))?;
+    eprintln!("Saved answers, run again with `kenchiku construct {scaffold_name} --replay {path}`");
+    Ok(())
+}
+
+/// Combines the values from `KENCHIKU_VAL_*` environment variables, the values file and `--set`
+/// flags, later ones taking precedence. Setting a `list` or `multiselect` value multiple times
+/// appends to it. Fields of `object` values are set as `<value>.<field>`.
+fn parse_values(
+    values: &[String],
+    values_file: Option<&Path>,
+    values_meta: &HashMap<String, ValueMeta>,
+) -> eyre::Result<HashMap<String, String>> {
+    let mut set_values: HashMap<String, String> = HashMap::new();
+    for val in values {
+        let (name, value) = val
+            .split_once("=")
+            .ok_or_else(|| eyre!("Invalid value format: {}", val))?;
+        let is_list = find_value_meta(values_meta, name).is_some_and(ValueMeta::is_list);
+        match set_values.get_mut(name) {
+            Some(existing) if is_list => {
+                existing.push(',');
+                existing.push_str(value);
+            }
+            _ => {
+                set_values.insert(name.to_string(), value.to_string());
+            }
+        }
+    }
+    let file_values = match values_file {
+        Some(path) => read_values_file(path, values_meta)?,
+        None => HashMap::new(),
+    };
+    Ok(kenchiku_common::get_env_values()
+        .into_iter()
+        .chain(file_values)
+        .chain(set_values)
+        .collect())
+}
+
+/// The names of the values set with `--set`, so logs never contain secrets.
+fn value_names(values: &[String]) -> Vec<&str> {
+    values
+        .iter()
+        .map(|val| val.split_once('=').map_or(val.as_str(), |(name, _)| name))
+        .collect()
+}
+
+/// `prompt_value`, unless `no_input` is set: then asking for a value is an error instead of
+/// waiting for an answer.
+fn prompt_value_unless(prompt_value: PromptValueFn, no_input: bool) -> PromptValueFn {
+    if !no_input {
+        return prompt_value;
+    }
+    Arc::new(|name, _, _, _, _, _| {
+        Err(eyre!(
+            "Value '{name}' needs to be set, input is disabled (--no-input or not a terminal)"
+        ))
+    })
+}
+
+/// [`confirm`], unless `no_input` is set: then anything which isn't confirmed automatically
+/// (with `-c`) is an error instead of waiting for an answer.
+fn confirm_unless(no_input: bool) -> Arc<dyn Fn(String) -> eyre::Result<bool> + Send + Sync> {
+    if !no_input {
+        return Arc::new(confirm);
+    }
+    Arc::new(|message| {
+        Err(eyre!(
+            "Confirmation required, input is disabled (--no-input or not a terminal), pass -c/-cc \
+             to confirm automatically: {}",
+            message.lines().last().unwrap_or_default()
+        ))
+    })
+}
+
+/// Prompts for confirmation, see [`print_details`].
+fn confirm(message: String) -> eyre::Result<bool> {
+    // TODO: handle ctrl c
+    Ok(Confirm::new(print_details(&message))
+        .with_default(false)
+        .prompt()?)
+}
+
+fn select(message: String, choices: Vec<String>) -> eyre::Result<String> {
+    Ok(inquire::Select::new(print_details(&message), choices).prompt()?)
+}
+
+/// Prints everything before the last line of `message` (like diffs) as is and returns the last
+/// line, which is used as the prompt.
+fn print_details(message: &str) -> &str {
+    match message.rsplit_once('\n') {
+        Some((details, question)) => {
+            eprintln!("{details}");
+            question
+        }
+        None => message,
+    }
+}
+
+fn print_plan(plan: &ChangePlan, json: bool) -> eyre::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(plan)?);
+    } else {
+        plan.print(&mut std::io::stdout())?;
+    }
+    Ok(())
+}
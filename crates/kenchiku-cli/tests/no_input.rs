@@ -0,0 +1,93 @@
+use std::{
+    fs,
+    path::Path,
+    process::{Command, Output, Stdio},
+};
+
+fn kenchiku(scaffolds: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_kenchiku"))
+        .args(args)
+        .env("KENCHIKU_PATH", scaffolds)
+        .stdin(Stdio::null())
+        .output()
+        .expect("to run kenchiku")
+}
+
+#[test]
+fn test_no_input_never_asks_for_confirmation() {
+    let scaffolds = tempfile::tempdir().unwrap();
+    fs::create_dir_all(scaffolds.path().join("tool")).unwrap();
+    fs::write(
+        scaffolds.path().join("tool/scaffold.lua"),
+        r#"
+            return {
+                description = "tool",
+                construct = function() exec.run("touch ran") end,
+                patches = {
+                    readme = { description = "readme", run = function() fs.write("README.md", "hi") end },
+                },
+            }
+        "#,
+    )
+    .unwrap();
+    let project = tempfile::tempdir().unwrap();
+    let project_dir = project.path().to_str().unwrap();
+
+    let output = kenchiku(
+        scaffolds.path(),
+        &["construct", "tool", project_dir, "--no-input"],
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Confirmation required"), "{stderr}");
+    assert!(!project.path().join("ran").exists());
+
+    let output = kenchiku(
+        scaffolds.path(),
+        &["patch", "tool:readme", "-o", project_dir, "--no-input"],
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Confirmation required"), "{stderr}");
+    assert!(!project.path().join("README.md").exists());
+
+    let output = kenchiku(
+        scaffolds.path(),
+        &[
+            "patch",
+            "tool:readme",
+            "-o",
+            project_dir,
+            "--no-input",
+            "-c",
+        ],
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert!(project.path().join("README.md").exists());
+}
+
+#[test]
+fn test_no_input_runs_declared_commands() {
+    let scaffolds = tempfile::tempdir().unwrap();
+    fs::create_dir_all(scaffolds.path().join("tool")).unwrap();
+    fs::write(
+        scaffolds.path().join("tool/scaffold.lua"),
+        r#"
+            return {
+                description = "tool",
+                capabilities = { exec = { "touch" } },
+                construct = function() exec.run("touch ran") end,
+            }
+        "#,
+    )
+    .unwrap();
+    let project = tempfile::tempdir().unwrap();
+    let project_dir = project.path().to_str().unwrap();
+
+    let output = kenchiku(
+        scaffolds.path(),
+        &["construct", "tool", project_dir, "--no-input"],
+    );
+    assert!(output.status.success(), "{output:?}");
+    assert!(project.path().join("ran").exists());
+}
@@ -1,46 +1,175 @@
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, Mutex, atomic::AtomicU64},
+    time::{Duration, Instant},
+};
 
-use crate::meta::ValueMeta;
+use crate::meta::{Capabilities, ValueMeta};
 
 pub mod meta;
 pub mod minijinja_extras;
+pub mod values;
+
+/// Validates user input, returning an error message if the input is rejected.
+pub type ValidatorFn = Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+/// Asks for a value, receives name, type, description, choices, default and validator.
+pub type PromptValueFn = Arc<
+    dyn Fn(
+            String,
+            String,
+            String,
+            Option<Vec<String>>,
+            Option<String>,
+            Option<ValidatorFn>,
+        ) -> eyre::Result<String>
+        + Send
+        + Sync,
+>;
+
+/// What to do when a constructed file already exists in the output.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictStrategy {
+    /// Ask the user for every conflicting file.
+    Ask,
+    /// Replace the existing file.
+    Overwrite,
+    /// Keep the existing file, leaving the new one in the working directory.
+    #[default]
+    Keep,
+    /// Write the new file next to the existing one, as `<file>.kenchiku-new`.
+    New,
+    /// Merge both files, marking differences with conflict markers.
+    Merge,
+}
+
+impl std::str::FromStr for ConflictStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ask" => Ok(Self::Ask),
+            "overwrite" => Ok(Self::Overwrite),
+            "keep" => Ok(Self::Keep),
+            "new" => Ok(Self::New),
+            "merge" => Ok(Self::Merge),
+            _ => Err(format!(
+                "unknown conflict strategy '{s}', expected one of ask, overwrite, keep, new, merge"
+            )),
+        }
+    }
+}
+
+/// Bounds for running scaffold Lua code, `None` means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum number of interrupts, which Luau triggers on function calls and loop iterations.
+    pub instructions: Option<u64>,
+    /// Maximum memory the Lua state may use, in bytes.
+    pub memory: Option<usize>,
+    /// Maximum wall-clock time, including time spent waiting for prompts and commands.
+    pub timeout: Option<Duration>,
+}
+
+impl Limits {
+    /// Used when evaluating `scaffold.lua`, eg. for `list` and `show`, which should never take
+    /// long.
+    pub const LOAD: Limits = Limits {
+        instructions: Some(10_000_000),
+        memory: Some(64 * 1024 * 1024),
+        timeout: Some(Duration::from_secs(5)),
+    };
+    /// Used when running construct and patch functions. These can prompt and run commands, so
+    /// there is no timeout by default.
+    pub const RUN: Limits = Limits {
+        instructions: Some(1_000_000_000),
+        memory: Some(512 * 1024 * 1024),
+        timeout: None,
+    };
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::RUN
+    }
+}
 
 #[derive(Clone)]
 pub struct Context {
     pub working_dir: PathBuf,
     pub confirm_all: u8,
     pub confirm_fn: Arc<dyn Fn(String) -> eyre::Result<bool> + Send + Sync>,
+    /// Asks the user to pick one of the choices, the first one is the default. Used eg. for
+    /// resolving conflicting files.
+    pub select_fn: Arc<dyn Fn(String, Vec<String>) -> eyre::Result<String> + Send + Sync>,
     pub output: PathBuf,
     pub scaffold_dir: PathBuf,
     pub allow_overwrite: bool,
+    /// Used for existing files unless `allow_overwrite` is set.
+    pub on_conflict: ConflictStrategy,
     pub values_meta: HashMap<String, ValueMeta>,
     pub values: HashMap<String, String>,
-    pub prompt_value: Arc<
-        dyn Fn(
-                String,
-                String,
-                String,
-                Option<Vec<String>>,
-                Option<String>,
-                Option<Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync>>,
-            ) -> eyre::Result<String>
-            + Send
-            + Sync,
-    >,
+    pub prompt_value: PromptValueFn,
+    /// Values returned by `values.get`, shared between clones so the caller can see which
+    /// values a scaffold actually used (and prompted values are only asked for once).
+    pub resolved_values: Arc<Mutex<HashMap<String, String>>>,
+    /// Limits for running the construct or patch function.
+    pub limits: Limits,
+    /// When the outermost run times out, set from `limits.timeout` once it starts. Scaffolds and
+    /// patches it invokes share it instead of getting a timeout of their own.
+    pub deadline: Option<Instant>,
+    /// Instructions run so far, set once the outermost run starts. Scaffolds and patches it
+    /// invokes count against it, so `limits.instructions` applies to the run as a whole.
+    pub instructions: Option<Arc<AtomicU64>>,
+    /// Capabilities declared by the scaffold, the Lua modules refuse anything else.
+    pub capabilities: Option<Capabilities>,
+    /// Ask for all values before running, even if the scaffold doesn't declare `eager`.
+    pub eager: bool,
+    /// Never prompt, values which are neither set nor have a default are an error. All values
+    /// are resolved before running, so every missing one is reported at once.
+    pub no_input: bool,
+    /// Scaffolds (`<scaffold>`) and patches (`<scaffold>:<patch>`) currently running, outermost
+    /// first. Used to detect scaffolds invoking each other in a cycle.
+    pub call_stack: Vec<String>,
+    /// The run only stages its changes (dry runs, patches and updates), so they can be reviewed
+    /// and applied all at once. Writes outside of the working dir are refused, they could
+    /// neither be reviewed nor rolled back.
+    pub staging: bool,
+    /// Directories of the scaffolds `scaffold_dir` extends, nearest first. Scaffold files
+    /// missing in `scaffold_dir` are taken from the first of them containing them.
+    pub parent_scaffold_dirs: Vec<PathBuf>,
 }
 
 impl Default for Context {
     fn default() -> Self {
         Self {
             confirm_fn: Arc::new(|_message| Ok(true)),
+            select_fn: Arc::new(|_message, choices| {
+                choices
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| eyre::eyre!("nothing to select from"))
+            }),
             working_dir: Default::default(),
             confirm_all: 0,
             output: Default::default(),
             scaffold_dir: Default::default(),
             allow_overwrite: false,
+            on_conflict: Default::default(),
             values_meta: Default::default(),
             values: Default::default(),
             prompt_value: Arc::new(|_, _, _, _, _, _| Ok("".to_string())),
+            resolved_values: Default::default(),
+            limits: Default::default(),
+            deadline: None,
+            instructions: None,
+            capabilities: None,
+            eager: false,
+            no_input: false,
+            call_stack: vec![],
+            staging: false,
+            parent_scaffold_dirs: vec![],
         }
     }
 }
@@ -58,11 +187,8 @@ impl<T> IntoLuaErrDebug<T> for eyre::Result<T> {
 pub fn get_env_values() -> HashMap<String, String> {
     std::env::vars()
         .filter_map(|(k, v)| {
-            if let Some(name) = k.strip_prefix("KENCHIKU_VAL_") {
-                Some((name.to_lowercase(), v))
-            } else {
-                None
-            }
+            k.strip_prefix("KENCHIKU_VAL_")
+                .map(|name| (name.to_lowercase(), v))
         })
         .collect()
 }
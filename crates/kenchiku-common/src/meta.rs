@@ -4,12 +4,7 @@ use mlua::{FromLua, Lua};
 use serde::Serialize;
 use std::collections::HashMap;
 
-fn get_and_check<'lua, T>(
-    table: &mlua::Table,
-    key: &str,
-    type_name: &str,
-    lua: &'lua Lua,
-) -> mlua::Result<T>
+fn get_and_check<T>(table: &mlua::Table, key: &str, type_name: &str, lua: &Lua) -> mlua::Result<T>
 where
     T: FromLua,
 {
@@ -30,14 +25,183 @@ where
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct ValueMeta {
     pub r#type: String,
     pub description: String,
+    /// Static default or a function computing it from the other values.
+    #[serde(serialize_with = "serialize_default")]
     pub default: Option<mlua::Value>,
     pub choices: Option<Vec<String>>,
     #[serde(skip)]
     pub validate: Option<mlua::Function>,
+    /// Nested values of an `object` value.
+    pub fields: Option<HashMap<String, ValueMeta>>,
+    /// Smallest allowed value for `number`, `integer` and `float` values.
+    pub min: Option<f64>,
+    /// Largest allowed value for `number`, `integer` and `float` values.
+    pub max: Option<f64>,
+    /// Allowed increments for `number`, `integer` and `float` values, starting at `min` (or 0).
+    pub step: Option<f64>,
+    /// Only if this condition is met the value is relevant, otherwise `values.get` returns nil.
+    pub when: Option<Condition>,
+}
+
+/// Condition of a value, either a Lua expression over other values (eg. `database ~= "none"`)
+/// or a function receiving a table of all values.
+#[derive(Debug, Clone)]
+pub enum Condition {
+    Expression(String),
+    Function(mlua::Function),
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Condition::Expression(expression) => write!(f, "{expression}"),
+            Condition::Function(_) => write!(f, "<function>"),
+        }
+    }
+}
+
+impl Serialize for Condition {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromLua for Condition {
+    fn from_lua(value: mlua::Value, _lua: &Lua) -> mlua::Result<Self> {
+        match value {
+            mlua::Value::String(expression) => {
+                Ok(Condition::Expression(expression.to_str()?.to_string()))
+            }
+            mlua::Value::Function(function) => Ok(Condition::Function(function)),
+            other => Err(eyre!(
+                "'when' needs to be an expression string or function, received {:?}",
+                other
+            ))
+            .into_lua_err_debug(),
+        }
+    }
+}
+
+impl ValueMeta {
+    /// Whether the value holds multiple items (`list` and `multiselect`). These are passed
+    /// around comma-separated, see [`split_list`] and [`join_list`].
+    pub fn is_list(&self) -> bool {
+        matches!(self.r#type.as_str(), "list" | "multiselect")
+    }
+
+    /// Whether the value is a `secret`, which must never be logged or persisted.
+    pub fn is_secret(&self) -> bool {
+        self.r#type == "secret"
+    }
+
+    /// Whether the value is numeric (`number`, `integer` or `float`).
+    pub fn is_number(&self) -> bool {
+        matches!(self.r#type.as_str(), "number" | "integer" | "float")
+    }
+
+    /// Checks `number` against `min`, `max` and `step`.
+    pub fn check_range(&self, number: f64) -> Result<(), String> {
+        if let Some(min) = self.min.filter(|min| number < *min) {
+            return Err(format!("{number} is smaller than the minimum of {min}"));
+        }
+        if let Some(max) = self.max.filter(|max| number > *max) {
+            return Err(format!("{number} is larger than the maximum of {max}"));
+        }
+        if let Some(step) = self.step.filter(|step| *step > 0.0) {
+            let steps = (number - self.min.unwrap_or(0.0)) / step;
+            if (steps - steps.round()).abs() > 1e-9 {
+                return Err(format!("{number} is not a multiple of the step {step}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// The default as a string, like it would be passed with `--set`. Computed defaults are
+    /// only known while running, see [`ValueMeta::has_computed_default`].
+    pub fn default_string(&self) -> Option<String> {
+        value_string(self.default.as_ref()?)
+    }
+
+    pub fn has_computed_default(&self) -> bool {
+        matches!(self.default, Some(mlua::Value::Function(_)))
+    }
+}
+
+/// Converts a Lua value to a string like it would be passed with `--set`, tables are joined
+/// with [`join_list`].
+pub fn value_string(value: &mlua::Value) -> Option<String> {
+    match value {
+        mlua::Value::Table(table) => Some(join_list(
+            table
+                .sequence_values::<String>()
+                .collect::<mlua::Result<Vec<_>>>()
+                .ok()?,
+        )),
+        mlua::Value::Nil | mlua::Value::Function(_) => None,
+        value => value.to_string().ok(),
+    }
+}
+
+fn serialize_default<S: serde::Serializer>(
+    default: &Option<mlua::Value>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match default {
+        Some(mlua::Value::Function(_)) => serializer.serialize_str("<function>"),
+        default => default.serialize(serializer),
+    }
+}
+
+/// Looks up the value `id` in `values_meta`, fields of `object` values are addressed as
+/// `<value>.<field>` (eg. `db.host`).
+pub fn find_value_meta<'a>(
+    values_meta: &'a HashMap<String, ValueMeta>,
+    id: &str,
+) -> Option<&'a ValueMeta> {
+    if let Some(meta) = values_meta.get(id) {
+        return Some(meta);
+    }
+    let (parent, field) = id.split_once('.')?;
+    find_value_meta(values_meta.get(parent)?.fields.as_ref()?, field)
+}
+
+/// Splits the comma-separated items of a `list` or `multiselect` value. `\,` is a comma inside
+/// of an item and `\\` a backslash, other backslashes are kept as they are.
+pub fn split_list(value: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut chars = value.chars();
+    while let Some(char) = chars.next() {
+        match (char, chars.clone().next()) {
+            ('\\', Some(escaped @ (',' | '\\'))) => {
+                item.push(escaped);
+                chars.next();
+            }
+            (',', _) => items.push(std::mem::take(&mut item)),
+            (char, _) => item.push(char),
+        }
+    }
+    items.push(item);
+    items
+        .iter()
+        .map(|item| item.trim())
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Joins the items of a `list` or `multiselect` value, escaping commas and backslashes so
+/// [`split_list`] returns the same items.
+pub fn join_list<S: AsRef<str>>(items: impl IntoIterator<Item = S>) -> String {
+    items
+        .into_iter()
+        .map(|item| item.as_ref().replace('\\', "\\\\").replace(',', "\\,"))
+        .collect::<Vec<_>>()
+        .join(",")
 }
 
 impl FromLua for ValueMeta {
@@ -54,24 +218,42 @@ impl FromLua for ValueMeta {
         };
 
         Ok(ValueMeta {
-            description: get_and_check(&table, "description", "string", &lua)?,
+            description: get_and_check(&table, "description", "string", lua)?,
             default: table.get("default").unwrap_or_default(),
-            r#type: get_and_check(&table, "type", "string", &lua)?,
+            r#type: get_and_check(&table, "type", "string", lua)?,
             choices: table.get("choices").unwrap_or_default(),
             validate: table.get("validate").ok(),
+            fields: table.get("fields")?,
+            min: table.get("min")?,
+            max: table.get("max")?,
+            step: table.get("step")?,
+            when: table.get("when")?,
         })
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct PatchMeta {
     /// Description of what the patch does.
     pub description: String,
     /// Function which executes the patch.
     #[serde(skip)]
     pub run: mlua::Function,
+    /// Read-only check whether the patch makes sense for a project, eg. if `Cargo.toml` exists.
+    #[serde(skip)]
+    pub applies: Option<mlua::Function>,
+    /// Read-only check whether the patch was already applied to a project.
+    #[serde(skip)]
+    pub applied: Option<mlua::Function>,
     /// Values this patch requires.
     pub values: HashMap<String, ValueMeta>,
+    /// Whether all values are asked for before the run function is called.
+    pub eager: bool,
+    /// Order in which values are asked for up front, remaining values follow alphabetically.
+    pub order: Vec<String>,
+    /// Patches which run before this one, as `<scaffold>:<patch>` or just `<patch>` for patches
+    /// of the same scaffold.
+    pub requires: Vec<String>,
 }
 
 impl FromLua for PatchMeta {
@@ -88,10 +270,80 @@ impl FromLua for PatchMeta {
         };
 
         Ok(PatchMeta {
-            description: get_and_check(&table, "description", "string", &lua)
+            description: get_and_check(&table, "description", "string", lua)
                 .map(|val: String| val.trim().to_string())?,
-            run: get_and_check(&table, "run", "function", &lua)?,
+            run: get_and_check(&table, "run", "function", lua)?,
+            applies: table.get("applies")?,
+            applied: table.get("applied")?,
             values: table.get("values").unwrap_or_default(),
+            eager: table.get::<Option<bool>>("eager")?.unwrap_or_default(),
+            order: table
+                .get::<Option<Vec<String>>>("order")?
+                .unwrap_or_default(),
+            requires: table
+                .get::<Option<Vec<String>>>("requires")?
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// What a scaffold is allowed to do, declared with `capabilities = { ... }`. Scaffolds without
+/// declared capabilities are only restricted by confirmations.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Capabilities {
+    /// Programs `exec.run` may execute.
+    pub exec: Vec<String>,
+    /// Directories outside of the output `fs` functions may write to (`~/` is the home dir).
+    pub fs_write: Vec<String>,
+}
+
+impl Capabilities {
+    /// Capabilities of a scaffold declaring `own` while it runs on behalf of a scaffold
+    /// restricted to `caller`, eg. through `kenchiku.construct`. Only what both allow is
+    /// allowed, so restricted scaffolds can't escape their capabilities by invoking others.
+    pub fn restrict(
+        own: Option<&Capabilities>,
+        caller: Option<&Capabilities>,
+    ) -> Option<Capabilities> {
+        let both = |own: &[String], caller: &[String]| {
+            own.iter()
+                .filter(|item| caller.contains(item))
+                .cloned()
+                .collect()
+        };
+        match (own, caller) {
+            (own, None) => own.cloned(),
+            (None, Some(caller)) => Some(caller.clone()),
+            (Some(own), Some(caller)) => Some(Capabilities {
+                exec: both(&own.exec, &caller.exec),
+                fs_write: both(&own.fs_write, &caller.fs_write),
+            }),
+        }
+    }
+}
+
+impl FromLua for Capabilities {
+    fn from_lua(value: mlua::Value, lua: &Lua) -> mlua::Result<Self> {
+        let table = match value {
+            mlua::Value::Table(table) => table,
+            other => {
+                return Err(eyre!(
+                    "Scaffold needs to return a table for capabilities, received {:?}",
+                    other
+                ))
+                .into_lua_err_debug();
+            }
+        };
+        let fs_write = match table.get("fs")? {
+            mlua::Value::Nil => Vec::new(),
+            value => {
+                let fs_table = mlua::Table::from_lua(value, lua)?;
+                fs_table.get::<Option<_>>("write")?.unwrap_or_default()
+            }
+        };
+        Ok(Capabilities {
+            exec: table.get::<Option<_>>("exec")?.unwrap_or_default(),
+            fs_write,
         })
     }
 }
@@ -107,6 +359,41 @@ pub struct ScaffoldMeta {
     pub values: HashMap<String, ValueMeta>,
     /// Patches this scaffold exposes.
     pub patches: HashMap<String, PatchMeta>,
+    /// Capabilities the construct function and all patches are restricted to, if declared.
+    pub capabilities: Option<Capabilities>,
+    /// Whether all values are asked for before the construct function is called.
+    pub eager: bool,
+    /// Order in which values are asked for up front, remaining values follow alphabetically.
+    pub order: Vec<String>,
+    /// Version of the scaffold itself.
+    pub version: Option<String>,
+    pub authors: Vec<String>,
+    pub tags: Vec<String>,
+    pub homepage: Option<String>,
+    /// Kenchiku versions the scaffold works with, it refuses to run on others.
+    pub requires_kenchiku: Option<semver::VersionReq>,
+    /// Scaffold this one is based on, by name or as path relative to this scaffold.
+    pub extends: Option<String>,
+}
+
+impl ScaffoldMeta {
+    /// Takes over the values and patches of `parent` which this scaffold doesn't override, and
+    /// its capabilities if this scaffold declares none.
+    pub fn inherit(&mut self, parent: &ScaffoldMeta) {
+        for (id, value) in &parent.values {
+            self.values
+                .entry(id.clone())
+                .or_insert_with(|| value.clone());
+        }
+        for (name, patch) in &parent.patches {
+            self.patches
+                .entry(name.clone())
+                .or_insert_with(|| patch.clone());
+        }
+        if self.capabilities.is_none() {
+            self.capabilities = parent.capabilities.clone();
+        }
+    }
 }
 
 impl FromLua for ScaffoldMeta {
@@ -121,12 +408,42 @@ impl FromLua for ScaffoldMeta {
                 .into_lua_err_debug();
             }
         };
+        let extends: Option<String> = table.get("extends")?;
+        let construct = match table.get("construct")? {
+            // scaffolds extending another one construct like it unless they override it
+            mlua::Value::Nil if extends.is_some() => lua.create_function(|lua, ()| {
+                let kenchiku: mlua::Table = lua.globals().get("kenchiku")?;
+                kenchiku
+                    .get::<mlua::Function>("construct_parent")?
+                    .call::<()>(())
+            })?,
+            _ => get_and_check(&table, "construct", "function", lua)?,
+        };
         Ok(ScaffoldMeta {
-            description: get_and_check(&table, "description", "string", &lua)
+            description: get_and_check(&table, "description", "string", lua)
                 .map(|val: String| val.trim().to_string())?,
-            construct: get_and_check(&table, "construct", "function", &lua)?,
+            construct,
             values: table.get("values").unwrap_or_default(),
             patches: table.get("patches").unwrap_or_default(),
+            capabilities: table.get("capabilities")?,
+            eager: table.get::<Option<bool>>("eager")?.unwrap_or_default(),
+            order: table
+                .get::<Option<Vec<String>>>("order")?
+                .unwrap_or_default(),
+            version: table.get("version")?,
+            authors: table.get::<Option<_>>("authors")?.unwrap_or_default(),
+            tags: table.get::<Option<_>>("tags")?.unwrap_or_default(),
+            homepage: table.get("homepage")?,
+            requires_kenchiku: table
+                .get::<Option<String>>("requires_kenchiku")?
+                .map(|req| {
+                    semver::VersionReq::parse(&req).map_err(|err| {
+                        eyre!("'requires_kenchiku' is not a valid version range: {err}")
+                    })
+                })
+                .transpose()
+                .into_lua_err_debug()?,
+            extends,
         })
     }
 }
@@ -0,0 +1,203 @@
+//! Structured values (from MCP tool calls or values files) converted to the string values
+//! scaffolds get passed.
+
+use eyre::{Context as _, Result, eyre};
+use std::{collections::HashMap, path::Path};
+
+use crate::meta::{ValueMeta, find_value_meta, join_list};
+
+/// Reads a JSON, YAML or TOML file (by extension) containing a map of values. `object` values
+/// are nested maps, `list` and `multiselect` values arrays.
+pub fn read_values_file(
+    path: &Path,
+    values_meta: &HashMap<String, ValueMeta>,
+) -> Result<HashMap<String, String>> {
+    let content = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("failed to read values file {}", path.display()))?;
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    let values: HashMap<String, serde_json::Value> = match extension {
+        "json" => serde_json::from_str(&content).map_err(eyre::Report::from),
+        "yaml" | "yml" => serde_norway::from_str(&content).map_err(eyre::Report::from),
+        "toml" => toml::from_str(&content).map_err(eyre::Report::from),
+        _ => {
+            return Err(eyre!(
+                "unsupported values file {}, expected a .json, .yaml, .yml or .toml file",
+                path.display()
+            ));
+        }
+    }
+    .wrap_err_with(|| format!("failed to parse values file {}", path.display()))?;
+    let values = flatten_values(values, values_meta)
+        .wrap_err_with(|| format!("invalid values file {}", path.display()))?;
+    Ok(values
+        .iter()
+        .map(|(id, value)| (id.clone(), json_to_value_string(value)))
+        .collect())
+}
+
+/// Flattens the fields of `object` values into `<value>.<field>` entries, checking them against
+/// the value's declared fields.
+pub fn flatten_values(
+    values: HashMap<String, serde_json::Value>,
+    values_meta: &HashMap<String, ValueMeta>,
+) -> eyre::Result<HashMap<String, serde_json::Value>> {
+    let mut flattened = HashMap::new();
+    for (id, value) in values {
+        flatten_value(id, value, values_meta, &mut flattened)?;
+    }
+    Ok(flattened)
+}
+
+fn flatten_value(
+    id: String,
+    value: serde_json::Value,
+    values_meta: &HashMap<String, ValueMeta>,
+    flattened: &mut HashMap<String, serde_json::Value>,
+) -> eyre::Result<()> {
+    let meta = find_value_meta(values_meta, &id);
+    let fields = meta
+        .filter(|meta| meta.r#type == "object")
+        .and_then(|meta| meta.fields.as_ref());
+    match (value, fields) {
+        (serde_json::Value::Object(object), Some(fields)) => {
+            for (name, value) in object {
+                if !fields.contains_key(&name) {
+                    let mut known: Vec<_> = fields.keys().map(String::as_str).collect();
+                    known.sort();
+                    return Err(eyre!(
+                        "Unknown field '{name}' for value '{id}', known fields: {}",
+                        known.join(", ")
+                    ));
+                }
+                flatten_value(format!("{id}.{name}"), value, values_meta, flattened)?;
+            }
+        }
+        (serde_json::Value::Object(_), None) if meta.is_some() => {
+            return Err(eyre!("Value '{id}' is not an object"));
+        }
+        (_, Some(_)) => {
+            return Err(eyre!(
+                "Value '{id}' is an object, pass its fields as a JSON object"
+            ));
+        }
+        (value, _) => {
+            flattened.insert(id, value);
+        }
+    }
+    Ok(())
+}
+
+/// Converts a JSON value to the string values get passed around as. Arrays (for `list` and
+/// `multiselect` values) are joined with [`join_list`].
+pub fn json_to_value_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(string) => string.clone(),
+        serde_json::Value::Array(items) => join_list(items.iter().map(json_to_value_string)),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meta::split_list;
+    use mlua::{FromLua, Lua};
+
+    fn values_meta(lua: &Lua) -> HashMap<String, ValueMeta> {
+        let table: mlua::Value = lua
+            .load(
+                r#"
+                    {
+                        name = { type = "string", description = "Name" },
+                        ports = { type = "list", description = "Ports" },
+                        db = {
+                            type = "object",
+                            description = "Database",
+                            fields = { host = { type = "string", description = "Host" } },
+                        },
+                    }
+                "#,
+            )
+            .eval()
+            .unwrap();
+        HashMap::from_lua(table, lua).unwrap()
+    }
+
+    #[test]
+    fn test_read_values_file_formats() -> Result<()> {
+        let lua = Lua::new();
+        let values_meta = values_meta(&lua);
+        let dir = tempfile::tempdir()?;
+        let files = [
+            (
+                "values.json",
+                r#"{ "name": "a=b\nc", "ports": [80, 443], "db": { "host": "localhost" } }"#,
+            ),
+            (
+                "values.yaml",
+                "name: |-\n  a=b\n  c\nports: [80, 443]\ndb:\n  host: localhost\n",
+            ),
+            (
+                "values.toml",
+                "name = \"a=b\\nc\"\nports = [80, 443]\n[db]\nhost = \"localhost\"\n",
+            ),
+        ];
+        for (name, content) in files {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content)?;
+            let values = read_values_file(&path, &values_meta)?;
+            assert_eq!(
+                values,
+                HashMap::from([
+                    ("name".to_string(), "a=b\nc".to_string()),
+                    ("ports".to_string(), "80,443".to_string()),
+                    ("db.host".to_string(), "localhost".to_string()),
+                ]),
+                "{name}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_values_file_errors() -> Result<()> {
+        let lua = Lua::new();
+        let values_meta = values_meta(&lua);
+        let dir = tempfile::tempdir()?;
+
+        let path = dir.path().join("values.ini");
+        std::fs::write(&path, "name=a")?;
+        let err = read_values_file(&path, &values_meta).unwrap_err();
+        assert!(err.to_string().contains("unsupported values file"));
+
+        let path = dir.path().join("values.json");
+        std::fs::write(&path, r#"{ "db": { "port": 5432 } }"#)?;
+        let err = read_values_file(&path, &values_meta).unwrap_err();
+        assert!(format!("{err:?}").contains("Unknown field 'port' for value 'db'"));
+
+        std::fs::write(&path, "[1, 2]")?;
+        let err = read_values_file(&path, &values_meta).unwrap_err();
+        assert!(err.to_string().contains("failed to parse values file"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_values_file_list_items_with_commas() -> Result<()> {
+        let lua = Lua::new();
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("values.yaml");
+        std::fs::write(&path, "ports: [\"80,443\", \"8080\"]\n")?;
+        let values = read_values_file(&path, &values_meta(&lua))?;
+        assert_eq!(split_list(&values["ports"]), vec!["80,443", "8080"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_items_keep_commas() {
+        let value = json_to_value_string(&serde_json::json!(["a,b", "c\\d", "e"]));
+        assert_eq!(value, "a\\,b,c\\\\d,e");
+        assert_eq!(split_list(&value), vec!["a,b", "c\\d", "e"]);
+        // unknown escapes are kept, eg. for windows paths
+        assert_eq!(split_list("C:\\dir, x"), vec!["C:\\dir", "x"]);
+    }
+}
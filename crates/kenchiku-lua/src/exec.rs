@@ -1,4 +1,5 @@
-use kenchiku_common::Context;
+use eyre::eyre;
+use kenchiku_common::{Context, IntoLuaErrDebug, meta::Capabilities};
 use mlua::{Lua, Result};
 use std::process::Command;
 
@@ -11,7 +12,21 @@ impl LuaExec {
         exec_table.set(
             "run",
             lua.create_function(move |lua, command: String| {
-                if context.confirm_all < 2 {
+                let mut process = match &context.capabilities {
+                    Some(capabilities) => {
+                        declared_command(capabilities, &command).into_lua_err_debug()?
+                    }
+                    None => {
+                        let mut process = Command::new("sh");
+                        process.arg("-c").arg(&command);
+                        process
+                    }
+                };
+
+                // declared commands were reviewed with the scaffold, so unattended runs don't
+                // need to confirm them
+                let declared = context.capabilities.is_some();
+                if context.confirm_all < 2 && !(declared && context.no_input) {
                     let ans = (context.confirm_fn)(format!(
                         "[sys] Execute command '{}' in {}?",
                         command,
@@ -20,14 +35,13 @@ impl LuaExec {
 
                     match ans {
                         Ok(true) => {}
-                        _ => return Err(mlua::Error::external("command denied by user")),
+                        Ok(false) => return Err(mlua::Error::external("command denied by user")),
+                        Err(err) => return Err(err).into_lua_err_debug(),
                     }
                 }
 
-                let output = Command::new("sh")
+                let output = process
                     .current_dir(&context.working_dir)
-                    .arg("-c")
-                    .arg(&command)
                     .output()
                     .map_err(mlua::Error::external)?;
 
@@ -52,6 +66,25 @@ impl LuaExec {
     }
 }
 
+/// Builds the command for scaffolds with declared capabilities. The program has to be one of
+/// the declared ones and runs without a shell, so it can't be used to chain other commands.
+fn declared_command(capabilities: &Capabilities, command: &str) -> eyre::Result<Command> {
+    let words =
+        shlex::split(command).ok_or_else(|| eyre!("could not parse command '{command}'"))?;
+    let Some((program, args)) = words.split_first() else {
+        return Err(eyre!("command is empty"));
+    };
+    if !capabilities.exec.contains(program) {
+        return Err(eyre!(
+            "'{program}' is not part of the scaffold's declared capabilities (exec = {{ {} }})",
+            capabilities.exec.join(", ")
+        ));
+    }
+    let mut process = Command::new(program);
+    process.args(args);
+    Ok(process)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -68,7 +101,7 @@ mod tests {
         let confirmed_clone = confirmed.clone();
 
         Context {
-            working_dir: working_dir.unwrap_or_else(|| std::env::temp_dir()),
+            working_dir: working_dir.unwrap_or_else(std::env::temp_dir),
             confirm_all: if auto_confirm { 2 } else { 0 },
             confirm_fn: Arc::new(move |prompt: String| {
                 confirmed_clone.lock().unwrap().push(prompt);
@@ -143,7 +176,7 @@ mod tests {
         LuaExec::register(&lua, context)?;
 
         let expected_dir = temp_dir.canonicalize()?;
-        lua.load(&format!(
+        lua.load(format!(
             r#"
             local result = exec.run("pwd")
             local trimmed = result.stdout:gsub("%s+$", "")
@@ -416,4 +449,85 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_lua_exec_declared_capabilities() -> eyre::Result<()> {
+        let lua = Lua::new();
+        let context = Context {
+            capabilities: Some(Capabilities {
+                exec: vec!["echo".to_string()],
+                ..Default::default()
+            }),
+            ..create_test_context_with_confirm(true, None)
+        };
+        LuaExec::register(&lua, context)?;
+
+        // runs without a shell, so `;` is just an argument
+        lua.load(
+            r#"
+            local result = exec.run("echo 'hello world'; rm -rf foo")
+            assert(result.stdout == "hello world; rm -rf foo\n")
+        "#,
+        )
+        .exec()?;
+
+        let err = lua.load(r#"exec.run("rm -rf foo")"#).exec().unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("'rm' is not part of the scaffold's declared capabilities"),
+            "{err}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lua_exec_undeclared_is_not_confirmed() -> eyre::Result<()> {
+        let lua = Lua::new();
+        let confirmed = Arc::new(Mutex::new(Vec::new()));
+        let confirmed_clone = confirmed.clone();
+        let context = Context {
+            confirm_fn: Arc::new(move |prompt: String| {
+                confirmed_clone.lock().unwrap().push(prompt);
+                Ok(true)
+            }),
+            capabilities: Some(Capabilities::default()),
+            ..Default::default()
+        };
+        LuaExec::register(&lua, context)?;
+
+        assert!(lua.load(r#"exec.run("echo 'test'")"#).exec().is_err());
+        assert!(confirmed.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lua_exec_declared_runs_without_input() -> eyre::Result<()> {
+        let context = Context {
+            working_dir: std::env::temp_dir(),
+            confirm_fn: Arc::new(|_prompt: String| Err(eyre!("confirmation required"))),
+            no_input: true,
+            ..Default::default()
+        };
+
+        let lua = Lua::new();
+        LuaExec::register(
+            &lua,
+            Context {
+                capabilities: Some(Capabilities {
+                    exec: vec!["echo".to_string()],
+                    ..Default::default()
+                }),
+                ..context.clone()
+            },
+        )?;
+        lua.load(r#"assert(exec.run("echo 'test'").stdout == "test\n")"#)
+            .exec()?;
+
+        // without declared capabilities any command could run, so it still has to be confirmed
+        let lua = Lua::new();
+        LuaExec::register(&lua, context)?;
+        let err = lua.load(r#"exec.run("echo 'test'")"#).exec().unwrap_err();
+        assert!(err.to_string().contains("confirmation required"), "{err}");
+        Ok(())
+    }
 }
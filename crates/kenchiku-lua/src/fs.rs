@@ -1,9 +1,8 @@
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 use eyre::{Result, eyre};
 use kenchiku_common::{Context, IntoLuaErrDebug};
 use mlua::{FromLua, Lua};
-use normalize_path::NormalizePath;
 use tracing::debug;
 
 pub struct LuaFS;
@@ -16,57 +15,56 @@ impl LuaFS {
         fs_table.set(
             "exists",
             lua.create_function(move |_, path: String| {
-                let user_path = normalize_path(&working_dir, path);
+                let user_path = confine_path(&working_dir, &path).into_lua_err_debug()?;
                 Ok(user_path.exists())
             })?,
         )?;
 
         let working_dir = context.working_dir.clone();
-        let scaffold_dir = context.scaffold_dir.clone();
+        let read_context = context.clone();
         fs_table.set(
             "read",
             lua.create_function(move |_, (path, opts): (String, LuaFsReadOpts)| {
                 let path = match opts.source.as_ref() {
-                    "workdir" => normalize_path(&working_dir, path),
-                    "scaffold" => normalize_path(&scaffold_dir, path),
+                    "workdir" => confine_path(&working_dir, &path),
+                    "scaffold" => confine_scaffold_path(&read_context, &path),
                     _ => {
                         return Err(eyre!(
                             "Invalid read source, must be one of workdir,scaffold"
                         ))
                         .into_lua_err_debug();
                     }
-                };
+                }
+                .into_lua_err_debug()?;
 
                 Ok(std::fs::read_to_string(&path)?)
             })?,
         )?;
 
-        let working_dir = context.working_dir.clone();
+        let mkdir_context = context.clone();
         fs_table.set(
             "mkdir",
             lua.create_function(move |_, path: String| {
-                let user_path = normalize_path(&working_dir, path);
+                let user_path = confine_write_path(&mkdir_context, &path).into_lua_err_debug()?;
                 Ok(std::fs::create_dir_all(&user_path)?)
             })?,
         )?;
 
-        let working_dir = context.working_dir.clone();
+        let write_context = context.clone();
         fs_table.set(
             "write",
             lua.create_function(move |_, (path, content): (String, String)| {
-                let user_path = normalize_path(&working_dir, path);
+                let user_path = confine_write_path(&write_context, &path).into_lua_err_debug()?;
                 debug!(?user_path, "Writing to file");
                 Ok(std::fs::write(&user_path, content)?)
             })?,
         )?;
 
-        let working_dir = context.working_dir.clone();
-        let scaffold_dir = context.scaffold_dir.clone();
         fs_table.set(
             "copy",
             lua.create_function(move |_, (source, destination): (String, String)| {
-                let source_path = normalize_path(&scaffold_dir, source);
-                let dest_path = normalize_path(&working_dir, destination);
+                let source_path = confine_scaffold_path(&context, &source).into_lua_err_debug()?;
+                let dest_path = confine_write_path(&context, &destination).into_lua_err_debug()?;
                 debug!(?source_path, ?dest_path, "Copying file");
                 Ok(std::fs::copy(&source_path, &dest_path)?)
             })?,
@@ -107,11 +105,116 @@ impl FromLua for LuaFsReadOpts {
     }
 }
 
-pub(crate) fn normalize_path(working_dir: &Path, path: String) -> PathBuf {
-    let normalized_path = Path::new("./").join(&path).normalize();
-    let user_path = working_dir.join(&normalized_path);
-    debug!(path, ?normalized_path, ?user_path, "Normalized path");
-    user_path.to_path_buf()
+/// Resolves the relative `path` of a scaffold file. Files missing in the scaffold are taken
+/// from the scaffolds it extends, so they can override single files.
+pub(crate) fn confine_scaffold_path(context: &Context, path: &str) -> Result<PathBuf> {
+    let user_path = confine_path(&context.scaffold_dir, path)?;
+    for parent_dir in &context.parent_scaffold_dirs {
+        if user_path.exists() {
+            break;
+        }
+        let parent_path = confine_path(parent_dir, path)?;
+        if parent_path.exists() {
+            return Ok(parent_path);
+        }
+    }
+    Ok(user_path)
+}
+
+/// Resolves the relative `path` inside of `base`. Absolute paths, paths leaving `base` via `..`
+/// and paths which end up outside of `base` by following symlinks are rejected.
+pub(crate) fn confine_path(base: &Path, path: &str) -> Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !relative.pop() {
+                    return Err(eyre!("path '{path}' is outside of {}", base.display()));
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(eyre!(
+                    "path '{path}' is absolute, only paths relative to {} are allowed",
+                    base.display()
+                ));
+            }
+        }
+    }
+    let user_path = base.join(&relative);
+    ensure_no_symlink_escape(base, &user_path, path)?;
+    debug!(path, ?user_path, "Confined path");
+    Ok(user_path)
+}
+
+/// Resolves a path `fs` functions write to. Relative paths are confined to the working dir,
+/// absolute ones (or ones starting with `~/`) have to be inside of a directory the scaffold
+/// declared in `capabilities.fs.write` and are refused while only staging changes.
+fn confine_write_path(context: &Context, path: &str) -> Result<PathBuf> {
+    let user_path = expand_home(path);
+    if !user_path.is_absolute() {
+        return confine_path(&context.working_dir, path);
+    }
+    if context.staging {
+        return Err(eyre!(
+            "path '{path}' is outside of {}, dry runs, patches and updates can only write \
+             to the project since changes elsewhere could not be reviewed",
+            context.working_dir.display()
+        ));
+    }
+    let declared = context
+        .capabilities
+        .iter()
+        .flat_map(|capabilities| &capabilities.fs_write);
+    for root in declared {
+        let root = expand_home(root);
+        if let Ok(relative) = user_path.strip_prefix(&root) {
+            return confine_path(&root, &relative.to_string_lossy());
+        }
+    }
+    Err(eyre!(
+        "path '{path}' is outside of {}, the scaffold needs to declare it in \
+         capabilities.fs.write to write to it",
+        context.working_dir.display()
+    ))
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Checks that the deepest existing ancestor of `user_path` (or the path itself) still resolves
+/// to somewhere inside of `base` once all symlinks are followed.
+fn ensure_no_symlink_escape(base: &Path, user_path: &Path, path: &str) -> Result<()> {
+    let Ok(canonical_base) = base.canonicalize() else {
+        // nothing exists yet, so there is nothing to follow either
+        return Ok(());
+    };
+    let mut existing = user_path;
+    while existing.symlink_metadata().is_err() {
+        match existing.parent() {
+            Some(parent) => existing = parent,
+            None => return Ok(()),
+        }
+    }
+    let resolved = existing.canonicalize().map_err(|err| {
+        eyre!(
+            "path '{path}' could not be resolved (dangling symlink at {}?): {err}",
+            existing.display()
+        )
+    })?;
+    if !resolved.starts_with(&canonical_base) {
+        return Err(eyre!(
+            "path '{path}' is outside of {}, it resolves to {} via a symlink",
+            base.display(),
+            resolved.display()
+        ));
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -166,10 +269,14 @@ mod tests {
         )?;
         assert!(working_dir.join("some/dir/nested").exists());
 
-        // Mkdir escape test (gets normalized to nested)
+        // Mkdir escape test
+        assert!(execute_lua(r#"fs.mkdir("../../../nested")"#).is_err());
+        assert!(!working_dir.join("nested").exists());
+
+        // Mkdir with `..` staying inside
         execute_lua(
             r#"
-                fs.mkdir("../../../nested")
+                fs.mkdir("some/../nested")
             "#,
         )?;
         assert!(working_dir.join("nested").exists());
@@ -221,48 +328,193 @@ mod tests {
     }
 
     #[test]
-    fn test_validate_path_valid() {
-        let temp_dir = tempdir().expect("temp dir should be created");
+    fn test_validate_path_valid() -> Result<()> {
+        let temp_dir = tempdir()?;
         let working_dir = temp_dir.path();
 
         // Valid path within the working directory
-        let path = "foo/bar".to_string();
-        fs::create_dir_all(working_dir.join(&path)).expect("directory should be created");
-        let result = normalize_path(working_dir, path.clone());
-        assert_eq!(result, working_dir.join(path));
+        let path = "foo/bar";
+        fs::create_dir_all(working_dir.join(path))?;
+        assert_eq!(confine_path(working_dir, path)?, working_dir.join(path));
+        // Not existing yet
+        assert_eq!(
+            confine_path(working_dir, "foo/baz/new.txt")?,
+            working_dir.join("foo/baz/new.txt")
+        );
+        Ok(())
     }
 
     #[test]
-    fn test_validate_path_working_dir() {
-        let temp_dir = tempdir().expect("temp dir should be created");
+    fn test_validate_path_working_dir() -> Result<()> {
+        let temp_dir = tempdir()?;
         let working_dir = temp_dir.path();
 
         // Path equal to the working directory
-        let path = ".".to_string();
-        let result = normalize_path(working_dir, path);
-        assert_eq!(result, working_dir.join(".").to_path_buf());
+        assert_eq!(confine_path(working_dir, ".")?, working_dir);
+        assert_eq!(confine_path(working_dir, "foo/..")?, working_dir);
+        Ok(())
     }
 
     #[test]
-    fn test_validate_path_invalid() {
-        let temp_dir = tempdir().expect("temp dir should be created");
+    fn test_validate_path_invalid() -> Result<()> {
+        let temp_dir = tempdir()?;
         let working_dir = temp_dir.path();
 
-        // Invalid path (outside the working directory), gets normalized to foo
-        let path = "../foo".to_string();
-        let result = normalize_path(working_dir, path);
-        assert_eq!(result, working_dir.join("foo").to_path_buf())
+        // Invalid paths (outside the working directory)
+        for path in ["../foo", "foo/../../bar", "/etc/passwd"] {
+            assert!(confine_path(working_dir, path).is_err(), "{path}");
+        }
+        Ok(())
     }
 
     #[test]
-    fn test_validate_path_file() {
-        let temp_dir = tempdir().expect("temp dir should be created");
+    fn test_validate_path_file() -> Result<()> {
+        let temp_dir = tempdir()?;
         let working_dir = temp_dir.path();
 
         // Path that is a file within the working directory
-        let path = "foo.txt".to_string();
-        fs::write(working_dir.join(&path), "test").expect("file to be created");
-        let result = normalize_path(working_dir, path.clone());
-        assert_eq!(result, working_dir.join(path));
+        let path = "foo.txt";
+        fs::write(working_dir.join(path), "test")?;
+        assert_eq!(confine_path(working_dir, path)?, working_dir.join(path));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_validate_path_symlink() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let outside_dir = tempdir()?;
+        let working_dir = temp_dir.path();
+        fs::create_dir(working_dir.join("inner"))?;
+        std::os::unix::fs::symlink(outside_dir.path(), working_dir.join("escape"))?;
+        std::os::unix::fs::symlink(working_dir.join("inner"), working_dir.join("inside"))?;
+        std::os::unix::fs::symlink(
+            outside_dir.path().join("missing"),
+            working_dir.join("dangling"),
+        )?;
+
+        assert!(confine_path(working_dir, "escape").is_err());
+        assert!(confine_path(working_dir, "escape/new/file.txt").is_err());
+        assert!(confine_path(working_dir, "dangling").is_err());
+        assert_eq!(
+            confine_path(working_dir, "inside/file.txt")?,
+            working_dir.join("inside/file.txt")
+        );
+        Ok(())
+    }
+
+    /// Every fs function has to refuse absolute paths, `..` escapes and symlinks leading out of
+    /// the working (or scaffold) directory.
+    #[cfg(unix)]
+    #[test]
+    fn test_lua_fs_confinement() -> Result<()> {
+        let working_temp_dir = tempdir()?;
+        let scaffold_temp_dir = tempdir()?;
+        let outside_temp_dir = tempdir()?;
+        let (working_dir, scaffold_dir, outside_dir) = (
+            working_temp_dir.path(),
+            scaffold_temp_dir.path(),
+            outside_temp_dir.path(),
+        );
+        fs::write(scaffold_dir.join("example.txt"), "hello world")?;
+        fs::write(outside_dir.join("secret.txt"), "secret")?;
+        std::os::unix::fs::symlink(outside_dir, working_dir.join("link"))?;
+        std::os::unix::fs::symlink(outside_dir, scaffold_dir.join("link"))?;
+
+        let lua = Lua::new();
+        let context = Context {
+            working_dir: working_dir.to_path_buf(),
+            scaffold_dir: scaffold_dir.to_path_buf(),
+            ..Default::default()
+        };
+        LuaFS::register(&lua, context)?;
+
+        let outside = outside_dir.display().to_string();
+        let calls = [
+            r#"fs.exists("{path}")"#,
+            r#"fs.read("{path}/secret.txt", { source = "workdir" })"#,
+            r#"fs.read("{path}/secret.txt", { source = "scaffold" })"#,
+            r#"fs.mkdir("{path}/dir")"#,
+            r#"fs.write("{path}/written.txt", "pwned")"#,
+            r#"fs.copy("{path}/secret.txt", "copied.txt")"#,
+            r#"fs.copy("example.txt", "{path}/copied.txt")"#,
+        ];
+        for call in calls {
+            for path in [outside.as_str(), "../..", "link"] {
+                let script = call.replace("{path}", path);
+                let err = lua.load(&script).exec().unwrap_err();
+                assert!(
+                    err.to_string().contains("is absolute")
+                        || err.to_string().contains("is outside of"),
+                    "{script}: {err}"
+                );
+            }
+        }
+
+        assert_eq!(fs::read_dir(outside_dir)?.count(), 1);
+        assert!(!working_dir.join("copied.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lua_fs_declared_write_capability() -> Result<()> {
+        let working_dir = tempdir()?;
+        let scaffold_dir = tempdir()?;
+        let shared_dir = tempdir()?;
+        let other_dir = tempdir()?;
+        fs::write(scaffold_dir.path().join("example.txt"), "hello world")?;
+
+        let lua = Lua::new();
+        let context = Context {
+            working_dir: working_dir.path().to_path_buf(),
+            scaffold_dir: scaffold_dir.path().to_path_buf(),
+            capabilities: Some(kenchiku_common::meta::Capabilities {
+                fs_write: vec![shared_dir.path().display().to_string()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        LuaFS::register(&lua, context.clone())?;
+
+        let (shared, other) = (shared_dir.path().display(), other_dir.path().display());
+        lua.load(format!(
+            r#"
+                fs.mkdir("{shared}/dir")
+                fs.write("{shared}/dir/written.txt", "written")
+                fs.copy("example.txt", "{shared}/copied.txt")
+            "#
+        ))
+        .exec()?;
+        assert_eq!(
+            fs::read_to_string(shared_dir.path().join("dir/written.txt"))?,
+            "written"
+        );
+        assert!(shared_dir.path().join("copied.txt").exists());
+
+        for script in [
+            format!(r#"fs.write("{other}/written.txt", "written")"#),
+            format!(r#"fs.write("{shared}/../written.txt", "written")"#),
+            format!(r#"fs.read("{shared}/dir/written.txt", {{ source = "workdir" }})"#),
+        ] {
+            assert!(lua.load(&script).exec().is_err(), "{script}");
+        }
+        assert_eq!(fs::read_dir(other_dir.path())?.count(), 0);
+
+        // staged runs can't write outside, even to declared directories
+        let lua = Lua::new();
+        LuaFS::register(
+            &lua,
+            Context {
+                staging: true,
+                ..context
+            },
+        )?;
+        let err = lua
+            .load(format!(r#"fs.write("{shared}/staged.txt", "staged")"#))
+            .exec()
+            .unwrap_err();
+        assert!(err.to_string().contains("dry runs, patches and updates"));
+        assert!(!shared_dir.path().join("staged.txt").exists());
+        Ok(())
     }
 }
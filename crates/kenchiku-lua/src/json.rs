@@ -11,9 +11,9 @@ impl LuaJson {
         json_table.set(
             "encode",
             lua.create_function(|_lua, data: mlua::Value| {
-                Ok(serde_json::to_string(&data)
+                serde_json::to_string(&data)
                     .wrap_err("failed to encode value to json")
-                    .into_lua_err_debug()?)
+                    .into_lua_err_debug()
             })?,
         )?;
 
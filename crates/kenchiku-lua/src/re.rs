@@ -24,11 +24,9 @@ impl LuaRe {
                         }
                     }
 
-                    for name in regex.capture_names() {
-                        if let Some(n) = name {
-                            if let Some(m) = captures.name(n) {
-                                matches.set(n, m.as_str())?;
-                            }
+                    for n in regex.capture_names().flatten() {
+                        if let Some(m) = captures.name(n) {
+                            matches.set(n, m.as_str())?;
                         }
                     }
                     Ok(Some(matches))
@@ -4,7 +4,7 @@ use minijinja::Environment;
 use mlua::{ExternalResult, Lua};
 use std::fs;
 
-use crate::fs::normalize_path;
+use crate::fs::confine_scaffold_path;
 
 pub struct LuaTmpl;
 
@@ -20,7 +20,7 @@ impl LuaTmpl {
                 env = minijinja_extras::register(env);
                 env.add_template("inline", &template).into_lua_err()?;
                 let template = env.get_template("inline").into_lua_err()?;
-                Ok(template.render(vars).into_lua_err()?)
+                template.render(vars).into_lua_err()
             })?,
         )?;
 
@@ -32,7 +32,12 @@ impl LuaTmpl {
                 env = minijinja_extras::register(env);
                 let context = context.clone();
                 env.set_loader(move |path| {
-                    let path = normalize_path(&context.scaffold_dir, path.to_string());
+                    let path = confine_scaffold_path(&context, path).map_err(|err| {
+                        minijinja::Error::new(
+                            minijinja::ErrorKind::InvalidOperation,
+                            err.to_string(),
+                        )
+                    })?;
                     match fs::read_to_string(path) {
                         Ok(result) => Ok(Some(result)),
                         Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
@@ -44,7 +49,7 @@ impl LuaTmpl {
                     }
                 });
                 let template = env.get_template(&file).into_lua_err()?;
-                Ok(template.render(vars).into_lua_err()?)
+                template.render(vars).into_lua_err()
             })?,
         )?;
 
@@ -152,6 +157,12 @@ mod tests {
             let nested_template = r#"Nested: {{ value }}"#;
             fs::write(template_dir.join("nested/child.txt"), nested_template)?;
 
+            fs::write(temp_dir.path().join("outside.txt"), "outside")?;
+            fs::write(
+                template_dir.join("include.txt"),
+                r#"{% include "../outside.txt" %}"#,
+            )?;
+
             let lua = Lua::new();
             let context = Context {
                 scaffold_dir: template_dir,
@@ -207,13 +218,36 @@ mod tests {
                 lua.load(script).exec()?;
             }
 
-            let error_cases = vec![(
-                "template_file non-existent file",
-                r#"
+            let error_cases = vec![
+                (
+                    "template_file non-existent file",
+                    r#"
                         tmpl.template_file("nonexistent.txt", {})
                     "#,
-                "template not found",
-            )];
+                    "template not found",
+                ),
+                (
+                    "template_file outside of scaffold",
+                    r#"
+                        tmpl.template_file("../outside.txt", {})
+                    "#,
+                    "is outside of",
+                ),
+                (
+                    "template_file absolute path",
+                    r#"
+                        tmpl.template_file("/etc/passwd", {})
+                    "#,
+                    "is absolute",
+                ),
+                (
+                    "template_file including a file outside of scaffold",
+                    r#"
+                        tmpl.template_file("include.txt", {})
+                    "#,
+                    "is outside of",
+                ),
+            ];
 
             for (name, script, error_message) in error_cases {
                 println!("Running template_file error case: {}", name);
@@ -262,4 +296,30 @@ mod tests {
 
         Ok(())
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lua_tmpl_template_file_symlink_escape() -> eyre::Result<()> {
+        let scaffold_dir = TempDir::new()?;
+        let outside_dir = TempDir::new()?;
+        fs::write(outside_dir.path().join("secret.txt"), "secret")?;
+        std::os::unix::fs::symlink(
+            outside_dir.path().join("secret.txt"),
+            scaffold_dir.path().join("secret.txt"),
+        )?;
+
+        let lua = Lua::new();
+        let context = Context {
+            scaffold_dir: scaffold_dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        LuaTmpl::register(&lua, context)?;
+
+        let err = lua
+            .load(r#"tmpl.template_file("secret.txt", {})"#)
+            .exec()
+            .unwrap_err();
+        assert!(err.to_string().contains("via a symlink"), "{err}");
+        Ok(())
+    }
 }
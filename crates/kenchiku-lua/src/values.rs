@@ -1,5 +1,8 @@
 use eyre::{Context as _, ContextCompat, Result, eyre};
-use kenchiku_common::{Context, IntoLuaErrDebug};
+use kenchiku_common::{
+    Context, IntoLuaErrDebug, ValidatorFn,
+    meta::{Condition, ValueMeta, find_value_meta, split_list, value_string},
+};
 use mlua::{IntoLua, Lua};
 use std::sync::Arc;
 use tracing::{debug, trace};
@@ -13,90 +16,17 @@ impl LuaValues {
         values_table.set(
             "get",
             lua.create_function(move |lua, id: String| {
-                let meta = context.values_meta.get(&id);
-                let val = context.values.get(&id);
-                debug!(id, ?val, ?meta, "Getting value");
-                if meta.is_none() {
-                    return Err(eyre!("No value named {} defined", id)).into_lua_err_debug();
-                }
-                let meta = meta.unwrap();
-
-                let run_validation = |val: &str| -> Result<(), String> {
-                    if let Some(validate) = &meta.validate {
-                        let res: mlua::Value =
-                            validate.call(val.to_string()).map_err(|e| e.to_string())?;
-                        match res {
-                            mlua::Value::Boolean(true) => Ok(()),
-                            mlua::Value::Boolean(false) => {
-                                Err("validate function rejected value".to_string())
-                            }
-                            mlua::Value::String(s) => Err(s.to_string_lossy()),
-                            _ => Err("Invalid return value from validate fn".to_string()),
-                        }
-                    } else {
-                        Ok(())
-                    }
-                };
-
-                // 1. if value was already set
-                if val.is_some() {
-                    trace!(id, "Value was already set");
-                    let val_str = val.unwrap();
-                    if let Err(e) = run_validation(&val_str) {
-                        return Err(eyre!("Value '{}' for '{}' is invalid: {}", val_str, id, e))
-                            .into_lua_err_debug();
-                    }
-                    return string_to_value_of_type(
-                        &lua,
-                        meta.r#type.clone(),
-                        val_str,
-                        meta.choices.clone(),
-                        id,
-                    );
-                }
-                // 2. if value is unset, ask the user
-                trace!(id, "Asking user for value...");
-
-                let validator: Option<Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync>> =
-                    if meta.validate.is_some() {
-                        let validate = meta.validate.clone().unwrap();
-                        Some(Arc::new(move |input: &str| {
-                            let res: mlua::Value = validate
-                                .call(input.to_string())
-                                .map_err(|e| e.to_string())?;
-                            match res {
-                                mlua::Value::Boolean(true) => Ok(()),
-                                mlua::Value::String(s) => Err(s
-                                    .to_str()
-                                    .map(|s| s.to_string())
-                                    .unwrap_or_else(|_| "Invalid value".to_string())),
-                                _ => Err("Invalid value".to_string()),
-                            }
-                        }))
-                    } else {
-                        None
-                    };
-
-                let answer = (context.prompt_value)(
-                    id.clone(),
-                    meta.r#type.clone(),
-                    meta.description.clone(),
-                    meta.choices.clone(),
-                    meta.default.clone().map(|v| {
-                        v.to_string()
-                            .expect("lua default should be serializable to string")
-                    }),
-                    validator,
-                )
-                .into_lua_err_debug()?;
-
-                return string_to_value_of_type(
-                    &lua,
-                    meta.r#type.clone(),
-                    &answer,
-                    meta.choices.clone(),
+                let meta = find_value_meta(&context.values_meta, &id);
+                // only the type, the default could be a secret
+                debug!(
                     id,
+                    r#type = meta.map(|meta| meta.r#type.as_str()),
+                    "Getting value"
                 );
+                let Some(meta) = meta else {
+                    return Err(eyre!("No value named {} defined", id)).into_lua_err_debug();
+                };
+                get_value(lua, &context, id, meta, meta.description.clone())
             })?,
         )?;
 
@@ -104,6 +34,316 @@ impl LuaValues {
 
         Ok(())
     }
+
+    /// Resolves all declared values up front, the ones in `order` first and the remaining ones
+    /// alphabetically. Returns the resolved values in that order (secrets redacted), values whose
+    /// condition isn't met are left out.
+    pub fn collect(
+        lua: &Lua,
+        context: &Context,
+        order: &[String],
+    ) -> mlua::Result<Vec<(String, String)>> {
+        if let Some(unknown) = order
+            .iter()
+            .find(|id| !context.values_meta.contains_key(*id))
+        {
+            return Err(eyre!("'{}' in order is not a declared value", unknown))
+                .into_lua_err_debug();
+        }
+        let mut remaining: Vec<_> = context
+            .values_meta
+            .keys()
+            .filter(|id| !order.contains(id))
+            .collect();
+        remaining.sort();
+
+        let mut summary = Vec::new();
+        let mut missing = Vec::new();
+        for id in order.iter().chain(remaining) {
+            let meta = &context.values_meta[id];
+            // without input, continue to report every missing value at once
+            if let Err(err) = get_value(lua, context, id.clone(), meta, meta.description.clone()) {
+                match missing_value(&err) {
+                    Some(id) if context.no_input => {
+                        if !missing.contains(&id) {
+                            missing.push(id);
+                        }
+                        continue;
+                    }
+                    _ => return Err(err),
+                }
+            }
+
+            let resolved = context
+                .resolved_values
+                .lock()
+                .expect("lock to not be poisoned");
+            let prefix = format!("{id}.");
+            let mut entries: Vec<_> = resolved
+                .iter()
+                .filter(|(key, _)| *key == id || key.starts_with(&prefix))
+                .collect();
+            entries.sort();
+            for (key, val) in entries {
+                let shown = find_value_meta(&context.values_meta, key)
+                    .and_then(|meta| redact(meta, Some(val)))
+                    .unwrap_or(val);
+                summary.push((key.clone(), shown.to_string()));
+            }
+        }
+        if !missing.is_empty() {
+            return Err(eyre!(
+                "Missing values which have no default (prompting is disabled): {}",
+                missing.join(", ")
+            ))
+            .into_lua_err_debug();
+        }
+        Ok(summary)
+    }
+}
+
+/// Returned by `values.get` if prompting is disabled and a value is neither set nor has a
+/// default.
+#[derive(Debug)]
+pub struct MissingValue(pub String);
+
+impl std::fmt::Display for MissingValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Value '{}' is missing and has no default (prompting is disabled)",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for MissingValue {}
+
+/// The id of the missing value anywhere in `err`'s chain (eg. a condition depending on it).
+fn missing_value(err: &mlua::Error) -> Option<String> {
+    err.chain()
+        .find_map(|err| err.downcast_ref::<MissingValue>())
+        .map(|missing| missing.0.clone())
+}
+
+/// Resolves the value `id`, prompting with `description` if it is not set yet. Objects are
+/// resolved field by field, the fields' ids being `<id>.<field>`. Values whose `when`
+/// condition is not met resolve to nil.
+fn get_value(
+    lua: &Lua,
+    context: &Context,
+    id: String,
+    meta: &ValueMeta,
+    description: String,
+) -> mlua::Result<mlua::Value> {
+    if let Some(condition) = &meta.when {
+        if !condition_met(lua, context, condition)? {
+            debug!(id, %condition, "Condition of value is not met, skipping it");
+            return Ok(mlua::Value::Nil);
+        }
+    }
+
+    if meta.r#type == "object" {
+        let fields = meta
+            .fields
+            .as_ref()
+            .wrap_err(format!("no fields on object type value {id}"))
+            .into_lua_err_debug()?;
+        let mut names: Vec<_> = fields.keys().collect();
+        names.sort();
+        let table = lua.create_table()?;
+        for name in names {
+            let field = &fields[name];
+            let field_description = format!("{description}: {}", field.description);
+            let value = get_value(
+                lua,
+                context,
+                format!("{id}.{name}"),
+                field,
+                field_description,
+            )?;
+            table.set(name.as_str(), value)?;
+        }
+        return Ok(mlua::Value::Table(table));
+    }
+
+    let val = context.values.get(&id).cloned().or_else(|| {
+        let resolved = context
+            .resolved_values
+            .lock()
+            .expect("lock to not be poisoned");
+        resolved.get(&id).cloned()
+    });
+    trace!(id, val = ?redact(meta, val.as_deref()), "Resolving value");
+
+    // 1. if value was already set
+    if let Some(val_str) = val {
+        trace!(id, "Value was already set");
+        if let Err(e) = validate_input(meta, &val_str) {
+            let shown = redact(meta, Some(&val_str)).unwrap_or_default();
+            return Err(eyre!("Value '{}' for '{}' is invalid: {}", shown, id, e))
+                .into_lua_err_debug();
+        }
+        let value = string_to_value_of_type(lua, meta, &val_str, id.clone())?;
+        record_resolved(context, id, val_str);
+        return Ok(value);
+    }
+    let default = match &meta.default {
+        Some(mlua::Value::Function(function)) => {
+            let default: mlua::Value = function.call(values_proxy(lua, context)?)?;
+            trace!(id, default = ?redact(meta, value_string(&default).as_deref()), "Computed default");
+            value_string(&default)
+        }
+        _ => meta.default_string(),
+    };
+
+    // 2. if prompting is disabled, fall back to the default
+    if context.no_input {
+        let Some(default) = default else {
+            return Err(mlua::Error::external(MissingValue(id)));
+        };
+        trace!(id, "Using default without asking");
+        if let Err(e) = validate_input(meta, &default) {
+            let shown = redact(meta, Some(&default)).unwrap_or_default();
+            return Err(eyre!("Default '{}' for '{}' is invalid: {}", shown, id, e))
+                .into_lua_err_debug();
+        }
+        let value = string_to_value_of_type(lua, meta, &default, id.clone())?;
+        record_resolved(context, id, default);
+        return Ok(value);
+    }
+
+    // 3. if value is unset, ask the user
+    trace!(id, "Asking user for value...");
+
+    let validator: Option<ValidatorFn> = (meta.validate.is_some() || meta.is_number()).then(|| {
+        let meta = meta.clone();
+        Arc::new(move |input: &str| validate_input(&meta, input)) as ValidatorFn
+    });
+
+    let answer = (context.prompt_value)(
+        id.clone(),
+        meta.r#type.clone(),
+        description,
+        meta.choices.clone(),
+        default,
+        validator,
+    )
+    .into_lua_err_debug()?;
+
+    let value = string_to_value_of_type(lua, meta, &answer, id.clone())?;
+    record_resolved(context, id, answer);
+    Ok(value)
+}
+
+/// Evaluates a `when` condition. Other values are accessible by name (expressions) or through
+/// the table passed as the first argument (functions), see [`values_proxy`].
+fn condition_met(lua: &Lua, context: &Context, condition: &Condition) -> mlua::Result<bool> {
+    let values = values_proxy(lua, context)?;
+    let result: mlua::Value = match condition {
+        Condition::Expression(expression) => lua
+            .load(format!("return {expression}"))
+            .set_name(format!("when: {expression}"))
+            .set_environment(values)
+            .eval()?,
+        Condition::Function(function) => function.call(values)?,
+    };
+    Ok(!matches!(
+        result,
+        mlua::Value::Nil | mlua::Value::Boolean(false)
+    ))
+}
+
+/// Table resolving the values by name like `values.get`, for conditions and computed defaults.
+/// Other names fall back to the globals, so expressions can still use eg. `string.match`.
+fn values_proxy(lua: &Lua, context: &Context) -> mlua::Result<mlua::Table> {
+    let values = lua.create_table()?;
+    let metatable = lua.create_table()?;
+    let context = context.clone();
+    metatable.set(
+        "__index",
+        lua.create_function(move |lua, (_, name): (mlua::Value, String)| {
+            match find_value_meta(&context.values_meta, &name) {
+                Some(meta) => get_value(lua, &context, name, meta, meta.description.clone()),
+                None => lua.globals().get(name),
+            }
+        })?,
+    )?;
+    values.set_metatable(Some(metatable))?;
+    Ok(values)
+}
+
+/// Hides the value of secrets, for logs and error messages.
+fn redact<'a>(meta: &ValueMeta, val: Option<&'a str>) -> Option<&'a str> {
+    match val {
+        Some(_) if meta.is_secret() => Some("<secret>"),
+        val => val,
+    }
+}
+
+fn record_resolved(context: &Context, id: String, val: String) {
+    context
+        .resolved_values
+        .lock()
+        .expect("lock to not be poisoned")
+        .insert(id, val);
+}
+
+/// Checks `val` against the constraints of the value's type and its `validate` function (for
+/// lists once per item).
+fn validate_input(meta: &ValueMeta, val: &str) -> Result<(), String> {
+    if meta.is_number() {
+        parse_number(meta, val)?;
+    }
+    if meta.is_list() {
+        return split_list(val)
+            .iter()
+            .try_for_each(|item| validate_single(meta.validate.as_ref(), item));
+    }
+    validate_single(meta.validate.as_ref(), val)
+}
+
+/// Parses a `number` (integer or float), `integer` or `float` value and checks its range.
+fn parse_number(meta: &ValueMeta, val: &str) -> Result<mlua::Value, String> {
+    let val = val.trim();
+    let parse_float = |kind: &str| {
+        val.parse::<f64>()
+            .ok()
+            .filter(|number| number.is_finite())
+            .ok_or_else(|| format!("failed to parse value as {kind}"))
+    };
+    let value = match meta.r#type.as_str() {
+        "integer" => mlua::Value::Integer(
+            val.parse::<i64>()
+                .map_err(|_| "failed to parse value as an integer".to_string())?,
+        ),
+        "float" => mlua::Value::Number(parse_float("a float")?),
+        _ => match val.parse::<i64>() {
+            Ok(integer) => mlua::Value::Integer(integer),
+            Err(_) => mlua::Value::Number(parse_float("a number")?),
+        },
+    };
+    let number = match value {
+        mlua::Value::Integer(integer) => integer as f64,
+        mlua::Value::Number(number) => number,
+        _ => unreachable!("only numbers are parsed"),
+    };
+    meta.check_range(number)?;
+    Ok(value)
+}
+
+/// Runs the `validate` function of a value (for lists once per item).
+fn validate_single(validate: Option<&mlua::Function>, val: &str) -> Result<(), String> {
+    let Some(validate) = validate else {
+        return Ok(());
+    };
+    let res: mlua::Value = validate.call(val.to_string()).map_err(|e| e.to_string())?;
+    match res {
+        mlua::Value::Boolean(true) => Ok(()),
+        mlua::Value::Boolean(false) => Err("validate function rejected value".to_string()),
+        mlua::Value::String(s) => Err(s.to_string_lossy()),
+        _ => Err("Invalid return value from validate fn".to_string()),
+    }
 }
 
 fn validate_enum_contains(
@@ -117,26 +357,38 @@ fn validate_enum_contains(
     if !choices.contains(val) {
         return Err(eyre!("Invalid choice for enum: {}", val)).into_lua_err_debug();
     }
-    Ok(val.clone().into_lua(lua)?)
+    val.clone().into_lua(lua)
 }
 
 fn string_to_value_of_type(
     lua: &mlua::Lua,
-    val_type: String,
+    meta: &ValueMeta,
     val: &String,
-    choices: Option<Vec<String>>,
     id: String,
 ) -> Result<mlua::Value, mlua::Error> {
-    let value = match val_type.as_str() {
-        "string" => val.clone().into_lua(lua)?,
-        "enum" => validate_enum_contains(&lua, choices.clone(), val)
+    let choices = meta.choices.clone();
+    let value = match meta.r#type.as_str() {
+        "string" | "secret" => val.clone().into_lua(lua)?,
+        "enum" => validate_enum_contains(lua, choices.clone(), val)
             .wrap_err(format!("on value {id}"))
             .into_lua_err_debug()?,
-        "number" => val
-            .parse::<usize>()
-            .wrap_err("failed to parse value as a number")
-            .into_lua_err_debug()?
-            .into_lua(lua)?,
+        "number" | "integer" | "float" => parse_number(meta, val)
+            .map_err(|err| eyre!("{err} on value {id}"))
+            .into_lua_err_debug()?,
+        "list" => lua.create_sequence_from(split_list(val))?.into_lua(lua)?,
+        "multiselect" => {
+            let choices = choices
+                .wrap_err("no choices on multiselect type value")
+                .into_lua_err_debug()?;
+            let items = split_list(val);
+            if let Some(invalid) = items.iter().find(|item| !choices.contains(item)) {
+                return Err(eyre!(
+                    "Invalid choice for multiselect on value {id}: {invalid}"
+                ))
+                .into_lua_err_debug();
+            }
+            lua.create_sequence_from(items)?.into_lua(lua)?
+        }
         "bool" => val
             .parse::<bool>()
             .wrap_err("failed to parse value as a bool")
@@ -200,6 +452,7 @@ mod tests {
                 default: None,
                 choices: None,
                 validate: None,
+                ..Default::default()
             },
         );
 
@@ -232,6 +485,7 @@ mod tests {
                 default: None,
                 choices: None,
                 validate: None,
+                ..Default::default()
             },
         );
 
@@ -264,6 +518,7 @@ mod tests {
                 default: None,
                 choices: None,
                 validate: None,
+                ..Default::default()
             },
         );
 
@@ -300,6 +555,7 @@ mod tests {
                     "blue".to_string(),
                 ]),
                 validate: None,
+                ..Default::default()
             },
         );
 
@@ -336,6 +592,7 @@ mod tests {
                     "blue".to_string(),
                 ]),
                 validate: None,
+                ..Default::default()
             },
         );
 
@@ -369,15 +626,14 @@ mod tests {
             ValueMeta {
                 r#type: "enum".to_string(),
                 description: "Color choice".to_string(),
-                default: Some(mlua::Value::String(
-                    lua.create_string("yellow".to_string())?,
-                )),
+                default: Some(mlua::Value::String(lua.create_string("yellow")?)),
                 choices: Some(vec![
                     "red".to_string(),
                     "green".to_string(),
                     "blue".to_string(),
                 ]),
                 validate: None,
+                ..Default::default()
             },
         );
 
@@ -429,6 +685,53 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_prompted_value_is_recorded_and_reused() -> eyre::Result<()> {
+        let lua = Lua::new();
+
+        let mut values_meta = HashMap::new();
+        values_meta.insert(
+            "name".to_string(),
+            ValueMeta {
+                r#type: "string".to_string(),
+                description: "User name".to_string(),
+                default: None,
+                choices: None,
+                validate: None,
+                ..Default::default()
+            },
+        );
+
+        let prompts = Arc::new(std::sync::Mutex::new(0));
+        let prompts_clone = prompts.clone();
+        let context = Context {
+            values_meta,
+            prompt_value: Arc::new(move |_name, _type, _desc, _choices, _default, _validator| {
+                *prompts_clone.lock().unwrap() += 1;
+                Ok("Jane".to_string())
+            }),
+            ..Default::default()
+        };
+        let resolved_values = context.resolved_values.clone();
+
+        let result = eval_lua_with_context(
+            &lua,
+            r#"
+                return values.get("name") .. values.get("name")
+            "#,
+            context,
+        )?;
+
+        assert_eq!(result.as_string().unwrap().to_str()?, "JaneJane");
+        assert_eq!(*prompts.lock().unwrap(), 1);
+        assert_eq!(
+            resolved_values.lock().unwrap().get("name"),
+            Some(&"Jane".to_string())
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_get_nonexistent_value() -> eyre::Result<()> {
         let lua = Lua::new();
@@ -469,6 +772,7 @@ mod tests {
                 default: None,
                 choices: None,
                 validate: None,
+                ..Default::default()
             },
         );
 
@@ -505,6 +809,7 @@ mod tests {
                 default: None,
                 choices: None,
                 validate: None,
+                ..Default::default()
             },
         );
 
@@ -541,6 +846,7 @@ mod tests {
                 default: None,
                 choices: None, // No choices defined for enum
                 validate: None,
+                ..Default::default()
             },
         );
 
@@ -577,6 +883,7 @@ mod tests {
                 default: None,
                 choices: None,
                 validate: None,
+                ..Default::default()
             },
         );
 
@@ -613,6 +920,7 @@ mod tests {
                 default: None,
                 choices: None,
                 validate: None,
+                ..Default::default()
             },
         );
         values_meta.insert(
@@ -623,6 +931,7 @@ mod tests {
                 default: None,
                 choices: None,
                 validate: None,
+                ..Default::default()
             },
         );
         values_meta.insert(
@@ -633,6 +942,7 @@ mod tests {
                 default: None,
                 choices: None,
                 validate: None,
+                ..Default::default()
             },
         );
 
@@ -779,4 +1089,524 @@ mod tests {
         assert!(err.contains("Custom error"));
         Ok(())
     }
+
+    fn meta_from_lua(lua: &Lua, code: &str) -> eyre::Result<ValueMeta> {
+        let meta_table: mlua::Table = lua.load(code).eval()?;
+        Ok(ValueMeta::from_lua(mlua::Value::Table(meta_table), lua)?)
+    }
+
+    #[test]
+    fn test_get_list_value() -> eyre::Result<()> {
+        let lua = Lua::new();
+        let mut values = HashMap::new();
+        values.insert("ports".to_string(), "80, 443,,8080".to_string());
+
+        let mut values_meta = HashMap::new();
+        values_meta.insert(
+            "ports".to_string(),
+            meta_from_lua(
+                &lua,
+                r#"
+                    {
+                        type = "list",
+                        description = "Ports",
+                        validate = function(v) return tonumber(v) ~= nil or "not a port" end
+                    }
+                "#,
+            )?,
+        );
+
+        let context = create_test_context(values, values_meta, None);
+        execute_lua_with_context(
+            &lua,
+            r#"
+                local ports = values.get("ports")
+                assert(#ports == 3)
+                assert(ports[1] == "80" and ports[2] == "443" and ports[3] == "8080")
+            "#,
+            context,
+        )
+    }
+
+    #[test]
+    fn test_list_validation_runs_per_item() -> eyre::Result<()> {
+        let lua = Lua::new();
+        let mut values = HashMap::new();
+        values.insert("ports".to_string(), "80,http".to_string());
+
+        let mut values_meta = HashMap::new();
+        values_meta.insert(
+            "ports".to_string(),
+            meta_from_lua(
+                &lua,
+                r#"
+                    {
+                        type = "list",
+                        description = "Ports",
+                        validate = function(v) return tonumber(v) ~= nil or "not a port: " .. v end
+                    }
+                "#,
+            )?,
+        );
+
+        let context = create_test_context(values, values_meta, None);
+        let err = execute_lua_with_context(&lua, r#"values.get("ports")"#, context).unwrap_err();
+        assert!(err.to_string().contains("not a port: http"), "{err}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_multiselect_value() -> eyre::Result<()> {
+        let lua = Lua::new();
+        let meta = r#"
+            {
+                type = "multiselect",
+                description = "Features",
+                choices = { "serde", "tokio", "tracing" },
+            }
+        "#;
+        let mut values_meta = HashMap::new();
+        values_meta.insert("features".to_string(), meta_from_lua(&lua, meta)?);
+
+        let values = HashMap::from([("features".to_string(), "tracing,serde".to_string())]);
+        let context = create_test_context(values, values_meta.clone(), None);
+        execute_lua_with_context(
+            &lua,
+            r#"
+                local features = values.get("features")
+                assert(#features == 2)
+                assert(features[1] == "tracing" and features[2] == "serde")
+            "#,
+            context,
+        )?;
+
+        let values = HashMap::from([("features".to_string(), "serde,rayon".to_string())]);
+        let context = create_test_context(values, values_meta, None);
+        let err = execute_lua_with_context(&lua, r#"values.get("features")"#, context).unwrap_err();
+        assert!(
+            format!("{err:?}").contains("Invalid choice for multiselect on value features: rayon")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_prompt_list_with_default() -> eyre::Result<()> {
+        let lua = Lua::new();
+        let mut values_meta = HashMap::new();
+        values_meta.insert(
+            "crates".to_string(),
+            meta_from_lua(
+                &lua,
+                r#"{ type = "list", description = "Crates", default = { "serde", "eyre" } }"#,
+            )?,
+        );
+
+        let context = Context {
+            values_meta,
+            prompt_value: Arc::new(|_, r#type, _, _, default, _| {
+                assert_eq!(r#type, "list");
+                assert_eq!(default.as_deref(), Some("serde,eyre"));
+                Ok(default.unwrap())
+            }),
+            ..Default::default()
+        };
+        execute_lua_with_context(
+            &lua,
+            r#"
+                local crates = values.get("crates")
+                assert(#crates == 2 and crates[1] == "serde" and crates[2] == "eyre")
+            "#,
+            context,
+        )
+    }
+
+    #[test]
+    fn test_prompt_with_computed_default() -> eyre::Result<()> {
+        let lua = Lua::new();
+        let values_meta = HashMap::from([
+            (
+                "project_name".to_string(),
+                meta_from_lua(&lua, r#"{ type = "string", description = "Project" }"#)?,
+            ),
+            (
+                "crate_name".to_string(),
+                meta_from_lua(
+                    &lua,
+                    r#"
+                        {
+                            type = "string",
+                            description = "Crate",
+                            default = function(v)
+                                return v.project_name:lower():gsub("[^%w]+", "_")
+                            end,
+                        }
+                    "#,
+                )?,
+            ),
+            (
+                "nothing".to_string(),
+                meta_from_lua(
+                    &lua,
+                    r#"{ type = "string", description = "Nothing", default = function() end }"#,
+                )?,
+            ),
+        ]);
+        let values = HashMap::from([("project_name".to_string(), "My Project".to_string())]);
+
+        let context = Context {
+            values_meta,
+            values,
+            prompt_value: Arc::new(|id, _, _, _, default, _| {
+                match id.as_str() {
+                    "crate_name" => assert_eq!(default.as_deref(), Some("my_project")),
+                    _ => assert_eq!(default, None),
+                }
+                Ok(default.unwrap_or_default())
+            }),
+            ..Default::default()
+        };
+        execute_lua_with_context(
+            &lua,
+            r#"
+                assert(values.get("crate_name") == "my_project")
+                assert(values.get("nothing") == "")
+            "#,
+            context,
+        )
+    }
+
+    #[test]
+    fn test_get_object_value() -> eyre::Result<()> {
+        let lua = Lua::new();
+        let mut values_meta = HashMap::new();
+        values_meta.insert(
+            "db".to_string(),
+            meta_from_lua(
+                &lua,
+                r#"
+                    {
+                        type = "object",
+                        description = "Database",
+                        fields = {
+                            host = { type = "string", description = "Host" },
+                            port = { type = "number", description = "Port" },
+                            auth = {
+                                type = "object",
+                                description = "Auth",
+                                fields = { user = { type = "string", description = "User" } },
+                            },
+                        },
+                    }
+                "#,
+            )?,
+        );
+
+        let prompts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let prompts_clone = prompts.clone();
+        let context = Context {
+            values: HashMap::from([
+                ("db.host".to_string(), "localhost".to_string()),
+                ("db.auth.user".to_string(), "admin".to_string()),
+            ]),
+            values_meta,
+            prompt_value: Arc::new(move |name, _, description, _, _, _| {
+                prompts_clone.lock().unwrap().push((name, description));
+                Ok("5432".to_string())
+            }),
+            ..Default::default()
+        };
+        let resolved = context.resolved_values.clone();
+        execute_lua_with_context(
+            &lua,
+            r#"
+                local db = values.get("db")
+                assert(db.host == "localhost")
+                assert(db.port == 5432)
+                assert(db.auth.user == "admin")
+                assert(values.get("db.port") == 5432)
+            "#,
+            context,
+        )?;
+
+        assert_eq!(
+            *prompts.lock().unwrap(),
+            vec![("db.port".to_string(), "Database: Port".to_string())]
+        );
+        assert_eq!(
+            resolved.lock().unwrap().get("db.port"),
+            Some(&"5432".to_string())
+        );
+        Ok(())
+    }
+
+    fn get_number(lua: &Lua, meta: &str, val: &str) -> eyre::Result<mlua::Value> {
+        let values_meta = HashMap::from([("n".to_string(), meta_from_lua(lua, meta)?)]);
+        let values = HashMap::from([("n".to_string(), val.to_string())]);
+        eval_lua_with_context(
+            lua,
+            r#"return values.get("n")"#,
+            create_test_context(values, values_meta, None),
+        )
+    }
+
+    #[test]
+    fn test_numeric_types() -> eyre::Result<()> {
+        let lua = Lua::new();
+        let integer = r#"{ type = "integer", description = "n" }"#;
+        let float = r#"{ type = "float", description = "n" }"#;
+        let number = r#"{ type = "number", description = "n" }"#;
+
+        assert_eq!(get_number(&lua, integer, "-1")?, mlua::Value::Integer(-1));
+        assert!(get_number(&lua, integer, "0.5").is_err());
+        assert_eq!(get_number(&lua, float, "0.5")?, mlua::Value::Number(0.5));
+        assert_eq!(get_number(&lua, float, "2")?, mlua::Value::Number(2.0));
+        assert!(get_number(&lua, float, "inf").is_err());
+        assert_eq!(get_number(&lua, number, "25")?, mlua::Value::Integer(25));
+        assert_eq!(
+            get_number(&lua, number, "-0.25")?,
+            mlua::Value::Number(-0.25)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_numeric_range() -> eyre::Result<()> {
+        let lua = Lua::new();
+        let port = r#"{ type = "integer", description = "n", min = 1, max = 65535 }"#;
+        let timeout = r#"{ type = "float", description = "n", min = 0.5, step = 0.25 }"#;
+
+        assert!(get_number(&lua, port, "8080").is_ok());
+        let err = get_number(&lua, port, "0").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("0 is smaller than the minimum of 1"),
+            "{err}"
+        );
+        let err = get_number(&lua, port, "70000").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("70000 is larger than the maximum of 65535")
+        );
+
+        assert!(get_number(&lua, timeout, "1.25").is_ok());
+        let err = get_number(&lua, timeout, "1.3").unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("1.3 is not a multiple of the step 0.25")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_prompt_validates_numeric_range() -> eyre::Result<()> {
+        let lua = Lua::new();
+        let values_meta = HashMap::from([(
+            "port".to_string(),
+            meta_from_lua(
+                &lua,
+                r#"{ type = "integer", description = "Port", max = 100 }"#,
+            )?,
+        )]);
+        let context = Context {
+            values_meta,
+            prompt_value: Arc::new(|_, _, _, _, _, validator| {
+                let validator = validator.expect("numbers to have a validator");
+                assert!(validator("abc").is_err());
+                assert!(validator("101").is_err());
+                assert!(validator("99").is_ok());
+                Ok("99".to_string())
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            eval_lua_with_context(&lua, r#"return values.get("port")"#, context)?,
+            mlua::Value::Integer(99)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_secret_is_not_shown() -> eyre::Result<()> {
+        let lua = Lua::new();
+        let values_meta = HashMap::from([(
+            "token".to_string(),
+            meta_from_lua(
+                &lua,
+                r#"
+                    {
+                        type = "secret",
+                        description = "API token",
+                        validate = function(v) return #v > 10 or "too short" end
+                    }
+                "#,
+            )?,
+        )]);
+        let values = HashMap::from([("token".to_string(), "hunter2".to_string())]);
+        let context = create_test_context(values, values_meta, None);
+
+        let err = execute_lua_with_context(&lua, r#"values.get("token")"#, context).unwrap_err();
+        let err = format!("{err:?}");
+        assert!(err.contains("Value '<secret>' for 'token' is invalid: too short"));
+        assert!(!err.contains("hunter2"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_conditional_values() -> eyre::Result<()> {
+        let lua = Lua::new();
+        let values_meta = HashMap::from([
+            (
+                "database".to_string(),
+                meta_from_lua(&lua, r#"{ type = "string", description = "Database" }"#)?,
+            ),
+            (
+                "db_password".to_string(),
+                meta_from_lua(
+                    &lua,
+                    r#"{ type = "secret", description = "Password", when = 'database ~= "none"' }"#,
+                )?,
+            ),
+            (
+                "db_port".to_string(),
+                meta_from_lua(
+                    &lua,
+                    r#"
+                        {
+                            type = "integer",
+                            description = "Port",
+                            when = function(v) return v.database == "postgres" end,
+                        }
+                    "#,
+                )?,
+            ),
+        ]);
+
+        let values = HashMap::from([
+            ("database".to_string(), "none".to_string()),
+            ("db_password".to_string(), "hunter2".to_string()),
+        ]);
+        let context = create_test_context(values, values_meta.clone(), None);
+        execute_lua_with_context(
+            &lua,
+            r#"
+                assert(values.get("db_password") == nil)
+                assert(values.get("db_port") == nil)
+            "#,
+            context.clone(),
+        )?;
+        assert!(
+            !context
+                .resolved_values
+                .lock()
+                .unwrap()
+                .contains_key("db_password")
+        );
+
+        let values = HashMap::from([
+            ("database".to_string(), "postgres".to_string()),
+            ("db_password".to_string(), "hunter2".to_string()),
+            ("db_port".to_string(), "5432".to_string()),
+        ]);
+        let context = create_test_context(values, values_meta, None);
+        execute_lua_with_context(
+            &lua,
+            r#"
+                assert(values.get("db_password") == "hunter2")
+                assert(values.get("db_port") == 5432)
+            "#,
+            context,
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_condition_prompts_for_dependencies() -> eyre::Result<()> {
+        let lua = Lua::new();
+        let values_meta = HashMap::from([
+            (
+                "database".to_string(),
+                meta_from_lua(&lua, r#"{ type = "string", description = "Database" }"#)?,
+            ),
+            (
+                "db_password".to_string(),
+                meta_from_lua(
+                    &lua,
+                    r#"{ type = "string", description = "Password", when = 'database ~= "none"' }"#,
+                )?,
+            ),
+        ]);
+        let context = Context {
+            values_meta,
+            prompt_value: Arc::new(|id, _, _, _, _, _| {
+                assert_eq!(id, "database", "db_password should not be asked for");
+                Ok("none".to_string())
+            }),
+            ..Default::default()
+        };
+        assert_eq!(
+            eval_lua_with_context(&lua, r#"return values.get("db_password")"#, context)?,
+            mlua::Value::Nil
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_input_reports_all_missing_values() -> eyre::Result<()> {
+        let lua = Lua::new();
+        let values_meta = HashMap::from([
+            (
+                "name".to_string(),
+                meta_from_lua(&lua, r#"{ type = "string", description = "Name" }"#)?,
+            ),
+            (
+                "crate_name".to_string(),
+                meta_from_lua(
+                    &lua,
+                    r#"{ type = "string", description = "Crate", default = function(v) return v.name end }"#,
+                )?,
+            ),
+            (
+                "port".to_string(),
+                meta_from_lua(
+                    &lua,
+                    r#"{ type = "integer", description = "Port", default = 8080 }"#,
+                )?,
+            ),
+            (
+                "token".to_string(),
+                meta_from_lua(&lua, r#"{ type = "secret", description = "Token" }"#)?,
+            ),
+        ]);
+        let context = Context {
+            values_meta,
+            no_input: true,
+            prompt_value: Arc::new(|id, _, _, _, _, _| panic!("prompted for {id}")),
+            ..Default::default()
+        };
+        LuaValues::register(&lua, context.clone())?;
+
+        let err = LuaValues::collect(&lua, &context, &[]).unwrap_err();
+        assert!(
+            err.to_string().contains(
+                "Missing values which have no default (prompting is disabled): name, token"
+            )
+        );
+
+        let values = HashMap::from([
+            ("name".to_string(), "demo".to_string()),
+            ("token".to_string(), "hunter2".to_string()),
+        ]);
+        let context = Context { values, ..context };
+        let summary = LuaValues::collect(&lua, &context, &[])?;
+        assert_eq!(
+            summary,
+            [
+                ("crate_name".to_string(), "demo".to_string()),
+                ("name".to_string(), "demo".to_string()),
+                ("port".to_string(), "8080".to_string()),
+                ("token".to_string(), "<secret>".to_string()),
+            ]
+        );
+        Ok(())
+    }
 }
@@ -1,10 +1,15 @@
 use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
 use eyre::{Result, WrapErr};
-use kenchiku_common::Context;
+use kenchiku_common::{
+    Context, Limits, ValidatorFn,
+    meta::ValueMeta,
+    values::{flatten_values, json_to_value_string},
+};
 use kenchiku_scaffold::{
     Scaffold,
     discovery::{discover_scaffold, find_all_scaffolds},
+    patch_set::PatchSet,
 };
 use rmcp::{
     ServerHandler, ServiceExt,
@@ -33,6 +38,7 @@ struct ConstructArgs {
     /// Values to pass to the scaffold. Always use the `show` tool to see which values are required
     /// and what types they are etc.
     /// A simple dictionary of keys being the value names and values being their values.
+    /// Use arrays for `list` and `multiselect` values.
     values: Option<HashMap<String, serde_json::Value>>,
     /// Output/target path to construct to. Optional, defaults to working directory.
     output: Option<String>,
@@ -45,11 +51,19 @@ struct PatchArgs {
     /// Values to pass to the patch. Always use the `show` tool to see which values are required
     /// and what types they are etc.
     /// A simple dictionary of keys being the value names and values being their values.
+    /// Use arrays for `list` and `multiselect` values.
     values: Option<HashMap<String, serde_json::Value>>,
     /// Output/target path to run patch in. Optional, defaults to working directory.
     output: Option<String>,
 }
 
+#[derive(serde::Deserialize, schemars::JsonSchema)]
+struct ListArgs {
+    /// Project directory to check the patches against. Optional, if set every patch is marked
+    /// as applicable, not applicable or already applied to this project.
+    project: Option<String>,
+}
+
 #[derive(serde::Deserialize, schemars::JsonSchema)]
 struct ShowArgs {
     /// Name of the thing to show. Use a normal string to show a scaffold,
@@ -59,7 +73,7 @@ struct ShowArgs {
 
 #[derive(serde::Deserialize, schemars::JsonSchema)]
 struct ProvideValuesArgs {
-    /// Values to provide to the current session.
+    /// Values to provide to the current session. Use arrays for `list` and `multiselect` values.
     values: HashMap<String, serde_json::Value>,
 }
 
@@ -83,7 +97,7 @@ impl KenchikuMcpServer {
         F: FnOnce(
                 Scaffold,
             ) -> eyre::Result<(
-                HashMap<String, kenchiku_common::meta::ValueMeta>,
+                HashMap<String, ValueMeta>,
                 Box<dyn FnOnce(Context) -> eyre::Result<String> + Send>,
             )> + Send
             + 'static,
@@ -117,7 +131,7 @@ impl KenchikuMcpServer {
                 kenchiku_common::get_env_values()
                     .into_iter()
                     .map(|(k, v)| (k, serde_json::Value::String(v)))
-                    .chain(values.unwrap_or_default().into_iter())
+                    .chain(values.unwrap_or_default())
                     .collect();
 
             let (cmd_tx, cmd_rx) = std::sync::mpsc::channel::<HashMap<String, serde_json::Value>>();
@@ -132,9 +146,13 @@ impl KenchikuMcpServer {
                 let (values_meta, operation) = setup_operation(scaffold)?;
 
                 let cmd_rx = std::sync::Mutex::new(cmd_rx);
-                let current_values = Arc::new(std::sync::Mutex::new(provided_values_clone));
+                let current_values = Arc::new(std::sync::Mutex::new(flatten_values(
+                    provided_values_clone,
+                    &values_meta,
+                )?));
 
                 let current_values_clone = current_values.clone();
+                let values_meta_clone = values_meta.clone();
 
                 let prompt_value = Arc::new(
                     move |name: String,
@@ -142,23 +160,21 @@ impl KenchikuMcpServer {
                           description: String,
                           choices: Option<Vec<String>>,
                           _default: Option<String>,
-                          validator: Option<
-                        Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync>,
-                    >|
+                          validator: Option<ValidatorFn>|
                           -> eyre::Result<String> {
+                        let mut error_msg = None;
                         loop {
-                            let error_msg = &mut None;
                             {
                                 let mut values = current_values_clone.lock().unwrap();
                                 if let Some(val) = values.get(&name) {
-                                    let val_str = val.to_string().trim_matches('"').to_string();
-                                    if let Some(validator) = &validator {
-                                        if let Err(e) = validator(&val_str) {
-                                            *error_msg = Some(e.to_string());
+                                    let val_str = json_to_value_string(val);
+                                    match validator.as_ref().map(|validator| validator(&val_str)) {
+                                        Some(Err(e)) => {
+                                            error_msg = Some(e);
                                             values.remove(&name);
                                         }
+                                        _ => return Ok(val_str),
                                     }
-                                    return Ok(val_str);
                                 }
                             }
 
@@ -175,8 +191,13 @@ impl KenchikuMcpServer {
                             // Wait for new values from the model
                             let rx = cmd_rx.lock().unwrap();
                             if let Ok(new_values) = rx.recv() {
-                                let mut values = current_values_clone.lock().unwrap();
-                                values.extend(new_values);
+                                match flatten_values(new_values, &values_meta_clone) {
+                                    Ok(new_values) => {
+                                        current_values_clone.lock().unwrap().extend(new_values);
+                                        error_msg = None;
+                                    }
+                                    Err(e) => error_msg = Some(e.to_string()),
+                                }
                             } else {
                                 return Err(eyre::eyre!("Session cancelled"));
                             }
@@ -184,27 +205,22 @@ impl KenchikuMcpServer {
                     },
                 );
 
-                let mut temp_dir = tempfile::tempdir()?;
-
                 let context = Context {
-                    working_dir: temp_dir.path().to_path_buf(),
+                    working_dir: output_path_clone.clone(),
                     scaffold_dir: scaffold_path,
                     output: output_path_clone,
                     values: current_values
                         .lock()
                         .unwrap()
                         .iter()
-                        .map(|(k, v)| (k.clone(), v.to_string().trim_matches('"').to_string()))
+                        .map(|(k, v)| (k.clone(), json_to_value_string(v)))
                         .collect(),
                     values_meta,
                     prompt_value,
                     ..Default::default()
                 };
 
-                let msg = operation(context)?;
-                // only disable cleanup if we constructed successfully
-                temp_dir.disable_cleanup(true);
-                Ok(msg)
+                operation(context)
             });
             tokio::select! {
                 Some(crate::session::Status::MissingValue(missing)) = status_rx.recv() => {
@@ -216,8 +232,8 @@ impl KenchikuMcpServer {
                         join_handle: Some(join_handle),
                     });
                     format!(
-                        "Missing value: {}. Description: {}. Type: {}. Please use `provide_values` to supply it.",
-                        missing.name, missing.description, missing.r#type
+                        "{}. Description: {}. Type: {}. Please use `provide_values` to supply it.",
+                        missing, missing.description, missing.r#type
                     )
                 }
                 result = &mut join_handle => {
@@ -250,8 +266,14 @@ impl KenchikuMcpServer {
         let scaffold_name_clone = scaffold_name.clone();
         self.start_session(scaffold_name, values, output, move |scaffold| {
             let meta = scaffold.meta.values.clone();
-            let op = Box::new(move |ctx| {
-                scaffold.construct(ctx)?;
+            let op = Box::new(move |ctx: Context| {
+                let mut temp_dir = tempfile::tempdir()?;
+                scaffold.construct(Context {
+                    working_dir: temp_dir.path().to_path_buf(),
+                    ..ctx
+                })?;
+                // only disable cleanup if we constructed successfully
+                temp_dir.disable_cleanup(true);
                 Ok(format!(
                     "Scaffold '{}' constructed successfully.",
                     scaffold_name_clone
@@ -266,6 +288,8 @@ impl KenchikuMcpServer {
         Patch an existing project. Specify values using the `values` parameter.
         Use the `show` tool to find out what values the patch wants.
         If you are unsure about some values, ask the user.
+        The patch runs against a copy of the project and its changes are only applied if it
+        succeeds. Returns the created (+), modified (~) and deleted (-) files.
     ")]
     pub async fn patch(
         &self,
@@ -284,22 +308,29 @@ impl KenchikuMcpServer {
         let patch_name_clone = patch_name.clone();
 
         self.start_session(scaffold_name, values, output, move |scaffold| {
-            let patch_meta = match scaffold.meta.patches.get(&patch_name_clone) {
-                Some(meta) => meta,
-                None => {
-                    return Err(eyre::eyre!(
-                        "Patch '{}' not found in scaffold '{}'.",
-                        patch_name_clone,
-                        scaffold_name_clone
-                    ));
-                }
-            };
-            let meta = patch_meta.values.clone();
+            if !scaffold.meta.patches.contains_key(&patch_name_clone) {
+                return Err(eyre::eyre!(
+                    "Patch '{}' not found in scaffold '{}'.",
+                    patch_name_clone,
+                    scaffold_name_clone
+                ));
+            }
+            // patches it requires run first and may ask for further values
+            let patch_set = PatchSet::for_scaffold(scaffold, &patch_name_clone, |name| {
+                discover_scaffold(name.to_string())
+                    .ok_or(eyre::eyre!("Scaffold '{name}' not found"))
+                    .and_then(Scaffold::load)
+            })?;
+            let meta = patch_set.values_meta();
             let op = Box::new(move |ctx| {
-                scaffold.call_patch(&patch_name_clone, ctx)?;
+                let plan = patch_set.apply(ctx)?;
+                let mut changes = Vec::new();
+                plan.print(&mut changes)?;
                 Ok(format!(
-                    "Patch '{}:{}' executed successfully.",
-                    scaffold_name_clone, patch_name_clone
+                    "Patch '{}:{}' executed successfully.\n{}",
+                    scaffold_name_clone,
+                    patch_name_clone,
+                    String::from_utf8(changes)?
                 ))
             });
             Ok((meta, op))
@@ -325,7 +356,7 @@ impl KenchikuMcpServer {
                 session.values.extend(values.clone());
 
                 // Send values to execution thread
-                if let Err(_) = session.value_sender.send(values) {
+                if session.value_sender.send(values).is_err() {
                     return "Execution thread died, that's unfortunate.".to_string();
                 }
 
@@ -346,9 +377,9 @@ impl KenchikuMcpServer {
                         session.missing_values = vec![missing.name.clone()];
                         session.status_receiver = Some(rx);
                         session.join_handle = Some(handle);
-                        format!("Missing value: {}. Description: {}. Type: {}. Choices (if enum): {:?}
+                        format!("{}. Description: {}. Type: {}. Choices (if enum): {:?}
                                  Please use `provide_values` to supply it.",
-                            missing.name, missing.description, missing.r#type, missing.choices,
+                            missing, missing.description, missing.r#type, missing.choices,
                         )
                     } else {
                         "Session was cancelled while processing.".to_string()
@@ -375,9 +406,10 @@ impl KenchikuMcpServer {
     #[tool(description = "
         List all available scaffolds and patches. This only gives an overview,
         use the `show` tool to get details & values.
+        Pass a project directory to see which patches apply to it.
     ")]
-    pub async fn list(&self) -> String {
-        tokio::task::spawn_blocking(|| -> eyre::Result<String> {
+    pub async fn list(&self, Parameters(ListArgs { project }): Parameters<ListArgs>) -> String {
+        tokio::task::spawn_blocking(move || -> eyre::Result<String> {
             let found_scaffolds = find_all_scaffolds()
                 .iter()
                 .map(|path| Scaffold::load(path.to_path_buf()))
@@ -389,6 +421,20 @@ impl KenchikuMcpServer {
                 writeln!(writer, "Found scaffolds:")?;
                 for scaffold in found_scaffolds {
                     scaffold.print(&mut writer, false)?;
+                    if let Some(project) = &project {
+                        let context = Context {
+                            output: PathBuf::from(project),
+                            limits: Limits::LOAD,
+                            ..Default::default()
+                        };
+                        writeln!(writer, "Patch status for {project}:")?;
+                        for (name, status) in scaffold.patch_statuses(context) {
+                            match status {
+                                Ok(status) => writeln!(writer, "  - {name}: {status}")?,
+                                Err(err) => writeln!(writer, "  - {name}: check failed: {err:#}")?,
+                            }
+                        }
+                    }
                     writeln!(writer, "======")?;
                 }
             }
@@ -465,6 +511,12 @@ impl KenchikuMcpServer {
     }
 }
 
+impl Default for KenchikuMcpServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[tool_handler(router = self.tool_router)]
 impl ServerHandler for KenchikuMcpServer {
     fn get_info(&self) -> ServerInfo {
@@ -7,10 +7,10 @@ use rmcp::{
     },
     service::{NotificationContext, RequestContext, RunningService, Service},
 };
-use std::sync::Mutex;
 use tokio::io::duplex;
+use tokio::sync::Mutex;
 
-static SEQUENTIAL_MUTEX: Mutex<()> = Mutex::new(());
+static SEQUENTIAL_MUTEX: Mutex<()> = Mutex::const_new(());
 
 struct TestClient {
     info: ClientInfo,
@@ -109,7 +109,7 @@ async fn test_mcp_server_list_tools() {
 
 #[tokio::test]
 async fn test_mcp_server_call_list_tool() {
-    let _lock = SEQUENTIAL_MUTEX.lock().unwrap();
+    let _lock = SEQUENTIAL_MUTEX.lock().await;
     use rmcp::model::{CallToolRequestParam, CallToolResult};
     use std::env;
     use std::path::Path;
@@ -155,9 +155,68 @@ async fn test_mcp_server_call_list_tool() {
     assert!(output.contains("Description: A test scaffold for listing"));
 }
 
+#[tokio::test]
+async fn test_mcp_server_list_tool_checks_patches() {
+    let _lock = SEQUENTIAL_MUTEX.lock().await;
+    use rmcp::model::{CallToolRequestParam, CallToolResult};
+    use serde_json::json;
+    use std::env;
+    use tempfile::tempdir;
+
+    let temp_dir = tempdir().unwrap();
+    let scaffold_dir = temp_dir.path().join("checks");
+    std::fs::create_dir_all(&scaffold_dir).unwrap();
+    std::fs::write(
+        scaffold_dir.join("scaffold.lua"),
+        r#"
+            return {
+                description = "patch checks",
+                construct = function() end,
+                patches = {
+                    cargo = {
+                        description = "needs Cargo.toml",
+                        applies = function() return fs.exists("Cargo.toml") end,
+                        run = function() end,
+                    },
+                    npm = {
+                        description = "needs package.json",
+                        applies = function() return fs.exists("package.json") end,
+                        run = function() end,
+                    },
+                },
+            }
+        "#,
+    )
+    .unwrap();
+    let project_dir = tempdir().unwrap();
+    std::fs::write(project_dir.path().join("Cargo.toml"), "").unwrap();
+
+    env::set_var("KENCHIKU_PATH", temp_dir.path());
+
+    let client = setup_client().await;
+    client.notify_initialized().await.unwrap();
+
+    let result: CallToolResult = client
+        .call_tool(CallToolRequestParam {
+            name: "list".into(),
+            arguments: json!({ "project": project_dir.path() })
+                .as_object()
+                .cloned(),
+        })
+        .await
+        .unwrap();
+
+    assert!(!result.is_error.unwrap_or(false));
+    let output = &result.content[0].as_text().unwrap().text;
+    assert!(
+        output.contains("  - cargo: applicable\n  - npm: not applicable\n"),
+        "{output}"
+    );
+}
+
 #[tokio::test]
 async fn test_mcp_server_session_flow() {
-    let _lock = SEQUENTIAL_MUTEX.lock().unwrap();
+    let _lock = SEQUENTIAL_MUTEX.lock().await;
     use rmcp::model::{CallToolRequestParam, CallToolResult};
     use serde_json::json;
     use std::{collections::HashMap, env, path::Path};
@@ -196,8 +255,13 @@ async fn test_mcp_server_session_flow() {
         .expect("Failed to notify initialized");
 
     // 1. Call construct without values
+    let output_dir = tempdir().unwrap();
     let mut args = HashMap::new();
     args.insert("scaffold_name".to_string(), json!(scaffold_name));
+    args.insert(
+        "output".to_string(),
+        json!(output_dir.path().to_string_lossy()),
+    );
 
     let result: CallToolResult = client
         .call_tool(CallToolRequestParam {
@@ -292,3 +356,259 @@ async fn test_mcp_server_session_flow() {
         "No active session."
     );
 }
+
+#[tokio::test]
+async fn test_mcp_server_patch_modifies_output() {
+    let _lock = SEQUENTIAL_MUTEX.lock().await;
+    use rmcp::model::{CallToolRequestParam, CallToolResult};
+    use serde_json::json;
+    use std::{env, path::Path};
+    use tempfile::tempdir;
+    use tokio::fs;
+
+    let temp_dir = tempdir().unwrap();
+    let temp_dir_path = temp_dir.path().to_string_lossy().to_string();
+    let scaffold_name = "patch-test-scaffold";
+    let scaffold_dir = Path::new(&temp_dir_path).join(scaffold_name);
+    fs::create_dir_all(&scaffold_dir).await.unwrap();
+    let scaffold_content = r#"
+        return {
+            description = "A test scaffold for patching",
+            construct = function() end,
+            patches = {
+                greet = {
+                    description = "Appends a greeting",
+                    values = {
+                        greeting = {
+                            type = "string",
+                            description = "Greeting to append",
+                        },
+                    },
+                    run = function()
+                        local content = fs.read("main.txt", { source = "workdir" })
+                        fs.write("main.txt", content .. values.get("greeting"))
+                        fs.write("new.txt", "new")
+                    end,
+                },
+            },
+        }
+    "#;
+    fs::write(scaffold_dir.join("scaffold.lua"), scaffold_content)
+        .await
+        .unwrap();
+
+    let project_dir = tempdir().unwrap();
+    fs::write(project_dir.path().join("main.txt"), "hello ")
+        .await
+        .unwrap();
+
+    env::set_var("KENCHIKU_PATH", temp_dir_path.clone());
+
+    let client = setup_client().await;
+    client
+        .notify_initialized()
+        .await
+        .expect("Failed to notify initialized");
+
+    let result: CallToolResult = client
+        .call_tool(CallToolRequestParam {
+            name: "patch".into(),
+            arguments: Some(
+                json!({
+                    "name": format!("{scaffold_name}:greet"),
+                    "values": { "greeting": "world" },
+                    "output": project_dir.path().to_string_lossy(),
+                })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ),
+        })
+        .await
+        .expect("Failed to call patch tool");
+
+    let output = &result.content[0].as_text().unwrap().text;
+    assert!(output.contains("executed successfully"));
+    assert!(output.contains("+ new.txt"));
+    assert!(output.contains("~ main.txt"));
+    assert_eq!(
+        fs::read_to_string(project_dir.path().join("main.txt"))
+            .await
+            .unwrap(),
+        "hello world"
+    );
+    assert!(project_dir.path().join("new.txt").exists());
+}
+
+#[tokio::test]
+async fn test_mcp_server_accepts_arrays_for_lists() {
+    let _lock = SEQUENTIAL_MUTEX.lock().await;
+    use rmcp::model::{CallToolRequestParam, CallToolResult};
+    use serde_json::json;
+    use std::{env, path::Path};
+    use tempfile::tempdir;
+    use tokio::fs;
+
+    let temp_dir = tempdir().unwrap();
+    let temp_dir_path = temp_dir.path().to_string_lossy().to_string();
+    let scaffold_name = "list-test-scaffold";
+    let scaffold_dir = Path::new(&temp_dir_path).join(scaffold_name);
+    fs::create_dir_all(&scaffold_dir).await.unwrap();
+    let scaffold_content = r#"
+        return {
+            description = "A test scaffold for list values",
+            construct = function() end,
+            patches = {
+                deps = {
+                    description = "Writes dependencies",
+                    values = {
+                        crates = {
+                            type = "list",
+                            description = "Crates to add",
+                        },
+                        features = {
+                            type = "multiselect",
+                            description = "Features to enable",
+                            choices = { "a", "b", "c" },
+                        },
+                    },
+                    run = function()
+                        local crates = values.get("crates")
+                        local features = values.get("features")
+                        fs.write("deps.txt", table.concat(crates, " ") .. ";" .. table.concat(features, " "))
+                    end,
+                },
+            },
+        }
+    "#;
+    fs::write(scaffold_dir.join("scaffold.lua"), scaffold_content)
+        .await
+        .unwrap();
+
+    let project_dir = tempdir().unwrap();
+
+    env::set_var("KENCHIKU_PATH", temp_dir_path.clone());
+
+    let client = setup_client().await;
+    client
+        .notify_initialized()
+        .await
+        .expect("Failed to notify initialized");
+
+    let result: CallToolResult = client
+        .call_tool(CallToolRequestParam {
+            name: "patch".into(),
+            arguments: Some(
+                json!({
+                    "name": format!("{scaffold_name}:deps"),
+                    "values": { "crates": ["serde", "eyre"], "features": ["c", "a"] },
+                    "output": project_dir.path().to_string_lossy(),
+                })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ),
+        })
+        .await
+        .expect("Failed to call patch tool");
+
+    let output = &result.content[0].as_text().unwrap().text;
+    assert!(output.contains("executed successfully"), "{output}");
+    assert_eq!(
+        fs::read_to_string(project_dir.path().join("deps.txt"))
+            .await
+            .unwrap(),
+        "serde eyre;c a"
+    );
+}
+
+#[tokio::test]
+async fn test_mcp_server_validates_objects() {
+    let _lock = SEQUENTIAL_MUTEX.lock().await;
+    use rmcp::model::{CallToolRequestParam, CallToolResult};
+    use serde_json::json;
+    use std::{env, path::Path};
+    use tempfile::tempdir;
+    use tokio::fs;
+
+    let temp_dir = tempdir().unwrap();
+    let temp_dir_path = temp_dir.path().to_string_lossy().to_string();
+    let scaffold_name = "object-test-scaffold";
+    let scaffold_dir = Path::new(&temp_dir_path).join(scaffold_name);
+    fs::create_dir_all(&scaffold_dir).await.unwrap();
+    let scaffold_content = r#"
+        return {
+            description = "A test scaffold for object values",
+            construct = function() end,
+            patches = {
+                db = {
+                    description = "Writes the database config",
+                    values = {
+                        db = {
+                            type = "object",
+                            description = "Database",
+                            fields = {
+                                host = { type = "string", description = "Host" },
+                                port = { type = "number", description = "Port" },
+                            },
+                        },
+                    },
+                    run = function()
+                        local db = values.get("db")
+                        fs.write("db.txt", db.host .. ":" .. db.port)
+                    end,
+                },
+            },
+        }
+    "#;
+    fs::write(scaffold_dir.join("scaffold.lua"), scaffold_content)
+        .await
+        .unwrap();
+
+    let project_dir = tempdir().unwrap();
+
+    env::set_var("KENCHIKU_PATH", temp_dir_path.clone());
+
+    let client = setup_client().await;
+    client
+        .notify_initialized()
+        .await
+        .expect("Failed to notify initialized");
+
+    let call_patch = |db: serde_json::Value| CallToolRequestParam {
+        name: "patch".into(),
+        arguments: Some(
+            json!({
+                "name": format!("{scaffold_name}:db"),
+                "values": { "db": db },
+                "output": project_dir.path().to_string_lossy(),
+            })
+            .as_object()
+            .unwrap()
+            .clone(),
+        ),
+    };
+
+    let result: CallToolResult = client
+        .call_tool(call_patch(json!({ "host": "localhost", "user": "admin" })))
+        .await
+        .expect("Failed to call patch tool");
+    let output = &result.content[0].as_text().unwrap().text;
+    assert!(
+        output.contains("Unknown field 'user' for value 'db', known fields: host, port"),
+        "{output}"
+    );
+
+    let result: CallToolResult = client
+        .call_tool(call_patch(json!({ "host": "localhost", "port": 5432 })))
+        .await
+        .expect("Failed to call patch tool");
+    let output = &result.content[0].as_text().unwrap().text;
+    assert!(output.contains("executed successfully"), "{output}");
+    assert_eq!(
+        fs::read_to_string(project_dir.path().join("db.txt"))
+            .await
+            .unwrap(),
+        "localhost:5432"
+    );
+}
@@ -27,10 +27,14 @@ pub struct MissingValueError {
 impl std::fmt::Display for MissingValueError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if let Some(err) = &self.error {
-            write!(f, "Missing value: {} (Error: {})", self.name, err)
+            write!(f, "Missing value: {} (Error: {})", self.name, err)?;
         } else {
-            write!(f, "Missing value: {}", self.name)
+            write!(f, "Missing value: {}", self.name)?;
         }
+        if self.r#type == "secret" {
+            write!(f, " (a secret, ask the user for it, never make one up)")?;
+        }
+        Ok(())
     }
 }
 
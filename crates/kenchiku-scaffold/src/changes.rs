@@ -0,0 +1,402 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Context, Result};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::transaction::Transaction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    /// File does not exist in the output yet.
+    Created,
+    /// File exists in the output and its content changes.
+    Modified,
+    /// File exists in the output and gets removed.
+    Deleted,
+    /// File exists in the output and would not be overwritten.
+    Skipped,
+    /// File exists in the output and differs, a real run asks what to do with it.
+    Conflict,
+}
+
+impl ChangeKind {
+    fn symbol(&self) -> char {
+        match self {
+            ChangeKind::Created => '+',
+            ChangeKind::Modified => '~',
+            ChangeKind::Deleted => '-',
+            ChangeKind::Skipped => '!',
+            ChangeKind::Conflict => '?',
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FileChange {
+    /// Path relative to the output directory.
+    pub path: PathBuf,
+    pub kind: ChangeKind,
+}
+
+/// Every file a construct or patch run would touch in the output directory.
+#[derive(Debug, Default, Serialize)]
+pub struct ChangePlan {
+    pub changes: Vec<FileChange>,
+}
+
+impl ChangePlan {
+    /// Compares the files in `staged` with the ones in `dest`.
+    ///
+    /// With `include_deletions` files which only exist in `dest` are reported as deleted, this
+    /// only makes sense if `staged` started out as a copy of `dest` (like for patches). Such
+    /// copies leave out [`SKIPPED_DIRS`], so they are not compared either.
+    /// Without `overwrite` existing files which would change are reported as skipped.
+    pub fn compute(
+        staged: &Path,
+        dest: &Path,
+        include_deletions: bool,
+        overwrite: bool,
+    ) -> Result<Self> {
+        let list = if include_deletions {
+            list_project_files
+        } else {
+            list_files
+        };
+        let staged_files = list(staged)?;
+        let mut changes = Vec::new();
+
+        for path in &staged_files {
+            let staged_path = staged.join(path);
+            let dest_path = dest.join(path);
+            let kind = if fs::symlink_metadata(&dest_path).is_err() {
+                ChangeKind::Created
+            } else if files_equal(&staged_path, &dest_path)? {
+                continue;
+            } else if overwrite {
+                ChangeKind::Modified
+            } else {
+                ChangeKind::Skipped
+            };
+            changes.push(FileChange {
+                path: path.clone(),
+                kind,
+            });
+        }
+
+        if include_deletions && dest.exists() {
+            for path in list(dest)? {
+                if !staged_files.contains(&path) {
+                    changes.push(FileChange {
+                        path,
+                        kind: ChangeKind::Deleted,
+                    });
+                }
+            }
+        }
+
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(Self { changes })
+    }
+
+    /// Copies created and modified files from `staged` to `dest` and removes deleted ones, as
+    /// part of `transaction`. Either all changes are applied or, if one of them fails, none.
+    pub(crate) fn apply_in(
+        &self,
+        transaction: &mut Transaction,
+        staged: &Path,
+        dest: &Path,
+    ) -> Result<()> {
+        for change in &self.changes {
+            let dest_path = dest.join(&change.path);
+            match change.kind {
+                ChangeKind::Created | ChangeKind::Modified => {
+                    transaction.copy_into(&staged.join(&change.path), &dest_path)?
+                }
+                ChangeKind::Deleted => transaction.remove(&dest_path)?,
+                ChangeKind::Skipped | ChangeKind::Conflict => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn print(&self, writer: &mut dyn std::io::Write) -> std::io::Result<()> {
+        if self.is_empty() {
+            return writeln!(writer, "No changes.");
+        }
+        writeln!(writer, "Changes:")?;
+        for change in &self.changes {
+            writeln!(
+                writer,
+                "  {} {}",
+                change.kind.symbol(),
+                change.path.display()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Directories of version control systems and build tools, which can be huge and are never
+/// touched by scaffolds. Staging copies of a project leave them out at the project root.
+pub(crate) const SKIPPED_DIRS: &[&str] = &[".git", ".hg", ".jj", ".svn", "node_modules", "target"];
+
+/// Recursively lists all files (and symlinks) in `root`, relative to `root`.
+pub(crate) fn list_files(root: &Path) -> Result<BTreeSet<PathBuf>> {
+    let mut files = BTreeSet::new();
+    collect_files(root, Path::new(""), false, &mut files)?;
+    Ok(files)
+}
+
+/// Like [`list_files`], but leaves out [`SKIPPED_DIRS`] at the root.
+pub(crate) fn list_project_files(root: &Path) -> Result<BTreeSet<PathBuf>> {
+    let mut files = BTreeSet::new();
+    collect_files(root, Path::new(""), true, &mut files)?;
+    Ok(files)
+}
+
+fn collect_files(
+    root: &Path,
+    relative: &Path,
+    skip_dirs: bool,
+    files: &mut BTreeSet<PathBuf>,
+) -> Result<()> {
+    let dir = root.join(relative);
+    for entry in fs::read_dir(&dir).wrap_err(format!("failed to read directory {dir:?}"))? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            if skip_dirs && is_skipped_dir(&entry.file_name()) {
+                continue;
+            }
+            collect_files(root, &path, false, files)?;
+        } else {
+            files.insert(path);
+        }
+    }
+    Ok(())
+}
+
+/// Reads the file at `path`, `None` if it does not exist.
+pub(crate) fn read_optional(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).wrap_err(format!("failed to read {path:?}")),
+    }
+}
+
+pub(crate) fn files_equal(a: &Path, b: &Path) -> Result<bool> {
+    let (meta_a, meta_b) = (fs::symlink_metadata(a)?, fs::symlink_metadata(b)?);
+    if meta_a.is_symlink() || meta_b.is_symlink() {
+        return Ok(meta_a.is_symlink()
+            && meta_b.is_symlink()
+            && fs::read_link(a)? == fs::read_link(b)?);
+    }
+    if !meta_b.is_file() || meta_a.len() != meta_b.len() {
+        return Ok(false);
+    }
+    Ok(fs::read(a)? == fs::read(b)?)
+}
+
+fn is_skipped_dir(name: &std::ffi::OsStr) -> bool {
+    SKIPPED_DIRS.iter().any(|skipped| name == *skipped)
+}
+
+/// Warns about [`SKIPPED_DIRS`] a run created in `staged` (eg. `git init` or a build), changes
+/// inside of them are not part of the plan and never applied.
+pub(crate) fn warn_skipped_dirs(staged: &Path) {
+    for dir in SKIPPED_DIRS {
+        if staged.join(dir).is_dir() {
+            warn!(
+                dir,
+                "The scaffold wrote into a version control or build directory, these changes are \
+                 not applied"
+            );
+        }
+    }
+}
+
+/// Recursively copies `source` into `dest`, keeping symlinks as symlinks.
+pub(crate) fn copy_dir_all(source: &Path, dest: &Path) -> Result<()> {
+    copy_dir(source, dest, false)
+}
+
+/// Copies a project into a staging directory, leaving out [`SKIPPED_DIRS`] at the root.
+pub(crate) fn copy_project(source: &Path, dest: &Path) -> Result<()> {
+    copy_dir(source, dest, true)
+}
+
+fn copy_dir(source: &Path, dest: &Path, skip_dirs: bool) -> Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let source_path = entry.path();
+        let dest_path = dest.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if skip_dirs && is_skipped_dir(&entry.file_name()) {
+                continue;
+            }
+            copy_dir(&source_path, &dest_path, false)?;
+        } else if file_type.is_symlink() {
+            copy_symlink(&source_path, &dest_path)?;
+        } else {
+            fs::copy(&source_path, &dest_path)
+                .wrap_err(format!("failed to copy {source_path:?} to {dest_path:?}"))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn copy_symlink(source: &Path, dest: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(source)?, dest)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn copy_symlink(source: &Path, dest: &Path) -> Result<()> {
+    fs::copy(source, dest)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_created_and_skipped() -> Result<()> {
+        let staged = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+
+        fs::create_dir(staged.path().join("sub"))?;
+        fs::write(staged.path().join("sub/new.txt"), "new")?;
+        fs::write(staged.path().join("same.txt"), "same")?;
+        fs::write(staged.path().join("changed.txt"), "staged")?;
+        fs::write(dest.path().join("same.txt"), "same")?;
+        fs::write(dest.path().join("changed.txt"), "dest")?;
+        fs::write(dest.path().join("untouched.txt"), "untouched")?;
+
+        let plan = ChangePlan::compute(staged.path(), dest.path(), false, false)?;
+        assert_eq!(
+            plan.changes,
+            vec![
+                FileChange {
+                    path: "changed.txt".into(),
+                    kind: ChangeKind::Skipped,
+                },
+                FileChange {
+                    path: "sub/new.txt".into(),
+                    kind: ChangeKind::Created,
+                },
+            ]
+        );
+
+        let plan = ChangePlan::compute(staged.path(), dest.path(), false, true)?;
+        assert_eq!(plan.changes[0].kind, ChangeKind::Modified);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compute_deletions() -> Result<()> {
+        let staged = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+
+        fs::write(dest.path().join("removed.txt"), "bye")?;
+        fs::write(dest.path().join("kept.txt"), "kept")?;
+        fs::write(staged.path().join("kept.txt"), "kept")?;
+
+        let plan = ChangePlan::compute(staged.path(), dest.path(), false, true)?;
+        assert!(plan.is_empty());
+
+        let plan = ChangePlan::compute(staged.path(), dest.path(), true, true)?;
+        assert_eq!(
+            plan.changes,
+            vec![FileChange {
+                path: "removed.txt".into(),
+                kind: ChangeKind::Deleted,
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_dir_all() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+
+        fs::create_dir_all(source.path().join("a/b"))?;
+        fs::write(source.path().join("a/b/c.txt"), "c")?;
+        fs::write(source.path().join("d.txt"), "d")?;
+
+        copy_dir_all(source.path(), dest.path())?;
+        assert_eq!(fs::read_to_string(dest.path().join("a/b/c.txt"))?, "c");
+        assert_eq!(fs::read_to_string(dest.path().join("d.txt"))?, "d");
+        assert!(ChangePlan::compute(source.path(), dest.path(), true, true)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_project_skips_vcs_and_build_dirs() -> Result<()> {
+        let project = tempfile::tempdir()?;
+        let staged = tempfile::tempdir()?;
+
+        fs::create_dir_all(project.path().join(".git/objects"))?;
+        fs::write(project.path().join(".git/objects/a"), "a")?;
+        fs::create_dir_all(project.path().join("target/debug"))?;
+        fs::write(project.path().join("target/debug/app"), "app")?;
+        // only skipped at the root, nested ones may well be part of the project
+        fs::create_dir_all(project.path().join("src/target"))?;
+        fs::write(project.path().join("src/target/mod.rs"), "mod")?;
+        fs::write(project.path().join("src/lib.rs"), "lib")?;
+
+        copy_project(project.path(), staged.path())?;
+        assert_eq!(
+            list_files(staged.path())?,
+            BTreeSet::from([
+                PathBuf::from("src/lib.rs"),
+                PathBuf::from("src/target/mod.rs")
+            ])
+        );
+        // the skipped directories are not reported as deleted
+        assert!(ChangePlan::compute(staged.path(), project.path(), true, true)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_print() -> Result<()> {
+        let plan = ChangePlan {
+            changes: vec![
+                FileChange {
+                    path: "a.txt".into(),
+                    kind: ChangeKind::Created,
+                },
+                FileChange {
+                    path: "b.txt".into(),
+                    kind: ChangeKind::Deleted,
+                },
+            ],
+        };
+        let mut output = Vec::new();
+        plan.print(&mut output)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "Changes:\n  + a.txt\n  - b.txt\n"
+        );
+
+        let mut output = Vec::new();
+        ChangePlan::default().print(&mut output)?;
+        assert_eq!(String::from_utf8(output)?, "No changes.\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,444 @@
+use std::{collections::HashMap, sync::Arc};
+
+use eyre::{Result, eyre};
+use kenchiku_common::{
+    Context, IntoLuaErrDebug,
+    meta::ValueMeta,
+    values::{flatten_values, json_to_value_string},
+};
+use mlua::{Lua, LuaSerdeExt};
+
+use crate::{
+    Scaffold,
+    patch_set::{PatchSet, load_discovered},
+};
+
+/// The `kenchiku` Lua module, lets scaffolds build on other scaffolds and patches instead of
+/// copying their logic.
+pub(crate) struct LuaKenchiku;
+
+impl LuaKenchiku {
+    pub fn register(
+        lua: &Lua,
+        context: Context,
+        parent: Option<Arc<Scaffold>>,
+    ) -> mlua::Result<()> {
+        let kenchiku_table = lua.create_table()?;
+
+        let construct_context = context.clone();
+        kenchiku_table.set(
+            "construct",
+            lua.create_function(move |lua, (name, values): (String, Option<mlua::Value>)| {
+                construct(lua, &name, values, construct_context.clone()).into_lua_err_debug()
+            })?,
+        )?;
+
+        let patch_context = context.clone();
+        kenchiku_table.set(
+            "patch",
+            lua.create_function(move |lua, (id, values): (String, Option<mlua::Value>)| {
+                patch(lua, &id, values, patch_context.clone()).into_lua_err_debug()
+            })?,
+        )?;
+
+        kenchiku_table.set(
+            "construct_parent",
+            lua.create_function(move |_, ()| {
+                let parent = parent
+                    .as_ref()
+                    .ok_or(eyre!("the scaffold does not extend another one"))
+                    .into_lua_err_debug()?;
+                // the parent shares values and uses files of the extending scaffold first
+                parent
+                    .construct_for_child(context.clone())
+                    .into_lua_err_debug()
+            })?,
+        )?;
+
+        lua.globals().set("kenchiku", kenchiku_table)?;
+
+        Ok(())
+    }
+}
+
+/// Runs the construct function of scaffold `name` in the same working directory. The child only
+/// sees the values passed to it and asks for the rest itself.
+fn construct(lua: &Lua, name: &str, values: Option<mlua::Value>, context: Context) -> Result<()> {
+    let scaffold = load_discovered(name, &context)?;
+    let values = child_values(lua, values, &scaffold.meta.values)?;
+    scaffold.call_construct(Context {
+        scaffold_dir: scaffold.path.clone(),
+        values_meta: scaffold.meta.values.clone(),
+        values,
+        resolved_values: Default::default(),
+        parent_scaffold_dirs: vec![],
+        ..context
+    })
+}
+
+/// Runs patch `id` (`<scaffold>:<patch>`) and the patches it requires directly in the working
+/// directory, which already is a staging copy or the directory a construct runs in.
+fn patch(lua: &Lua, id: &str, values: Option<mlua::Value>, context: Context) -> Result<()> {
+    let patch_set = PatchSet::resolve(&[id.to_string()], |name| load_discovered(name, &context))?;
+    let values = child_values(lua, values, &patch_set.values_meta())?;
+    let dir = context.working_dir.clone();
+    let context = Context {
+        values,
+        resolved_values: Default::default(),
+        ..context
+    };
+    patch_set.run_in(&dir, &context)?;
+    Ok(())
+}
+
+/// Converts the values table passed from Lua like the values of the MCP server, so lists and
+/// objects can be passed as tables.
+fn child_values(
+    lua: &Lua,
+    values: Option<mlua::Value>,
+    values_meta: &HashMap<String, ValueMeta>,
+) -> Result<HashMap<String, String>> {
+    let Some(values) = values else {
+        return Ok(HashMap::new());
+    };
+    let values: HashMap<String, serde_json::Value> = lua.from_value(values)?;
+    Ok(flatten_values(values, values_meta)?
+        .iter()
+        .map(|(id, value)| (id.clone(), json_to_value_string(value)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kenchiku_common::Limits;
+    use std::{fs, path::Path, sync::Mutex, time::Duration};
+
+    fn write_scaffold(root: &Path, name: &str, lua: &str) -> Result<()> {
+        fs::create_dir_all(root.join(name))?;
+        fs::write(
+            root.join(name).join("scaffold.lua"),
+            lua.replace("ROOT", &root.display().to_string()),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_construct_builds_on_other_scaffolds() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write_scaffold(
+            root.path(),
+            "lib",
+            r#"
+                return {
+                    description = "lib",
+                    values = {
+                        name = { type = "string", description = "Name" },
+                        features = { type = "list", description = "Features" },
+                    },
+                    construct = function()
+                        fs.write("lib.txt", values.get("name") .. " " .. table.concat(values.get("features"), ","))
+                    end,
+                    patches = {
+                        ci = {
+                            description = "ci",
+                            eager = true,
+                            values = { runner = { type = "string", description = "Runner" } },
+                            run = function()
+                                fs.write("ci.txt", values.get("runner"))
+                            end,
+                        },
+                    },
+                }
+            "#,
+        )?;
+        write_scaffold(
+            root.path(),
+            "app",
+            r#"
+                return {
+                    description = "app",
+                    values = { name = { type = "string", description = "Name" } },
+                    construct = function()
+                        kenchiku.construct("ROOT/lib", { name = values.get("name") .. "-lib", features = { "a", "b" } })
+                        kenchiku.patch("ROOT/lib:ci")
+                        fs.write("app.txt", values.get("name"))
+                    end,
+                }
+            "#,
+        )?;
+        let scaffold = Scaffold::load(root.path().join("app"))?;
+        let working_dir = tempfile::tempdir()?;
+        let confirmations = Arc::new(Mutex::new(Vec::new()));
+        let messages = confirmations.clone();
+        scaffold.call_construct(Context {
+            working_dir: working_dir.path().to_path_buf(),
+            values_meta: scaffold.meta.values.clone(),
+            values: HashMap::from([("name".to_string(), "demo".to_string())]),
+            prompt_value: Arc::new(|id, _, _, _, _, _| Ok(format!("prompted {id}"))),
+            confirm_fn: Arc::new(move |message| {
+                messages
+                    .lock()
+                    .expect("lock to not be poisoned")
+                    .push(message);
+                Ok(true)
+            }),
+            ..Default::default()
+        })?;
+
+        let read = |file| fs::read_to_string(working_dir.path().join(file));
+        assert_eq!(read("lib.txt")?, "demo-lib a,b");
+        assert_eq!(read("ci.txt")?, "prompted runner");
+        assert_eq!(read("app.txt")?, "demo");
+        // the eager patch asked to confirm its values through the parent's confirm function
+        let confirmations = confirmations.lock().expect("lock to not be poisoned");
+        assert_eq!(confirmations.len(), 1);
+        assert!(confirmations[0].contains("runner: prompted runner"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_relative_names_are_relative_to_the_scaffold() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write_scaffold(
+            root.path(),
+            "lib",
+            r#"
+                return {
+                    description = "lib",
+                    construct = function() fs.write("lib.txt", "lib") end,
+                    patches = {
+                        ci = { description = "ci", run = function() fs.write("ci.txt", "ci") end },
+                    },
+                }
+            "#,
+        )?;
+        write_scaffold(
+            root.path(),
+            "app",
+            r#"
+                return {
+                    description = "app",
+                    construct = function()
+                        kenchiku.construct("../lib")
+                        kenchiku.patch("../lib:ci")
+                    end,
+                }
+            "#,
+        )?;
+        let scaffold = Scaffold::load(root.path().join("app"))?;
+        let working_dir = tempfile::tempdir()?;
+        scaffold.call_construct(Context {
+            working_dir: working_dir.path().to_path_buf(),
+            scaffold_dir: scaffold.path.clone(),
+            ..Default::default()
+        })?;
+        assert!(working_dir.path().join("lib.txt").exists());
+        assert!(working_dir.path().join("ci.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_invoked_scaffolds_share_the_limits() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write_scaffold(
+            root.path(),
+            "slow",
+            r#"
+                return {
+                    description = "slow",
+                    construct = function()
+                        local started = os.clock()
+                        while os.clock() - started < 0.3 do end
+                    end,
+                }
+            "#,
+        )?;
+        write_scaffold(
+            root.path(),
+            "heavy",
+            r#"
+                for i = 1, 1000 do end
+                return { description = "heavy", construct = function() end }
+            "#,
+        )?;
+        write_scaffold(
+            root.path(),
+            "busy",
+            r#"
+                return {
+                    description = "busy",
+                    construct = function()
+                        for i = 1, 600 do end
+                    end,
+                }
+            "#,
+        )?;
+        write_scaffold(
+            root.path(),
+            "app",
+            r#"
+                return {
+                    description = "app",
+                    values = { child = { type = "string", description = "Child" } },
+                    construct = function()
+                        local started = os.clock()
+                        while values.get("child") == "slow" and os.clock() - started < 0.3 do end
+                        if values.get("child") == "busy" then
+                            for i = 1, 600 do end
+                        end
+                        kenchiku.construct("ROOT/" .. values.get("child"))
+                    end,
+                }
+            "#,
+        )?;
+        let scaffold = Scaffold::load(root.path().join("app"))?;
+        let working_dir = tempfile::tempdir()?;
+        let run = |child: &str, limits| {
+            scaffold.call_construct(Context {
+                working_dir: working_dir.path().to_path_buf(),
+                values_meta: scaffold.meta.values.clone(),
+                values: HashMap::from([("child".to_string(), child.to_string())]),
+                limits,
+                ..Default::default()
+            })
+        };
+
+        // loading the invoked scaffold counts against the limits of the run
+        let limits = Limits {
+            instructions: Some(500),
+            ..Limits::RUN
+        };
+        let err = run("heavy", limits).unwrap_err();
+        assert!(
+            format!("{err:?}")
+                .contains("scaffold 'heavy' exceeded the instruction limit of 500 during load"),
+            "{err:?}"
+        );
+
+        // both run less than the instruction limit, but not together
+        let limits = Limits {
+            instructions: Some(1000),
+            ..Limits::RUN
+        };
+        let err = run("busy", limits).unwrap_err();
+        assert!(
+            format!("{err:?}").contains(
+                "scaffold 'busy' exceeded the instruction limit of 1000 during construct"
+            ),
+            "{err:?}"
+        );
+
+        // both take less than the timeout, but not together
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(500)),
+            ..Limits::RUN
+        };
+        let err = run("slow", limits).unwrap_err();
+        assert!(
+            format!("{err:?}")
+                .contains("scaffold 'slow' exceeded the time limit of 500ms during construct"),
+            "{err:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_invoked_scaffolds_are_restricted_by_the_caller() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write_scaffold(
+            root.path(),
+            "child",
+            r#"
+                return {
+                    description = "child",
+                    construct = function() exec.run("touch constructed") end,
+                    patches = {
+                        hook = { description = "hook", run = function() exec.run("touch patched") end },
+                    },
+                }
+            "#,
+        )?;
+        write_scaffold(
+            root.path(),
+            "app",
+            r#"
+                return {
+                    description = "app",
+                    capabilities = { exec = {} },
+                    values = { call = { type = "string", description = "Call" } },
+                    construct = function()
+                        if values.get("call") == "construct" then
+                            kenchiku.construct("ROOT/child")
+                        else
+                            kenchiku.patch("ROOT/child:hook")
+                        end
+                    end,
+                }
+            "#,
+        )?;
+        let scaffold = Scaffold::load(root.path().join("app"))?;
+        let working_dir = tempfile::tempdir()?;
+        for call in ["construct", "patch"] {
+            let err = scaffold
+                .call_construct(Context {
+                    working_dir: working_dir.path().to_path_buf(),
+                    confirm_all: 2,
+                    values_meta: scaffold.meta.values.clone(),
+                    values: HashMap::from([("call".to_string(), call.to_string())]),
+                    ..Default::default()
+                })
+                .unwrap_err();
+            assert!(
+                format!("{err:?}").contains("'touch' is not part of the scaffold's declared"),
+                "{err:?}"
+            );
+        }
+        assert!(fs::read_dir(working_dir.path())?.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scaffolds_invoking_each_other_fail() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write_scaffold(
+            root.path(),
+            "a",
+            r#"
+                return {
+                    description = "a",
+                    construct = function() kenchiku.patch("ROOT/b:loop") end,
+                }
+            "#,
+        )?;
+        write_scaffold(
+            root.path(),
+            "b",
+            r#"
+                return {
+                    description = "b",
+                    construct = function() end,
+                    patches = {
+                        loop = {
+                            description = "loop",
+                            run = function() kenchiku.construct("ROOT/a") end,
+                        },
+                    },
+                }
+            "#,
+        )?;
+        let scaffold = Scaffold::load(root.path().join("a"))?;
+        let working_dir = tempfile::tempdir()?;
+        let err = scaffold
+            .call_construct(Context {
+                working_dir: working_dir.path().to_path_buf(),
+                ..Default::default()
+            })
+            .unwrap_err();
+        assert!(
+            format!("{err:?}").contains("scaffolds invoke each other in a cycle: a -> b:loop -> a"),
+            "{err:?}"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+use std::{fs, path::Path};
+
+use eyre::Result;
+use kenchiku_common::{ConflictStrategy, Context};
+use similar::{DiffOp, TextDiff};
+use tracing::debug;
+
+use crate::{
+    changes::{ChangeKind, ChangePlan, FileChange},
+    review::unified_diff,
+    update::new_sibling,
+};
+
+/// How a single conflicting file gets resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Resolution {
+    Overwrite,
+    /// Keep the existing file, chosen by the user.
+    Keep,
+    /// Keep the existing file and leave the new one in the working directory, so the user can
+    /// copy it over manually. Used for the `keep` strategy, which doesn't ask.
+    Leave,
+    /// Write the new file as `<file>.kenchiku-new`.
+    WriteNew,
+    /// Replace the existing file with a merge of both, see [`merge_two_way`].
+    Merge,
+}
+
+/// Decides what happens with `source`, as `dest` already exists, based on the context's
+/// conflict strategy (asking the user if needed).
+pub(crate) fn resolve_conflict(
+    source: &Path,
+    dest: &Path,
+    context: &Context,
+) -> Result<Resolution> {
+    let mergeable = read_text(source)?.is_some() && read_text(dest)?.is_some();
+    let strategy = if context.allow_overwrite {
+        ConflictStrategy::Overwrite
+    } else {
+        context.on_conflict
+    };
+    Ok(match strategy {
+        ConflictStrategy::Overwrite => Resolution::Overwrite,
+        ConflictStrategy::Keep => Resolution::Leave,
+        ConflictStrategy::New => Resolution::WriteNew,
+        ConflictStrategy::Merge if mergeable => Resolution::Merge,
+        ConflictStrategy::Merge => {
+            debug!(?dest, "Cannot merge, writing new file next to it instead");
+            Resolution::WriteNew
+        }
+        ConflictStrategy::Ask => ask(source, dest, mergeable, context)?,
+    })
+}
+
+fn ask(source: &Path, dest: &Path, mergeable: bool, context: &Context) -> Result<Resolution> {
+    let path = dest.strip_prefix(&context.output).unwrap_or(dest);
+    let mut choices = vec!["keep", "overwrite", "new"];
+    if mergeable {
+        choices.extend(["diff", "merge"]);
+    }
+    let mut details = String::new();
+    loop {
+        let answer = (context.select_fn)(
+            format!(
+                "{details}'{}' already exists, what should happen? \
+                 (new writes it as '.kenchiku-new', merge adds conflict markers)",
+                path.display()
+            ),
+            choices.iter().map(|choice| choice.to_string()).collect(),
+        )?;
+        match answer.as_str() {
+            "overwrite" => return Ok(Resolution::Overwrite),
+            "new" => return Ok(Resolution::WriteNew),
+            "merge" => return Ok(Resolution::Merge),
+            "diff" => {
+                let (old, new) = (
+                    read_text(dest)?.unwrap_or_default(),
+                    read_text(source)?.unwrap_or_default(),
+                );
+                details = unified_diff(&TextDiff::from_lines(&old, &new), path);
+            }
+            _ => return Ok(Resolution::Keep),
+        }
+    }
+}
+
+/// Resolves the conflicts in `plan` (computed with overwriting, see [`ChangePlan::compute`])
+/// like moving the `staged` files into the output would, so dry runs report what a real run
+/// does. Conflicts a real run would ask about are reported as such instead of asking.
+pub(crate) fn resolve_planned_conflicts(
+    plan: ChangePlan,
+    staged: &Path,
+    context: &Context,
+) -> Result<ChangePlan> {
+    let ask = !context.allow_overwrite && context.on_conflict == ConflictStrategy::Ask;
+    let mut changes = Vec::new();
+    for change in plan.changes {
+        if change.kind != ChangeKind::Modified {
+            changes.push(change);
+            continue;
+        }
+        if ask {
+            changes.push(FileChange {
+                kind: ChangeKind::Conflict,
+                ..change
+            });
+            continue;
+        }
+        let dest = context.output.join(&change.path);
+        let change = match resolve_conflict(&staged.join(&change.path), &dest, context)? {
+            Resolution::Overwrite | Resolution::Merge => change,
+            Resolution::Keep | Resolution::Leave => FileChange {
+                kind: ChangeKind::Skipped,
+                ..change
+            },
+            Resolution::WriteNew => FileChange {
+                kind: if fs::symlink_metadata(new_sibling(&dest)).is_ok() {
+                    ChangeKind::Modified
+                } else {
+                    ChangeKind::Created
+                },
+                path: new_sibling(&change.path),
+            },
+        };
+        changes.push(change);
+    }
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(ChangePlan { changes })
+}
+
+/// Reads `path` if it is a text file.
+fn read_text(path: &Path) -> Result<Option<String>> {
+    if !fs::symlink_metadata(path)?.is_file() {
+        return Ok(None);
+    }
+    Ok(String::from_utf8(fs::read(path)?).ok())
+}
+
+/// Combines both versions, keeping common lines and wrapping every difference in conflict
+/// markers, `existing` first.
+pub(crate) fn merge_two_way(existing: &str, new: &str) -> String {
+    let diff = TextDiff::from_lines(existing, new);
+    let (old_lines, new_lines) = (diff.old_slices(), diff.new_slices());
+    let mut output = String::new();
+    for op in diff.ops() {
+        if let DiffOp::Equal { .. } = op {
+            output.extend(old_lines[op.old_range()].iter().copied());
+            continue;
+        }
+        output.push_str("<<<<<<< existing\n");
+        push_lines(&mut output, &old_lines[op.old_range()]);
+        output.push_str("=======\n");
+        push_lines(&mut output, &new_lines[op.new_range()]);
+        output.push_str(">>>>>>> scaffold\n");
+    }
+    output
+}
+
+fn push_lines(output: &mut String, lines: &[&str]) {
+    for line in lines {
+        output.push_str(line);
+        if !line.ends_with('\n') {
+            output.push('\n');
+        }
+    }
+}
+
+/// Merges `source` into `dest`, writing the result to `source` so it can be moved over.
+pub(crate) fn write_merged(source: &Path, dest: &Path) -> Result<()> {
+    let merged = merge_two_way(&fs::read_to_string(dest)?, &fs::read_to_string(source)?);
+    fs::write(source, merged)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_merge_two_way() {
+        assert_eq!(
+            merge_two_way("a\nb\nc\n", "a\nB\nc\nd"),
+            "a\n<<<<<<< existing\nb\n=======\nB\n>>>>>>> scaffold\nc\n\
+             <<<<<<< existing\n=======\nd\n>>>>>>> scaffold\n"
+        );
+    }
+
+    #[test]
+    fn test_strategies() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (source, dest) = (dir.path().join("source"), dir.path().join("dest"));
+        fs::write(&source, "new")?;
+        fs::write(&dest, [0, 159])?;
+
+        let resolve = |on_conflict, allow_overwrite| {
+            resolve_conflict(
+                &source,
+                &dest,
+                &Context {
+                    on_conflict,
+                    allow_overwrite,
+                    ..Default::default()
+                },
+            )
+        };
+        assert_eq!(resolve(ConflictStrategy::Keep, false)?, Resolution::Leave);
+        assert_eq!(
+            resolve(ConflictStrategy::Keep, true)?,
+            Resolution::Overwrite
+        );
+        assert_eq!(resolve(ConflictStrategy::New, false)?, Resolution::WriteNew);
+        // binary files can't be merged
+        assert_eq!(
+            resolve(ConflictStrategy::Merge, false)?,
+            Resolution::WriteNew
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ask_shows_diff() -> Result<()> {
+        let output = tempfile::tempdir()?;
+        let (source, dest) = (output.path().join("source"), output.path().join("a.txt"));
+        fs::write(&source, "new\n")?;
+        fs::write(&dest, "old\n")?;
+
+        let prompts = Arc::new(Mutex::new(Vec::new()));
+        let prompts_clone = prompts.clone();
+        let answers = Mutex::new(vec!["diff", "merge"].into_iter());
+        let context = Context {
+            output: output.path().to_path_buf(),
+            on_conflict: ConflictStrategy::Ask,
+            select_fn: Arc::new(move |message, choices| {
+                assert_eq!(choices.len(), 5);
+                prompts_clone.lock().unwrap().push(message);
+                Ok(answers.lock().unwrap().next().unwrap().to_string())
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            resolve_conflict(&source, &dest, &context)?,
+            Resolution::Merge
+        );
+        let prompts = prompts.lock().unwrap();
+        assert!(prompts[0].starts_with("'a.txt' already exists"));
+        assert!(prompts[1].contains("-old\n+new\n"));
+        Ok(())
+    }
+}
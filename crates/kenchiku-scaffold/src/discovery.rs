@@ -21,7 +21,7 @@ pub fn discover_scaffold(path_or_name: String) -> Option<PathBuf> {
             "More than one scaffold found with name"
         );
     }
-    if results.len() > 0 {
+    if !results.is_empty() {
         let path = results.first().unwrap().into();
         info!(scaffold = path_or_name, ?path, "Found scaffold");
         return Some(path);
@@ -57,13 +57,11 @@ fn find_scaffold_directories_in_path(path_env: &str) -> Vec<PathBuf> {
         if path.is_empty() {
             continue;
         }
-        for scaffold_path in read_dir(path).expect("to read directory") {
-            if let Ok(scaffold_path) = scaffold_path {
-                let full_path = scaffold_path.path().join("scaffold.lua");
-                if let Ok(metadata) = fs::metadata(&full_path) {
-                    if metadata.is_file() {
-                        found_directories.push(scaffold_path.path().to_path_buf());
-                    }
+        for scaffold_path in read_dir(path).expect("to read directory").flatten() {
+            let full_path = scaffold_path.path().join("scaffold.lua");
+            if let Ok(metadata) = fs::metadata(&full_path) {
+                if metadata.is_file() {
+                    found_directories.push(scaffold_path.path().to_path_buf());
                 }
             }
         }
@@ -1,7 +1,7 @@
 use eyre::{Context as _, Result, eyre};
 use kenchiku_common::{
-    Context,
-    meta::{ScaffoldMeta, ValueMeta},
+    Context, IntoLuaErrDebug, Limits,
+    meta::{Capabilities, ScaffoldMeta, ValueMeta, find_value_meta},
 };
 use kenchiku_lua::{
     exec::LuaExec, fs::LuaFS, json::LuaJson, log::LuaLog, re::LuaRe, tmpl::LuaTmpl,
@@ -9,15 +9,69 @@ use kenchiku_lua::{
 };
 use mlua::{FromLua, Lua};
 use serde::Serialize;
-use std::{fs::read_to_string, path::PathBuf};
+use std::{
+    collections::BTreeSet,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+    sync::{Arc, atomic::AtomicU64},
+    time::Instant,
+};
+use tempfile::TempDir;
 use tracing::{debug, info, warn};
 
-use crate::{requirer::SimpleRequirer, utils::move_files_to_destination};
+use crate::{
+    changes::{ChangePlan, copy_project, warn_skipped_dirs},
+    compose::LuaKenchiku,
+    conflict::{resolve_conflict, resolve_planned_conflicts},
+    discovery::discover_scaffold,
+    limits::{deadline, run_limited},
+    manifest::{
+        BASE_PATH, EntryKind, Manifest, ManifestEntry, remove_rejected, replace_base,
+        secret_values, snapshot_base, updated_base,
+    },
+    patch_set::{PatchSet, load_discovered},
+    requirer::SimpleRequirer,
+    review::review_changes,
+    transaction::Transaction,
+    update::{UpdatePlan, merge_generated, rejected_paths, retain_conflicts, staged_contents},
+    utils::move_files,
+};
 
+pub mod changes;
+mod compose;
+mod conflict;
 pub mod discovery;
+mod limits;
+pub mod manifest;
+pub mod patch_set;
+pub mod replay;
 mod requirer;
+mod review;
+mod transaction;
+pub mod update;
 mod utils;
 
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Result of a patch's `applies` and `applied` checks for a project.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchStatus {
+    Applicable,
+    NotApplicable,
+    AlreadyApplied,
+}
+
+impl std::fmt::Display for PatchStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatchStatus::Applicable => write!(f, "applicable"),
+            PatchStatus::NotApplicable => write!(f, "not applicable"),
+            PatchStatus::AlreadyApplied => write!(f, "already applied"),
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct Scaffold {
     #[allow(dead_code)]
@@ -26,10 +80,49 @@ pub struct Scaffold {
     pub name: String,
     pub path: PathBuf,
     pub meta: ScaffoldMeta,
+    /// The scaffold this one extends, its values and patches are merged into `meta`.
+    #[serde(skip)]
+    parent: Option<Arc<Scaffold>>,
+    /// Patches taken over from the parent, they run in the parent's Lua state.
+    #[serde(skip)]
+    inherited_patches: BTreeSet<String>,
 }
 
 impl Scaffold {
+    /// Loads the scaffold at `path` with the default [`Limits::LOAD`].
     pub fn load(path: PathBuf) -> Result<Self> {
+        Self::load_with_limits(path, Limits::LOAD)
+    }
+
+    /// Loads the scaffold at `path`, evaluating `scaffold.lua` within `limits`.
+    pub fn load_with_limits(path: PathBuf, limits: Limits) -> Result<Self> {
+        Self::load_extending(
+            path,
+            limits,
+            deadline(limits),
+            Default::default(),
+            &mut vec![],
+        )
+    }
+
+    /// Loads a scaffold invoked by a running one, within the limits and the remaining time and
+    /// instructions of the run.
+    pub(crate) fn load_invoked(path: PathBuf, context: &Context) -> Result<Self> {
+        let deadline = context.deadline.or_else(|| deadline(context.limits));
+        let instructions = context.instructions.clone().unwrap_or_default();
+        Self::load_extending(path, context.limits, deadline, instructions, &mut vec![])
+    }
+
+    /// Loads the scaffold at `path` and the scaffolds it extends, `chain` contains the
+    /// scaffolds extending it to detect cycles. All of them count against `deadline` and
+    /// `instructions` (see [`run_limited`]).
+    fn load_extending(
+        path: PathBuf,
+        limits: Limits,
+        deadline: Option<Instant>,
+        instructions: Arc<AtomicU64>,
+        chain: &mut Vec<String>,
+    ) -> Result<Self> {
         if !path.exists() {
             return Err(eyre!("Path does not exist"));
         }
@@ -43,55 +136,235 @@ impl Scaffold {
 
         debug!(?path, "loading scaffold...");
 
+        let name: String = path
+            .file_name()
+            .expect("to get filename of path")
+            .to_str()
+            .expect("to get filename of path")
+            .to_owned();
+
         let lua = Lua::new();
         lua.sandbox(true)?;
         let require_fn = lua.create_require_function(SimpleRequirer::new(path.clone()))?;
         lua.globals().set("require", require_fn)?;
 
         let file_content = read_to_string(&scaffold_lua_path)?;
-        let scaffold_content: mlua::Value = lua
-            .load(&file_content)
-            .set_name(scaffold_lua_path.display().to_string())
-            .eval()
-            .wrap_err("failed to load scaffold.lua")?;
+        let scaffold_content: mlua::Value = run_limited(
+            &lua,
+            limits,
+            deadline,
+            instructions.clone(),
+            &name,
+            "load",
+            || {
+                lua.load(&file_content)
+                    .set_name(scaffold_lua_path.display().to_string())
+                    .eval()
+            },
+        )
+        .wrap_err("failed to load scaffold.lua")?;
 
-        let meta = ScaffoldMeta::from_lua(scaffold_content, &lua)?;
-        let name: String = path
-            .file_name()
-            .expect("to get filename of path")
-            .to_str()
-            .expect("to get filename of path")
-            .to_owned();
+        let mut meta = ScaffoldMeta::from_lua(scaffold_content, &lua)?;
+
+        let parent = match &meta.extends {
+            Some(extends) => {
+                chain.push(name.clone());
+                let parent =
+                    Self::load_parent(&path, extends, limits, deadline, instructions, chain)
+                        .wrap_err(format!(
+                            "failed to load scaffold '{extends}' extended by '{name}'"
+                        ))?;
+                chain.pop();
+                Some(Arc::new(parent))
+            }
+            None => None,
+        };
+        let mut inherited_patches = BTreeSet::new();
+        if let Some(parent) = &parent {
+            inherited_patches = parent
+                .meta
+                .patches
+                .keys()
+                .filter(|patch| !meta.patches.contains_key(*patch))
+                .cloned()
+                .collect();
+            meta.inherit(&parent.meta);
+        }
 
         Ok(Self {
             lua,
             name,
             path,
             meta,
+            parent,
+            inherited_patches,
         })
     }
 
-    fn register_functions(&self, context: Context) -> Result<()> {
+    fn load_parent(
+        path: &Path,
+        extends: &str,
+        limits: Limits,
+        deadline: Option<Instant>,
+        instructions: Arc<AtomicU64>,
+        chain: &mut Vec<String>,
+    ) -> Result<Self> {
+        // relative paths are relative to the extending scaffold, not the current directory
+        let parent_path = if extends.starts_with('.') {
+            path.join(extends)
+        } else {
+            discover_scaffold(extends.to_string()).ok_or(eyre!("scaffold not found"))?
+        };
+        let parent_name = parent_path
+            .canonicalize()
+            .unwrap_or_else(|_| parent_path.clone())
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(extends)
+            .to_string();
+        if chain.contains(&parent_name) {
+            chain.push(parent_name);
+            return Err(eyre!(
+                "scaffolds extend each other in a cycle: {}",
+                chain.join(" -> ")
+            ));
+        }
+        Self::load_extending(parent_path, limits, deadline, instructions, chain)
+    }
+
+    /// The scaffold running patch `name` if it was inherited.
+    fn inherited_patch_owner(&self, name: &str) -> Option<&Scaffold> {
+        self.parent
+            .as_deref()
+            .filter(|_| self.inherited_patches.contains(name))
+    }
+
+    /// Capabilities to run with, restricted further by those of a scaffold invoking this one
+    /// (in `context`).
+    fn capabilities(&self, context: &Context) -> Option<Capabilities> {
+        Capabilities::restrict(
+            self.meta.capabilities.as_ref(),
+            context.capabilities.as_ref(),
+        )
+    }
+
+    /// Registers the Lua modules, restricted to `context.capabilities`.
+    fn register_functions(&self, mut context: Context) -> Result<()> {
+        let mut parent = self.parent.as_deref();
+        while let Some(scaffold) = parent {
+            if scaffold.path != context.scaffold_dir
+                && !context.parent_scaffold_dirs.contains(&scaffold.path)
+            {
+                context.parent_scaffold_dirs.push(scaffold.path.clone());
+            }
+            parent = scaffold.parent.as_deref();
+        }
         LuaLog::register(&self.lua, context.clone())?;
         LuaFS::register(&self.lua, context.clone())?;
         LuaExec::register(&self.lua, context.clone())?;
         LuaTmpl::register(&self.lua, context.clone())?;
         LuaJson::register(&self.lua, context.clone())?;
         LuaValues::register(&self.lua, context.clone())?;
-        LuaRe::register(&self.lua, context)?;
+        LuaRe::register(&self.lua, context.clone())?;
+        LuaKenchiku::register(&self.lua, context, self.parent.clone())?;
         Ok(())
     }
 
-    pub fn call_construct(self, context: Context) -> Result<()> {
-        self.register_functions(context)?;
-        self.meta
-            .construct
-            .call::<()>(())
-            .wrap_err("failed to call construct function")
+    /// Adds `id` to the call stack, refusing scaffolds and patches which (indirectly) invoke
+    /// themselves through `kenchiku.construct` or `kenchiku.patch`.
+    fn enter(&self, mut context: Context, id: String) -> Result<Context> {
+        if context.call_stack.contains(&id) {
+            context.call_stack.push(id);
+            return Err(eyre!(
+                "scaffolds invoke each other in a cycle: {}",
+                context.call_stack.join(" -> ")
+            ));
+        }
+        context.call_stack.push(id);
+        context.deadline = context.deadline.or_else(|| deadline(context.limits));
+        context.instructions.get_or_insert_default();
+        Ok(context)
+    }
+
+    /// Refuses scaffolds whose `requires_kenchiku` doesn't include this version.
+    fn check_kenchiku_version(&self) -> Result<()> {
+        let Some(requirement) = &self.meta.requires_kenchiku else {
+            return Ok(());
+        };
+        let version = semver::Version::parse(VERSION)?;
+        if !requirement.matches(&version) {
+            return Err(eyre!(
+                "scaffold '{}' requires Kenchiku {requirement}, but this is version {VERSION}",
+                self.name
+            ));
+        }
+        Ok(())
     }
 
-    pub fn call_patch(self, name: &str, context: Context) -> Result<()> {
-        self.register_functions(context)?;
+    pub fn call_construct(&self, context: Context) -> Result<()> {
+        self.check_kenchiku_version()?;
+        let context = Context {
+            capabilities: self.capabilities(&context),
+            ..context
+        };
+        let context = self.enter(context, self.name.clone())?;
+        let collect_values = context.eager || context.no_input || self.meta.eager;
+        self.run_construct(context.clone(), collect_values)?;
+        warn_unused_values(&context);
+        Ok(())
+    }
+
+    /// Runs the construct function on behalf of a scaffold extending this one (see
+    /// `kenchiku.construct_parent`). The extending scaffold already collected the values and
+    /// reports unused ones, its capabilities in `context` apply.
+    pub(crate) fn construct_for_child(&self, context: Context) -> Result<()> {
+        self.check_kenchiku_version()?;
+        let context = self.enter(context, self.name.clone())?;
+        self.run_construct(context, false)
+    }
+
+    fn run_construct(&self, context: Context, collect_values: bool) -> Result<()> {
+        let (limits, deadline) = (context.limits, context.deadline);
+        let instructions = context.instructions.clone().unwrap_or_default();
+        self.register_functions(context.clone())?;
+        run_limited(
+            &self.lua,
+            limits,
+            deadline,
+            instructions,
+            &self.name,
+            "construct",
+            || {
+                if collect_values {
+                    self.collect_values(&context, &self.meta.order)?;
+                }
+                self.meta.construct.call::<()>(())
+            },
+        )
+        .wrap_err("failed to call construct function")
+    }
+
+    pub fn call_patch(&self, name: &str, context: Context) -> Result<()> {
+        self.run_patch(
+            name,
+            Context {
+                capabilities: self.capabilities(&context),
+                ..context
+            },
+        )
+    }
+
+    /// Runs patch `name` restricted to `context.capabilities`, so inherited patches run with
+    /// the capabilities of the extending scaffold.
+    fn run_patch(&self, name: &str, context: Context) -> Result<()> {
+        self.check_kenchiku_version()?;
+        if let Some(parent) = self.inherited_patch_owner(name) {
+            return parent.run_patch(name, context);
+        }
+        let context = self.enter(context, format!("{}:{name}", self.name))?;
+        let (limits, deadline) = (context.limits, context.deadline);
+        let instructions = context.instructions.clone().unwrap_or_default();
+        self.register_functions(context.clone())?;
         let patch_meta = self
             .meta
             .patches
@@ -99,10 +372,127 @@ impl Scaffold {
             .find(|patch| patch.0 == name)
             .ok_or(eyre!("no patch with name '{}' found", name))?
             .1;
-        patch_meta
-            .run
-            .call::<()>(())
-            .wrap_err("failed to call patch function")
+        run_limited(
+            &self.lua,
+            limits,
+            deadline,
+            instructions,
+            &self.name,
+            &format!("patch '{name}'"),
+            || {
+                if context.eager || context.no_input || patch_meta.eager {
+                    self.collect_values(&context, &patch_meta.order)?;
+                }
+                patch_meta.run.call::<()>(())
+            },
+        )
+        .wrap_err("failed to call patch function")?;
+        warn_unused_values(&context);
+        Ok(())
+    }
+
+    /// Resolves all values before anything runs, so the user can't end up answering the last
+    /// prompt after side effects already happened. Shows a summary to confirm afterwards.
+    fn collect_values(&self, context: &Context, order: &[String]) -> mlua::Result<()> {
+        let summary = LuaValues::collect(&self.lua, context, order)?;
+        if context.confirm_all >= 1 || context.no_input || summary.is_empty() {
+            return Ok(());
+        }
+        let mut message = "Values:\n".to_string();
+        for (id, val) in summary {
+            message.push_str(&format!("  {id}: {val}\n"));
+        }
+        message.push_str("[values] Continue with these values?");
+        if !(context.confirm_fn)(message).into_lua_err_debug()? {
+            return Err(eyre!("values were rejected by the user")).into_lua_err_debug();
+        }
+        Ok(())
+    }
+
+    /// Runs the `applies` and `applied` checks of patch `name` against the project in
+    /// `context.output`. Checks may read the project but not modify it or run commands, missing
+    /// checks count as passing. They run with `context.limits`.
+    pub fn check_patch(&self, name: &str, context: Context) -> Result<PatchStatus> {
+        if let Some(parent) = self.inherited_patch_owner(name) {
+            return parent.check_patch(name, context);
+        }
+        let patch_meta = self
+            .meta
+            .patches
+            .get(name)
+            .ok_or(eyre!("no patch with name '{}' found", name))?;
+        if patch_meta.applies.is_none() && patch_meta.applied.is_none() {
+            return Ok(PatchStatus::Applicable);
+        }
+        let limits = context.limits;
+        let deadline = context.deadline.or_else(|| deadline(limits));
+        let instructions = context.instructions.clone().unwrap_or_default();
+        self.register_functions(Context {
+            working_dir: context.output.clone(),
+            capabilities: self.capabilities(&context),
+            no_input: true,
+            ..context
+        })?;
+        self.disable_side_effects()?;
+        let check = |function: &Option<mlua::Function>, what: &str| match function {
+            Some(function) => run_limited(
+                &self.lua,
+                limits,
+                deadline,
+                instructions.clone(),
+                &self.name,
+                &format!("{what} check of patch '{name}'"),
+                || function.call::<bool>(()),
+            )
+            .wrap_err(format!("failed to check whether patch '{name}' {what}")),
+            None => Ok(true),
+        };
+        if !check(&patch_meta.applies, "applies")? {
+            return Ok(PatchStatus::NotApplicable);
+        }
+        if patch_meta.applied.is_some() && check(&patch_meta.applied, "applied")? {
+            return Ok(PatchStatus::AlreadyApplied);
+        }
+        Ok(PatchStatus::Applicable)
+    }
+
+    /// Status of every patch for the project in `context.output`, sorted by name. A failing
+    /// check only affects the status of its own patch.
+    pub fn patch_statuses(&self, context: Context) -> Vec<(String, Result<PatchStatus>)> {
+        let mut names: Vec<_> = self.meta.patches.keys().collect();
+        names.sort();
+        names
+            .into_iter()
+            .map(|name| (name.clone(), self.check_patch(name, context.clone())))
+            .collect()
+    }
+
+    /// Replaces the functions which modify anything, so `applies` and `applied` checks stay
+    /// read-only. Registering the functions again restores them.
+    fn disable_side_effects(&self) -> Result<()> {
+        let globals = self.lua.globals();
+        for (module, function) in [
+            ("fs", "mkdir"),
+            ("fs", "write"),
+            ("fs", "copy"),
+            ("exec", "run"),
+            ("kenchiku", "construct"),
+            ("kenchiku", "patch"),
+            ("kenchiku", "construct_parent"),
+        ] {
+            let table: mlua::Table = globals.get(module)?;
+            table.set(
+                function,
+                self.lua
+                    .create_function(move |_, _: mlua::MultiValue| -> mlua::Result<()> {
+                        Err(mlua::Error::runtime(format!(
+                            "{module}.{function} is not allowed in applies and applied checks, \
+                             they need to be read-only"
+                        )))
+                    })?,
+            )?;
+        }
+        Ok(())
     }
 
     pub fn print(
@@ -111,13 +501,32 @@ impl Scaffold {
         with_details: bool,
     ) -> std::io::Result<()> {
         writeln!(writer, "Name: {}", self.name)?;
+        if let Some(version) = &self.meta.version {
+            writeln!(writer, "Version: {version}")?;
+        }
         writeln!(writer, "Description: {}", self.meta.description)?;
+        if let Some(extends) = &self.meta.extends {
+            writeln!(writer, "Extends: {extends}")?;
+        }
+        if !self.meta.tags.is_empty() {
+            writeln!(writer, "Tags: {}", self.meta.tags.join(", "))?;
+        }
 
         if with_details {
+            if !self.meta.authors.is_empty() {
+                writeln!(writer, "Authors: {}", self.meta.authors.join(", "))?;
+            }
+            if let Some(homepage) = &self.meta.homepage {
+                writeln!(writer, "Homepage: {homepage}")?;
+            }
+            if let Some(requirement) = &self.meta.requires_kenchiku {
+                writeln!(writer, "Requires Kenchiku: {requirement}")?;
+            }
             writeln!(writer, "Values:")?;
             for value in &self.meta.values {
                 print_value(writer, value, 2)?;
             }
+            print_capabilities(writer, self.meta.capabilities.as_ref())?;
         }
 
         writeln!(writer, "Patches:")?;
@@ -153,6 +562,15 @@ impl Scaffold {
         )?;
 
         if with_details {
+            if !patch.requires.is_empty() {
+                writeln!(
+                    writer,
+                    "{:indent$}Requires: {}",
+                    "",
+                    patch.requires.join(", "),
+                    indent = indent
+                )?;
+            }
             writeln!(writer, "{:indent$}Values:", "", indent = indent)?;
             let value_indent = if nested { 6 } else { 2 };
             for value in &patch.values {
@@ -162,17 +580,130 @@ impl Scaffold {
         Ok(())
     }
 
+    /// Runs the construct function in the working dir without touching the output and returns
+    /// the changes moving the results would cause.
+    pub fn plan_construct(self, context: Context) -> Result<ChangePlan> {
+        debug!(dir = ?context.working_dir, "Planning scaffold construction");
+        self.call_construct(Context {
+            staging: true,
+            ..context.clone()
+        })?;
+        let plan = ChangePlan::compute(&context.working_dir, &context.output, false, true)?;
+        resolve_planned_conflicts(plan, &context.working_dir, &context)
+    }
+
+    /// Runs the patch and the patches it requires against a copy of the output and returns the
+    /// changes it would cause.
+    pub fn plan_patch(self, name: &str, context: Context) -> Result<ChangePlan> {
+        PatchSet::for_scaffold(self, name, |name| load_discovered(name, &context))?.plan(context)
+    }
+
+    /// Runs the patch and the patches it requires against a copy of the output, lets the user
+    /// review every change and applies the accepted ones to the output. Returns the applied
+    /// changes.
+    ///
+    /// Applied changes are recorded in the project's manifest.
+    pub fn patch(self, name: &str, context: Context) -> Result<ChangePlan> {
+        PatchSet::for_scaffold(self, name, |name| load_discovered(name, &context))?.apply(context)
+    }
+
+    /// Runs the construct function again and returns the changes merging its results into the
+    /// output would cause.
+    pub fn plan_update(self, context: Context) -> Result<UpdatePlan> {
+        let (_staging_dir, update) = self.stage_update(context)?;
+        Ok(update)
+    }
+
+    /// Runs the construct function again (with the values recorded in the manifest) and merges
+    /// the results into the output, using the output of the previous run as common ancestor.
+    /// Conflicting changes are written with conflict markers and have to be resolved manually.
+    pub fn update(self, context: Context) -> Result<UpdatePlan> {
+        let project_dir = context.output.clone();
+        let (staging_dir, mut update) = self.stage_update(context.clone())?;
+        let proposed = staged_contents(&update.plan, staging_dir.path())?;
+        update.plan = review_changes(update.plan, staging_dir.path(), &project_dir, &context)?;
+        // rejected changes keep their previous base, so the next update proposes them again
+        let rejected = rejected_paths(
+            &proposed,
+            &update.plan,
+            staging_dir.path(),
+            &update.conflicts,
+        )?;
+        let base = updated_base(
+            &project_dir.join(BASE_PATH),
+            &context.working_dir,
+            &rejected,
+            &secret_values(&context),
+        )?;
+        retain_conflicts(&mut update.conflicts, &update.plan);
+        let mut entry =
+            ManifestEntry::generated(EntryKind::Update, &self, &context, &context.working_dir)?;
+        entry.retain_updated(
+            &update.plan,
+            &rejected,
+            &update.conflicts,
+            staging_dir.path(),
+        )?;
+        Transaction::run(|transaction| {
+            update
+                .plan
+                .apply_in(transaction, staging_dir.path(), &project_dir)?;
+            replace_base(transaction, &project_dir, base.path())?;
+            Manifest::append_in(transaction, &project_dir, vec![entry])
+        })?;
+        info!(to = ?project_dir, changes = update.plan.changes.len(), "Update applied");
+        Ok(update)
+    }
+
+    fn stage_update(&self, context: Context) -> Result<(TempDir, UpdatePlan)> {
+        debug!(dir = ?context.working_dir, "Constructing scaffold for update");
+        self.call_construct(Context {
+            staging: true,
+            ..context.clone()
+        })?;
+        warn_skipped_dirs(&context.working_dir);
+        let staging_dir = tempfile::tempdir()?;
+        debug!(project = ?context.output, staging = ?staging_dir.path(), "Staging update");
+        copy_project(&context.output, staging_dir.path())
+            .wrap_err("failed to copy project into staging directory")?;
+        let conflicts = merge_generated(
+            &context.output.join(BASE_PATH),
+            &context.working_dir,
+            staging_dir.path(),
+        )?;
+        let plan = ChangePlan::compute(staging_dir.path(), &context.output, true, true)?;
+        Ok((staging_dir, UpdatePlan { plan, conflicts }))
+    }
+
+    /// Runs the construct function and moves the results to the output, recording the
+    /// generated files in the output's manifest.
     pub fn construct(self, context: Context) -> Result<()> {
         debug!(dir = ?context.working_dir, "Constructing scaffold");
         self.call_construct(context.clone())?;
-        let remaining = move_files_to_destination(
-            &context.working_dir,
-            &context.output,
-            // TODO: make configurable?
-            true,
-            context.allow_overwrite,
-        )?;
-        if remaining.len() > 0 {
+        let mut entry =
+            ManifestEntry::generated(EntryKind::Construct, &self, &context, &context.working_dir)?;
+        let base = snapshot_base(&context.working_dir, &secret_values(&context))?;
+        let mut resolutions = Vec::new();
+        let remaining = Transaction::run(|transaction| {
+            let remaining = move_files(
+                transaction,
+                &context.working_dir,
+                &context.output,
+                // TODO: make configurable?
+                true,
+                &mut |source, dest| {
+                    let resolution = resolve_conflict(source, dest, &context)?;
+                    resolutions.push((source.to_path_buf(), resolution));
+                    Ok(resolution)
+                },
+            )?;
+            remove_rejected(base.path(), &context.working_dir, &resolutions)?;
+            replace_base(transaction, &context.output, base.path())?;
+            entry.retain_moved(&context.working_dir, &resolutions);
+            Manifest::append_in(transaction, &context.output, vec![entry])?;
+            Ok(remaining)
+        })?;
+        if !remaining.is_empty() {
             let paths_pretty = remaining
                 .iter()
                 .map(|path| format!("- {}", path.display()))
@@ -187,6 +718,59 @@ impl Scaffold {
     }
 }
 
+fn print_capabilities(
+    writer: &mut dyn std::io::Write,
+    capabilities: Option<&Capabilities>,
+) -> std::io::Result<()> {
+    let Some(capabilities) = capabilities else {
+        return writeln!(
+            writer,
+            "Capabilities: not declared (only restricted by confirmations)"
+        );
+    };
+    let list = |items: &[String]| {
+        if items.is_empty() {
+            "none".to_string()
+        } else {
+            items.join(", ")
+        }
+    };
+    writeln!(writer, "Capabilities:")?;
+    writeln!(writer, "  Exec: {}", list(&capabilities.exec))?;
+    writeln!(
+        writer,
+        "  Write outside output: {}",
+        list(&capabilities.fs_write)
+    )
+}
+
+/// Warns about provided values which were never used, eg. because their `when` condition was
+/// not met, instead of silently ignoring them. Undeclared values (like unrelated
+/// `KENCHIKU_VAL_*` env vars) are skipped.
+fn warn_unused_values(context: &Context) {
+    let resolved = context
+        .resolved_values
+        .lock()
+        .expect("lock to not be poisoned");
+    let mut unused: Vec<_> = context
+        .values
+        .keys()
+        .filter(|id| !resolved.contains_key(*id))
+        .filter_map(|id| find_value_meta(&context.values_meta, id).map(|meta| (id, meta)))
+        .collect();
+    unused.sort_by_key(|(id, _)| *id);
+    for (id, meta) in unused {
+        match &meta.when {
+            Some(condition) => warn!(
+                id,
+                %condition,
+                "Value was provided but is not relevant since its condition is not met, ignoring it"
+            ),
+            None => warn!(id, "Value was provided but never used, ignoring it"),
+        }
+    }
+}
+
 fn print_value(
     writer: &mut dyn std::io::Write,
     value: (&String, &ValueMeta),
@@ -196,26 +780,51 @@ fn print_value(
     writeln!(writer, "{indent_str}- Name: {}", value.0)?;
     writeln!(writer, "{indent_str}  Description: {}", value.1.description)?;
     writeln!(writer, "{indent_str}  Type: {}", value.1.r#type)?;
-    if value.1.default.is_some() {
+    if let Some(condition) = &value.1.when {
+        writeln!(writer, "{indent_str}  Only when: {condition}")?;
+    }
+    if value.1.is_secret() {
         writeln!(
             writer,
-            "{indent_str}  Default: {:?}",
-            value.1.default.clone().unwrap()
+            "{indent_str}  Secret: ask the user for it, never make one up"
         )?;
     }
-    if value.1.r#type == "enum" && value.1.choices.is_some() {
+    if let Some(default) = value.1.default_string() {
+        writeln!(writer, "{indent_str}  Default: {default}")?;
+    } else if value.1.has_computed_default() {
+        writeln!(writer, "{indent_str}  Default: computed from other values")?;
+    }
+    if matches!(value.1.r#type.as_str(), "enum" | "multiselect") && value.1.choices.is_some() {
         writeln!(writer, "{indent_str}  Choices:")?;
         for choice in value.1.choices.clone().unwrap() {
             writeln!(writer, "{indent_str}    - {}", choice)?;
         }
     }
+    for (name, limit) in [
+        ("Min", value.1.min),
+        ("Max", value.1.max),
+        ("Step", value.1.step),
+    ] {
+        if let Some(limit) = limit {
+            writeln!(writer, "{indent_str}  {name}: {limit}")?;
+        }
+    }
+    if let Some(fields) = &value.1.fields {
+        writeln!(writer, "{indent_str}  Fields:")?;
+        let mut fields: Vec<_> = fields.iter().collect();
+        fields.sort_by_key(|field| field.0);
+        for field in fields {
+            print_value(writer, field, indent + 4)?;
+        }
+    }
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::{fs, path::Path};
+    use crate::changes::{ChangeKind, FileChange};
+    use std::{collections::HashMap, fs, sync::Mutex};
 
     #[test]
     fn test_load_valid_path() {
@@ -254,4 +863,1208 @@ mod tests {
             .to_string()
         );
     }
+
+    #[test]
+    fn test_load_infinite_loop_is_limited() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let scaffold_dir = tmp.path().join("looping");
+        fs::create_dir(&scaffold_dir)?;
+        fs::write(scaffold_dir.join("scaffold.lua"), "while true do end")?;
+
+        let limits = Limits {
+            instructions: Some(100_000),
+            ..Limits::LOAD
+        };
+        let err = Scaffold::load_with_limits(scaffold_dir, limits).unwrap_err();
+        assert!(
+            format!("{err:?}").contains(
+                "scaffold 'looping' exceeded the instruction limit of 100000 during load"
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_declared_capabilities_are_enforced() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        fs::write(
+            scaffold_dir.path().join("scaffold.lua"),
+            r#"
+                return {
+                    description = "capabilities",
+                    capabilities = {
+                        exec = { "echo" },
+                        fs = { write = { "~/.config/example" } },
+                    },
+                    construct = function()
+                        exec.run("echo hello")
+                        exec.run("touch pwned")
+                    end,
+                }
+            "#,
+        )?;
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+
+        let mut details = Vec::new();
+        scaffold.print(&mut details, true)?;
+        let details = String::from_utf8(details)?;
+        assert!(
+            details.contains(
+                "Capabilities:\n  Exec: echo\n  Write outside output: ~/.config/example\n"
+            )
+        );
+
+        let working_dir = tempfile::tempdir()?;
+        let err = scaffold
+            .call_construct(Context {
+                working_dir: working_dir.path().to_path_buf(),
+                confirm_all: 2,
+                ..Default::default()
+            })
+            .unwrap_err();
+        assert!(format!("{err:?}").contains("'touch' is not part of the scaffold's declared"));
+        assert!(!working_dir.path().join("pwned").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_eager_values_are_collected_before_construct() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        fs::write(
+            scaffold_dir.path().join("scaffold.lua"),
+            r#"
+                return {
+                    description = "eager",
+                    eager = true,
+                    order = { "name", "database" },
+                    values = {
+                        database = { type = "string", description = "Database" },
+                        name = { type = "string", description = "Name" },
+                        password = {
+                            type = "secret",
+                            description = "Password",
+                            when = 'database ~= "none"',
+                        },
+                        port = { type = "integer", description = "Port", when = "false" },
+                    },
+                    construct = function()
+                        fs.write("constructed.txt", values.get("name"))
+                    end,
+                }
+            "#,
+        )?;
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+
+        let prompted = Arc::new(Mutex::new(Vec::new()));
+        let summaries = Arc::new(Mutex::new(Vec::new()));
+        let working_dir = tempfile::tempdir()?;
+        let context = |accept: bool| Context {
+            working_dir: working_dir.path().to_path_buf(),
+            values_meta: scaffold.meta.values.clone(),
+            prompt_value: Arc::new({
+                let prompted = prompted.clone();
+                move |id, _, _, _, _, _| {
+                    prompted.lock().unwrap().push(id.clone());
+                    Ok(if id == "database" {
+                        "postgres"
+                    } else {
+                        "hunter2"
+                    }
+                    .to_string())
+                }
+            }),
+            confirm_fn: Arc::new({
+                let summaries = summaries.clone();
+                move |message| {
+                    summaries.lock().unwrap().push(message);
+                    Ok(accept)
+                }
+            }),
+            ..Default::default()
+        };
+
+        let err = scaffold.call_construct(context(false)).unwrap_err();
+        assert!(format!("{err:?}").contains("values were rejected by the user"));
+        assert!(!working_dir.path().join("constructed.txt").exists());
+        assert_eq!(*prompted.lock().unwrap(), ["name", "database", "password"]);
+        assert_eq!(
+            summaries.lock().unwrap()[0],
+            "Values:\n  name: hunter2\n  database: postgres\n  password: <secret>\n\
+             [values] Continue with these values?"
+        );
+
+        scaffold.call_construct(context(true))?;
+        assert!(working_dir.path().join("constructed.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scaffold_extends_base() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        let write = |path: &str, content: &str| -> Result<()> {
+            let path = root.path().join(path);
+            fs::create_dir_all(path.parent().expect("path to have a parent"))?;
+            Ok(fs::write(path, content)?)
+        };
+        write(
+            "base/scaffold.lua",
+            r#"
+                return {
+                    description = "base service",
+                    values = {
+                        name = { type = "string", description = "Name" },
+                        port = { type = "integer", description = "Port", default = 80 },
+                    },
+                    construct = function()
+                        fs.write("README.md", tmpl.template_file("README.md.j2", { name = values.get("name") }))
+                        fs.copy("main.txt", "main.txt")
+                    end,
+                    patches = {
+                        docker = {
+                            description = "docker",
+                            values = { port = { type = "integer", description = "Port", default = 80 } },
+                            run = function()
+                                fs.write("Dockerfile", "EXPOSE " .. values.get("port"))
+                            end,
+                        },
+                        ci = { description = "base ci", run = function() fs.write("ci.txt", "base") end },
+                    },
+                }
+            "#,
+        )?;
+        write("base/README.md.j2", "base {{ name }}")?;
+        write("base/main.txt", "main")?;
+        write(
+            "rust/scaffold.lua",
+            r#"
+                return {
+                    description = "rust service",
+                    extends = "../base",
+                    values = {
+                        port = { type = "integer", description = "Port", default = 8080 },
+                        edition = { type = "string", description = "Edition", default = "2021" },
+                    },
+                    construct = function()
+                        kenchiku.construct_parent()
+                        fs.write("Cargo.toml", "edition = " .. values.get("edition") .. ", port = " .. values.get("port"))
+                    end,
+                    patches = {
+                        ci = { description = "rust ci", run = function() fs.write("ci.txt", "rust") end },
+                    },
+                }
+            "#,
+        )?;
+        write("rust/README.md.j2", "rust {{ name }}")?;
+        write(
+            "plain/scaffold.lua",
+            r#"return { description = "plain", extends = "../base" }"#,
+        )?;
+
+        let scaffold = Scaffold::load(root.path().join("rust"))?;
+        assert_eq!(scaffold.meta.values.len(), 3);
+        assert_eq!(
+            scaffold.meta.values["port"].default_string().as_deref(),
+            Some("8080")
+        );
+        assert_eq!(scaffold.meta.patches["ci"].description, "rust ci");
+        assert_eq!(scaffold.meta.patches["docker"].description, "docker");
+
+        let working_dir = tempfile::tempdir()?;
+        let context = Context {
+            working_dir: working_dir.path().to_path_buf(),
+            scaffold_dir: scaffold.path.clone(),
+            values_meta: scaffold.meta.values.clone(),
+            values: HashMap::from([("name".to_string(), "demo".to_string())]),
+            no_input: true,
+            ..Default::default()
+        };
+        scaffold.call_construct(context.clone())?;
+        scaffold.call_patch(
+            "docker",
+            Context {
+                values_meta: scaffold.meta.patches["docker"].values.clone(),
+                resolved_values: Default::default(),
+                ..context.clone()
+            },
+        )?;
+        scaffold.call_patch("ci", context.clone())?;
+        let read = |file| fs::read_to_string(working_dir.path().join(file));
+        assert_eq!(read("README.md")?, "rust demo");
+        assert_eq!(read("main.txt")?, "main");
+        assert_eq!(read("Cargo.toml")?, "edition = 2021, port = 8080");
+        assert_eq!(read("Dockerfile")?, "EXPOSE 80");
+        assert_eq!(read("ci.txt")?, "rust");
+
+        let scaffold = Scaffold::load(root.path().join("plain"))?;
+        let working_dir = tempfile::tempdir()?;
+        scaffold.call_construct(Context {
+            working_dir: working_dir.path().to_path_buf(),
+            scaffold_dir: scaffold.path.clone(),
+            values_meta: scaffold.meta.values.clone(),
+            values: HashMap::from([("name".to_string(), "demo".to_string())]),
+            no_input: true,
+            ..Default::default()
+        })?;
+        assert_eq!(
+            fs::read_to_string(working_dir.path().join("README.md"))?,
+            "base demo"
+        );
+
+        write(
+            "a/scaffold.lua",
+            r#"return { description = "a", extends = "../b" }"#,
+        )?;
+        write(
+            "b/scaffold.lua",
+            r#"return { description = "b", extends = "../a" }"#,
+        )?;
+        let err = Scaffold::load(root.path().join("a")).unwrap_err();
+        assert!(
+            format!("{err:?}").contains("scaffolds extend each other in a cycle: a -> b -> a"),
+            "{err:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parent_code_runs_for_the_extending_scaffold() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        for (name, content) in [
+            (
+                "base",
+                r#"
+                    return {
+                        description = "base",
+                        eager = true,
+                        capabilities = { exec = { "echo", "touch" } },
+                        values = { name = { type = "string", description = "Name" } },
+                        construct = function()
+                            fs.write("name.txt", values.get("name"))
+                            exec.run("touch pwned")
+                        end,
+                        patches = {
+                            hook = { description = "hook", run = function() exec.run("touch hooked") end },
+                        },
+                    }
+                "#,
+            ),
+            (
+                "variant",
+                r#"
+                    return {
+                        description = "variant",
+                        extends = "../base",
+                        eager = true,
+                        capabilities = { exec = { "echo" } },
+                    }
+                "#,
+            ),
+        ] {
+            fs::create_dir_all(root.path().join(name))?;
+            fs::write(root.path().join(name).join("scaffold.lua"), content)?;
+        }
+        let scaffold = Scaffold::load(root.path().join("variant"))?;
+
+        let summaries = Arc::new(Mutex::new(Vec::new()));
+        let working_dir = tempfile::tempdir()?;
+        let context = Context {
+            working_dir: working_dir.path().to_path_buf(),
+            scaffold_dir: scaffold.path.clone(),
+            values_meta: scaffold.meta.values.clone(),
+            prompt_value: Arc::new(|_, _, _, _, _, _| Ok("demo".to_string())),
+            confirm_fn: Arc::new({
+                let summaries = summaries.clone();
+                move |message| {
+                    if message.starts_with("Values:") {
+                        summaries.lock().unwrap().push(message);
+                    }
+                    Ok(true)
+                }
+            }),
+            ..Default::default()
+        };
+
+        let err = scaffold.call_construct(context.clone()).unwrap_err();
+        assert!(
+            format!("{err:?}").contains("'touch' is not part of the scaffold's declared"),
+            "{err:?}"
+        );
+        // the values were only collected and confirmed by the extending scaffold
+        assert_eq!(summaries.lock().unwrap().len(), 1);
+        assert_eq!(
+            fs::read_to_string(working_dir.path().join("name.txt"))?,
+            "demo"
+        );
+
+        let err = scaffold.call_patch("hook", context).unwrap_err();
+        assert!(
+            format!("{err:?}").contains("'touch' is not part of the scaffold's declared"),
+            "{err:?}"
+        );
+        assert!(!working_dir.path().join("pwned").exists());
+        assert!(!working_dir.path().join("hooked").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_scaffold_metadata() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        let write_scaffold = |requires: &str| {
+            fs::write(
+                scaffold_dir.path().join("scaffold.lua"),
+                format!(
+                    r#"
+                        return {{
+                            description = "metadata",
+                            version = "1.2.0",
+                            authors = {{ "Jane Doe", "John Doe" }},
+                            tags = {{ "rust", "cli" }},
+                            homepage = "https://example.com",
+                            requires_kenchiku = "{requires}",
+                            construct = function() end,
+                        }}
+                    "#
+                ),
+            )
+        };
+
+        write_scaffold(">=0.1, <1000")?;
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        let mut details = Vec::new();
+        scaffold.print(&mut details, true)?;
+        let details = String::from_utf8(details)?;
+        assert!(details.contains(
+            "Version: 1.2.0\nDescription: metadata\nTags: rust, cli\nAuthors: Jane Doe, John Doe\n\
+             Homepage: https://example.com\nRequires Kenchiku: >=0.1, <1000\n"
+        ));
+        let json = serde_json::to_value(&scaffold)?;
+        assert_eq!(json["meta"]["version"], "1.2.0");
+        assert_eq!(json["meta"]["tags"][1], "cli");
+        assert_eq!(json["meta"]["requires_kenchiku"], ">=0.1, <1000");
+        scaffold.call_construct(Context::default())?;
+
+        write_scaffold(">=1000")?;
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        let err = scaffold.call_construct(Context::default()).unwrap_err();
+        assert!(err.to_string().contains(&format!(
+            "requires Kenchiku >=1000, but this is version {VERSION}"
+        )));
+
+        write_scaffold("not a version")?;
+        let err = Scaffold::load(scaffold_dir.path().to_path_buf()).unwrap_err();
+        assert!(format!("{err:?}").contains("'requires_kenchiku' is not a valid version range"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_show_numeric_constraints() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        fs::write(
+            scaffold_dir.path().join("scaffold.lua"),
+            r#"
+                return {
+                    description = "numbers",
+                    values = {
+                        timeout = {
+                            type = "float",
+                            description = "Timeout",
+                            min = 0.5,
+                            max = 10,
+                            step = 0.5,
+                        },
+                    },
+                    construct = function() end,
+                }
+            "#,
+        )?;
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+
+        let mut details = Vec::new();
+        scaffold.print(&mut details, true)?;
+        assert!(
+            String::from_utf8(details)?
+                .contains("    Type: float\n    Min: 0.5\n    Max: 10\n    Step: 0.5\n")
+        );
+        let json = serde_json::to_value(&scaffold.meta.values["timeout"])?;
+        assert_eq!(json["min"], 0.5);
+        assert_eq!(json["max"], 10.0);
+        assert_eq!(json["step"], 0.5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_show_value_conditions() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        fs::write(
+            scaffold_dir.path().join("scaffold.lua"),
+            r#"
+                return {
+                    description = "conditions",
+                    values = {
+                        db_password = {
+                            type = "secret",
+                            description = "Password",
+                            when = 'database ~= "none"',
+                        },
+                        db_port = {
+                            type = "integer",
+                            description = "Port",
+                            when = function(v) return v.database == "postgres" end,
+                            default = function(v) return v.database == "postgres" and 5432 end,
+                        },
+                    },
+                    construct = function() end,
+                }
+            "#,
+        )?;
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+
+        let mut details = Vec::new();
+        scaffold.print(&mut details, true)?;
+        let details = String::from_utf8(details)?;
+        assert!(details.contains("    Type: secret\n    Only when: database ~= \"none\"\n"));
+        assert!(details.contains("    Type: integer\n    Only when: <function>\n"));
+        let json = serde_json::to_value(&scaffold.meta.values)?;
+        assert_eq!(json["db_password"]["when"], r#"database ~= "none""#);
+        assert_eq!(json["db_port"]["when"], "<function>");
+        assert_eq!(json["db_port"]["default"], "<function>");
+        assert!(details.contains("    Default: computed from other values\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_construct_leaves_output_untouched() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        fs::write(
+            scaffold_dir.path().join("scaffold.lua"),
+            r#"
+                return {
+                    description = "dry run",
+                    construct = function()
+                        fs.write("new.txt", "new")
+                        fs.write("existing.txt", "changed")
+                    end,
+                }
+            "#,
+        )?;
+        let working_dir = tempfile::tempdir()?;
+        let output_dir = tempfile::tempdir()?;
+        fs::write(output_dir.path().join("existing.txt"), "original")?;
+
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        let plan = scaffold.plan_construct(Context {
+            working_dir: working_dir.path().to_path_buf(),
+            output: output_dir.path().to_path_buf(),
+            scaffold_dir: scaffold_dir.path().to_path_buf(),
+            ..Default::default()
+        })?;
+
+        let kinds: Vec<_> = plan
+            .changes
+            .iter()
+            .map(|change| (change.path.display().to_string(), change.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("existing.txt".to_string(), ChangeKind::Skipped),
+                ("new.txt".to_string(), ChangeKind::Created),
+            ]
+        );
+        assert!(!output_dir.path().join("new.txt").exists());
+        assert_eq!(
+            fs::read_to_string(output_dir.path().join("existing.txt"))?,
+            "original"
+        );
+
+        // conflicts are reported like a real run would resolve them
+        let working_dir = tempfile::tempdir()?;
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        let plan = scaffold.plan_construct(Context {
+            working_dir: working_dir.path().to_path_buf(),
+            output: output_dir.path().to_path_buf(),
+            scaffold_dir: scaffold_dir.path().to_path_buf(),
+            on_conflict: kenchiku_common::ConflictStrategy::New,
+            ..Default::default()
+        })?;
+        assert_eq!(
+            plan.changes[0],
+            FileChange {
+                path: "existing.txt.kenchiku-new".into(),
+                kind: ChangeKind::Created,
+            }
+        );
+
+        // conflicts a real run would ask about are reported, not asked about
+        let working_dir = tempfile::tempdir()?;
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        let plan = scaffold.plan_construct(Context {
+            working_dir: working_dir.path().to_path_buf(),
+            output: output_dir.path().to_path_buf(),
+            scaffold_dir: scaffold_dir.path().to_path_buf(),
+            on_conflict: kenchiku_common::ConflictStrategy::Ask,
+            select_fn: Arc::new(|message, _| Err(eyre!("asked: {message}"))),
+            ..Default::default()
+        })?;
+        assert_eq!(plan.changes[0].kind, ChangeKind::Conflict);
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_patch_leaves_project_untouched() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        fs::write(
+            scaffold_dir.path().join("scaffold.lua"),
+            r#"
+                return {
+                    description = "dry run",
+                    construct = function() end,
+                    patches = {
+                        edit = {
+                            description = "edits files",
+                            run = function()
+                                local content = fs.read("main.txt", { source = "workdir" })
+                                fs.write("main.txt", content .. "patched")
+                                fs.write("added.txt", "added")
+                            end,
+                        },
+                    },
+                }
+            "#,
+        )?;
+        let project_dir = tempfile::tempdir()?;
+        fs::write(project_dir.path().join("main.txt"), "original")?;
+
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        let plan = scaffold.plan_patch(
+            "edit",
+            Context {
+                working_dir: project_dir.path().to_path_buf(),
+                output: project_dir.path().to_path_buf(),
+                scaffold_dir: scaffold_dir.path().to_path_buf(),
+                ..Default::default()
+            },
+        )?;
+
+        let kinds: Vec<_> = plan
+            .changes
+            .iter()
+            .map(|change| (change.path.display().to_string(), change.kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("added.txt".to_string(), ChangeKind::Created),
+                ("main.txt".to_string(), ChangeKind::Modified),
+            ]
+        );
+        assert!(!project_dir.path().join("added.txt").exists());
+        assert_eq!(
+            fs::read_to_string(project_dir.path().join("main.txt"))?,
+            "original"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_patch_applicability_checks() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        fs::write(
+            scaffold_dir.path().join("scaffold.lua"),
+            r#"
+                return {
+                    description = "checks",
+                    construct = function() end,
+                    patches = {
+                        logging = {
+                            description = "adds logging",
+                            applies = function() return fs.exists("Cargo.toml") end,
+                            applied = function()
+                                return fs.read("Cargo.toml", { source = "workdir" }):find("tracing") ~= nil
+                            end,
+                            run = function()
+                                local content = fs.read("Cargo.toml", { source = "workdir" })
+                                fs.write("Cargo.toml", content .. "tracing = \"0.1\"\n")
+                            end,
+                        },
+                        sneaky = {
+                            description = "writes while checking",
+                            applies = function() fs.write("pwned", "") return true end,
+                            run = function() end,
+                        },
+                        unchecked = { description = "no checks", run = function() end },
+                    },
+                }
+            "#,
+        )?;
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        let project_dir = tempfile::tempdir()?;
+        let context = Context {
+            output: project_dir.path().to_path_buf(),
+            scaffold_dir: scaffold_dir.path().to_path_buf(),
+            confirm_all: 1,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            scaffold.check_patch("logging", context.clone())?,
+            PatchStatus::NotApplicable
+        );
+        assert_eq!(
+            scaffold.check_patch("unchecked", context.clone())?,
+            PatchStatus::Applicable
+        );
+        let err = scaffold.check_patch("sneaky", context.clone()).unwrap_err();
+        assert!(format!("{err:?}").contains("fs.write is not allowed in applies and applied"));
+        assert!(!project_dir.path().join("pwned").exists());
+        // a failing check doesn't hide the status of the other patches
+        let statuses: Vec<_> = scaffold
+            .patch_statuses(context.clone())
+            .into_iter()
+            .map(|(name, status)| (name, status.ok()))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("logging".to_string(), Some(PatchStatus::NotApplicable)),
+                ("sneaky".to_string(), None),
+                ("unchecked".to_string(), Some(PatchStatus::Applicable)),
+            ]
+        );
+        // checks run with the limits of the context
+        let limits = Limits {
+            instructions: Some(1),
+            ..Limits::LOAD
+        };
+        let err = scaffold
+            .check_patch(
+                "logging",
+                Context {
+                    limits,
+                    ..context.clone()
+                },
+            )
+            .unwrap_err();
+        assert!(format!("{err:?}").contains("exceeded the instruction limit of 1"));
+
+        let err = scaffold.plan_patch("logging", context.clone()).unwrap_err();
+        assert!(err.to_string().contains(":logging' does not apply to"));
+
+        fs::write(project_dir.path().join("Cargo.toml"), "[dependencies]\n")?;
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        assert_eq!(
+            scaffold.check_patch("logging", context.clone())?,
+            PatchStatus::Applicable
+        );
+        let plan = scaffold.patch("logging", context.clone())?;
+        assert_eq!(plan.changes.len(), 1);
+
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        assert_eq!(
+            scaffold.check_patch("logging", context.clone())?,
+            PatchStatus::AlreadyApplied
+        );
+        let plan = scaffold.patch("logging", context)?;
+        assert!(plan.is_empty());
+        assert_eq!(
+            fs::read_to_string(project_dir.path().join("Cargo.toml"))?,
+            "[dependencies]\ntracing = \"0.1\"\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_patch_applies_reviewed_changes() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        fs::write(
+            scaffold_dir.path().join("scaffold.lua"),
+            r#"
+                return {
+                    description = "patching",
+                    construct = function() end,
+                    patches = {
+                        edit = {
+                            description = "edits files",
+                            run = function()
+                                fs.write("main.txt", "patched")
+                                fs.write("added.txt", "added")
+                            end,
+                        },
+                    },
+                }
+            "#,
+        )?;
+        let project_dir = tempfile::tempdir()?;
+        fs::write(project_dir.path().join("main.txt"), "original")?;
+
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        let plan = scaffold.patch(
+            "edit",
+            Context {
+                output: project_dir.path().to_path_buf(),
+                scaffold_dir: scaffold_dir.path().to_path_buf(),
+                confirm_fn: std::sync::Arc::new(|message| Ok(message.contains("added.txt"))),
+                ..Default::default()
+            },
+        )?;
+
+        assert_eq!(plan.changes.len(), 1);
+        assert_eq!(
+            fs::read_to_string(project_dir.path().join("added.txt"))?,
+            "added"
+        );
+        assert_eq!(
+            fs::read_to_string(project_dir.path().join("main.txt"))?,
+            "original"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_patch_error_leaves_project_untouched() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        fs::write(
+            scaffold_dir.path().join("scaffold.lua"),
+            r#"
+                return {
+                    description = "patching",
+                    construct = function() end,
+                    patches = {
+                        broken = {
+                            description = "fails halfway",
+                            run = function()
+                                fs.write("main.txt", "half applied")
+                                fs.write("added.txt", "added")
+                                error("oops")
+                            end,
+                        },
+                    },
+                }
+            "#,
+        )?;
+        let project_dir = tempfile::tempdir()?;
+        fs::write(project_dir.path().join("main.txt"), "original")?;
+
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        let result = scaffold.patch(
+            "broken",
+            Context {
+                output: project_dir.path().to_path_buf(),
+                scaffold_dir: scaffold_dir.path().to_path_buf(),
+                confirm_all: 1,
+                ..Default::default()
+            },
+        );
+
+        assert!(result.is_err());
+        assert!(!project_dir.path().join("added.txt").exists());
+        assert_eq!(
+            fs::read_to_string(project_dir.path().join("main.txt"))?,
+            "original"
+        );
+        Ok(())
+    }
+    #[test]
+    fn test_construct_and_patch_record_manifest() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        fs::write(
+            scaffold_dir.path().join("scaffold.lua"),
+            r##"
+                return {
+                    description = "manifest",
+                    values = {
+                        name = { type = "string", description = "project name" },
+                        unused = { type = "string", description = "never requested" },
+                        token = { type = "secret", description = "api token" },
+                    },
+                    construct = function()
+                        fs.write("README.md", "# " .. values.get("name"))
+                        values.get("token")
+                    end,
+                    patches = {
+                        license = {
+                            description = "adds a license",
+                            run = function()
+                                fs.write("LICENSE", "MIT")
+                            end,
+                        },
+                    },
+                }
+            "##,
+        )?;
+        let working_dir = tempfile::tempdir()?;
+        let output_dir = tempfile::tempdir()?;
+
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        scaffold.construct(Context {
+            working_dir: working_dir.path().to_path_buf(),
+            output: output_dir.path().to_path_buf(),
+            scaffold_dir: scaffold_dir.path().to_path_buf(),
+            values_meta: Scaffold::load(scaffold_dir.path().to_path_buf())?
+                .meta
+                .values,
+            values: [
+                ("name".to_string(), "demo".to_string()),
+                ("token".to_string(), "hunter2".to_string()),
+            ]
+            .into(),
+            ..Default::default()
+        })?;
+
+        let manifest = Manifest::load(output_dir.path())?;
+        assert!(
+            !fs::read_to_string(output_dir.path().join(manifest::MANIFEST_PATH))?
+                .contains("hunter2")
+        );
+        assert_eq!(manifest.entries.len(), 1);
+        let entry = &manifest.entries[0];
+        assert_eq!(entry.kind, manifest::EntryKind::Construct);
+        assert_eq!(entry.values.len(), 1);
+        assert_eq!(entry.values["name"], "demo");
+        assert_eq!(
+            entry.files[Path::new("README.md")],
+            manifest::checksum_file(&output_dir.path().join("README.md"))?
+        );
+        assert_eq!(
+            entry.scaffold_hash,
+            manifest::checksum_dir(scaffold_dir.path())?
+        );
+
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        scaffold.patch(
+            "license",
+            Context {
+                output: output_dir.path().to_path_buf(),
+                scaffold_dir: scaffold_dir.path().to_path_buf(),
+                confirm_all: 1,
+                ..Default::default()
+            },
+        )?;
+
+        let manifest = Manifest::load(output_dir.path())?;
+        assert_eq!(manifest.entries.len(), 2);
+        let entry = &manifest.entries[1];
+        assert_eq!(entry.kind, manifest::EntryKind::Patch);
+        assert_eq!(entry.patch.as_deref(), Some("license"));
+        assert_eq!(
+            entry.files.keys().collect::<Vec<_>>(),
+            vec![Path::new("LICENSE")]
+        );
+        Ok(())
+    }
+    #[test]
+    fn test_construct_manifest_records_moved_files() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        fs::write(
+            scaffold_dir.path().join("scaffold.lua"),
+            r#"
+                return {
+                    description = "conflicts",
+                    construct = function()
+                        fs.write("kept.txt", "generated")
+                        fs.write("new.txt", "generated")
+                    end,
+                }
+            "#,
+        )?;
+        let working_dir = tempfile::tempdir()?;
+        let output_dir = tempfile::tempdir()?;
+        fs::write(output_dir.path().join("kept.txt"), "mine")?;
+
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        scaffold.construct(Context {
+            working_dir: working_dir.path().to_path_buf(),
+            output: output_dir.path().to_path_buf(),
+            scaffold_dir: scaffold_dir.path().to_path_buf(),
+            on_conflict: kenchiku_common::ConflictStrategy::Keep,
+            ..Default::default()
+        })?;
+
+        // the kept file is the user's, not the generated one
+        let manifest = Manifest::load(output_dir.path())?;
+        assert_eq!(
+            manifest.entries[0].files.keys().collect::<Vec<_>>(),
+            vec![Path::new("new.txt")]
+        );
+        let base = output_dir.path().join(BASE_PATH);
+        assert!(!base.join("kept.txt").exists());
+        assert_eq!(fs::read_to_string(base.join("new.txt"))?, "generated");
+        Ok(())
+    }
+
+    #[test]
+    fn test_construct_leaves_secrets_out_of_the_base() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        fs::write(
+            scaffold_dir.path().join("scaffold.lua"),
+            r#"
+                return {
+                    description = "secrets",
+                    values = { token = { type = "secret", description = "Token" } },
+                    construct = function()
+                        fs.write("config.txt", "token = " .. values.get("token"))
+                        fs.write("README.md", "readme")
+                    end,
+                }
+            "#,
+        )?;
+        let working_dir = tempfile::tempdir()?;
+        let output_dir = tempfile::tempdir()?;
+
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        let values_meta = scaffold.meta.values.clone();
+        scaffold.construct(Context {
+            working_dir: working_dir.path().to_path_buf(),
+            output: output_dir.path().to_path_buf(),
+            values_meta,
+            values: HashMap::from([("token".to_string(), "hunter2".to_string())]),
+            ..Default::default()
+        })?;
+
+        assert_eq!(
+            fs::read_to_string(output_dir.path().join("config.txt"))?,
+            "token = hunter2"
+        );
+        let base = output_dir.path().join(BASE_PATH);
+        assert!(!base.join("config.txt").exists());
+        assert_eq!(fs::read_to_string(base.join("README.md"))?, "readme");
+        Ok(())
+    }
+
+    #[test]
+    fn test_construct_cleans_up_after_keeping_existing_files() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        fs::write(
+            scaffold_dir.path().join("scaffold.lua"),
+            r#"
+                return {
+                    description = "keep",
+                    construct = function() fs.write("kept.txt", "generated") end,
+                }
+            "#,
+        )?;
+        let working_dir = tempfile::tempdir()?.keep();
+        let output_dir = tempfile::tempdir()?;
+        fs::write(output_dir.path().join("kept.txt"), "mine")?;
+
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        scaffold.construct(Context {
+            working_dir: working_dir.clone(),
+            output: output_dir.path().to_path_buf(),
+            on_conflict: kenchiku_common::ConflictStrategy::Ask,
+            select_fn: Arc::new(|_, _| Ok("keep".to_string())),
+            ..Default::default()
+        })?;
+        assert_eq!(
+            fs::read_to_string(output_dir.path().join("kept.txt"))?,
+            "mine"
+        );
+        // nothing is left to copy over manually
+        assert!(!working_dir.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_construct_is_rolled_back_if_the_manifest_fails() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        fs::write(
+            scaffold_dir.path().join("scaffold.lua"),
+            r#"
+                return {
+                    description = "manifest",
+                    construct = function() fs.write("new.txt", "generated") end,
+                }
+            "#,
+        )?;
+        let working_dir = tempfile::tempdir()?;
+        let output_dir = tempfile::tempdir()?;
+        // a directory can't be read as manifest
+        fs::create_dir_all(output_dir.path().join(manifest::MANIFEST_PATH))?;
+
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        assert!(
+            scaffold
+                .construct(Context {
+                    working_dir: working_dir.path().to_path_buf(),
+                    output: output_dir.path().to_path_buf(),
+                    ..Default::default()
+                })
+                .is_err()
+        );
+        assert!(!output_dir.path().join("new.txt").exists());
+        assert!(!output_dir.path().join(BASE_PATH).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_merges_new_scaffold_version() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        let write_scaffold = |readme: &str| {
+            fs::write(
+                scaffold_dir.path().join("scaffold.lua"),
+                format!(
+                    r#"
+                        return {{
+                            description = "update",
+                            values = {{
+                                name = {{ type = "string", description = "project name" }},
+                            }},
+                            construct = function()
+                                fs.write("README.md", "{readme}" .. values.get("name") .. "\n")
+                                fs.write("config.txt", "generated\n")
+                            end,
+                        }}
+                    "#
+                ),
+            )
+        };
+        write_scaffold("title\\nintro\\n\\n")?;
+        let output_dir = tempfile::tempdir()?;
+        let construct_context = |working_dir: &Path| -> Result<Context> {
+            Ok(Context {
+                working_dir: working_dir.to_path_buf(),
+                output: output_dir.path().to_path_buf(),
+                scaffold_dir: scaffold_dir.path().to_path_buf(),
+                values_meta: Scaffold::load(scaffold_dir.path().to_path_buf())?
+                    .meta
+                    .values,
+                values: [("name".to_string(), "demo".to_string())].into(),
+                confirm_all: 1,
+                ..Default::default()
+            })
+        };
+
+        let working_dir = tempfile::tempdir()?;
+        Scaffold::load(scaffold_dir.path().to_path_buf())?
+            .construct(construct_context(working_dir.path())?)?;
+        // user edits the last line, the new scaffold version the first line
+        fs::write(
+            output_dir.path().join("README.md"),
+            "title\nintro\n\nmy demo\n",
+        )?;
+        fs::write(output_dir.path().join("config.txt"), "customized\n")?;
+        write_scaffold("new title\\nintro\\n\\n")?;
+
+        let working_dir = tempfile::tempdir()?;
+        let update = Scaffold::load(scaffold_dir.path().to_path_buf())?
+            .update(construct_context(working_dir.path())?)?;
+
+        assert!(update.conflicts.is_empty());
+        assert_eq!(
+            fs::read_to_string(output_dir.path().join("README.md"))?,
+            "new title\nintro\n\nmy demo\n"
+        );
+        assert_eq!(
+            fs::read_to_string(output_dir.path().join("config.txt"))?,
+            "customized\n"
+        );
+        assert_eq!(
+            fs::read_to_string(output_dir.path().join(BASE_PATH).join("README.md"))?,
+            "new title\nintro\n\ndemo\n"
+        );
+        let manifest = Manifest::load(output_dir.path())?;
+        assert_eq!(manifest.entries.len(), 2);
+        assert_eq!(manifest.entries[1].kind, EntryKind::Update);
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_keeps_base_of_rejected_changes() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        let write_scaffold = |version: &str| {
+            fs::write(
+                scaffold_dir.path().join("scaffold.lua"),
+                format!(
+                    r#"
+                        return {{
+                            description = "update",
+                            construct = function()
+                                fs.write("README.md", "readme {version}\n")
+                                fs.write("config.txt", "config {version}\n")
+                            end,
+                        }}
+                    "#
+                ),
+            )
+        };
+        write_scaffold("v1")?;
+        let output_dir = tempfile::tempdir()?;
+        let context = |working_dir: &Path| Context {
+            working_dir: working_dir.to_path_buf(),
+            output: output_dir.path().to_path_buf(),
+            scaffold_dir: scaffold_dir.path().to_path_buf(),
+            // only accept the changes to the README
+            confirm_fn: Arc::new(|message| Ok(message.contains("'README.md'"))),
+            ..Default::default()
+        };
+
+        let working_dir = tempfile::tempdir()?;
+        Scaffold::load(scaffold_dir.path().to_path_buf())?
+            .construct(context(working_dir.path()))?;
+        write_scaffold("v2")?;
+        let working_dir = tempfile::tempdir()?;
+        Scaffold::load(scaffold_dir.path().to_path_buf())?.update(context(working_dir.path()))?;
+
+        let read = |path: &str| fs::read_to_string(output_dir.path().join(path));
+        assert_eq!(read("README.md")?, "readme v2\n");
+        assert_eq!(read("config.txt")?, "config v1\n");
+        // the rejected change is proposed again by the next update
+        assert_eq!(read(".kenchiku/base/README.md")?, "readme v2\n");
+        assert_eq!(read(".kenchiku/base/config.txt")?, "config v1\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_manifest_leaves_out_rejected_changes() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        let write_scaffold = |version: &str| {
+            fs::write(
+                scaffold_dir.path().join("scaffold.lua"),
+                format!(
+                    r#"
+                        return {{
+                            description = "update",
+                            construct = function()
+                                local lines = {{}}
+                                for i = 1, 12 do lines[i] = "line " .. i end
+                                lines[1] = "first {version}"
+                                lines[12] = "last {version}"
+                                fs.write("main.txt", table.concat(lines, "\n") .. "\n")
+                                fs.write("README.md", "readme {version}\n")
+                            end,
+                        }}
+                    "#
+                ),
+            )
+        };
+        write_scaffold("v1")?;
+        let output_dir = tempfile::tempdir()?;
+        let context = |working_dir: &Path| Context {
+            working_dir: working_dir.to_path_buf(),
+            output: output_dir.path().to_path_buf(),
+            scaffold_dir: scaffold_dir.path().to_path_buf(),
+            // accept the README, but only the first hunk of main.txt
+            confirm_fn: Arc::new(|message| {
+                Ok(message.contains("'README.md'") || message.contains("hunk 1/2"))
+            }),
+            ..Default::default()
+        };
+
+        let working_dir = tempfile::tempdir()?;
+        Scaffold::load(scaffold_dir.path().to_path_buf())?
+            .construct(context(working_dir.path()))?;
+        write_scaffold("v2")?;
+        let working_dir = tempfile::tempdir()?;
+        Scaffold::load(scaffold_dir.path().to_path_buf())?.update(context(working_dir.path()))?;
+
+        let main = fs::read_to_string(output_dir.path().join("main.txt"))?;
+        assert!(main.starts_with("first v2\n") && main.ends_with("last v1\n"));
+        let manifest = Manifest::load(output_dir.path())?;
+        assert_eq!(manifest.entries[1].kind, EntryKind::Update);
+        assert_eq!(
+            manifest.entries[1].files.keys().collect::<Vec<_>>(),
+            vec![Path::new("README.md")]
+        );
+        Ok(())
+    }
 }
@@ -0,0 +1,159 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+
+use eyre::{Result, eyre};
+use kenchiku_common::Limits;
+use mlua::{Lua, VmState};
+
+/// Only check the clock every so many interrupts, it's comparatively expensive.
+const TIMEOUT_CHECK_INTERVAL: u64 = 1024;
+
+/// When a run starting now exceeds `limits.timeout`, `None` if there is no timeout.
+pub(crate) fn deadline(limits: Limits) -> Option<Instant> {
+    limits
+        .timeout
+        .and_then(|timeout| Instant::now().checked_add(timeout))
+}
+
+/// Runs `f` with `limits` applied to `lua`, it has to finish before `deadline` (see
+/// [`deadline`]). Instructions are counted in `instructions`, which may already contain those of
+/// earlier calls in the same run. Errors caused by exceeding a limit name the scaffold and
+/// `phase` (eg. "load" or "construct").
+pub(crate) fn run_limited<T>(
+    lua: &Lua,
+    limits: Limits,
+    deadline: Option<Instant>,
+    instructions: Arc<AtomicU64>,
+    scaffold: &str,
+    phase: &str,
+    f: impl FnOnce() -> mlua::Result<T>,
+) -> Result<T> {
+    lua.set_memory_limit(limits.memory.unwrap_or(0))?;
+
+    let (scaffold_name, phase_name) = (scaffold.to_string(), phase.to_string());
+    lua.set_interrupt(move |_| {
+        let count = instructions.fetch_add(1, Ordering::Relaxed) + 1;
+        if limits.instructions.is_some_and(|max| count > max) {
+            return Err(mlua::Error::runtime(format!(
+                "scaffold '{scaffold_name}' exceeded the instruction limit of {} during \
+                 {phase_name}",
+                limits.instructions.unwrap_or_default()
+            )));
+        }
+        let timed_out = |deadline| Instant::now() > deadline;
+        if count.is_multiple_of(TIMEOUT_CHECK_INTERVAL) && deadline.is_some_and(timed_out) {
+            return Err(mlua::Error::runtime(format!(
+                "scaffold '{scaffold_name}' exceeded the time limit of {:?} during {phase_name}",
+                limits.timeout.unwrap_or_default()
+            )));
+        }
+        Ok(VmState::Continue)
+    });
+
+    let result = f();
+    lua.remove_interrupt();
+    result.map_err(|err| {
+        if is_memory_error(&err) {
+            eyre!(
+                "scaffold '{scaffold}' exceeded the memory limit of {} bytes during {phase}",
+                limits.memory.unwrap_or_default()
+            )
+        } else {
+            err.into()
+        }
+    })
+}
+
+fn is_memory_error(err: &mlua::Error) -> bool {
+    match err {
+        mlua::Error::MemoryError(_) => true,
+        mlua::Error::CallbackError { cause, .. } | mlua::Error::WithContext { cause, .. } => {
+            is_memory_error(cause)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const UNLIMITED: Limits = Limits {
+        instructions: None,
+        memory: None,
+        timeout: None,
+    };
+
+    fn run(limits: Limits, code: &str) -> Result<()> {
+        let lua = Lua::new();
+        lua.sandbox(true)?;
+        run_limited(
+            &lua,
+            limits,
+            deadline(limits),
+            Default::default(),
+            "test",
+            "construct",
+            || lua.load(code).exec(),
+        )
+    }
+
+    #[test]
+    fn test_instruction_limit() {
+        let limits = Limits {
+            instructions: Some(10_000),
+            ..UNLIMITED
+        };
+        let err = run(limits, "while true do end").unwrap_err();
+        assert!(
+            format!("{err:?}").contains(
+                "scaffold 'test' exceeded the instruction limit of 10000 during construct"
+            )
+        );
+        assert!(run(limits, "for i = 1, 100 do end").is_ok());
+    }
+
+    #[test]
+    fn test_instruction_limit_survives_pcall() {
+        let limits = Limits {
+            instructions: Some(10_000),
+            ..UNLIMITED
+        };
+        assert!(
+            run(
+                limits,
+                "while true do pcall(function() while true do end end) end"
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let limits = Limits {
+            memory: Some(4 * 1024 * 1024),
+            ..UNLIMITED
+        };
+        let err = run(limits, "local t = {} for i = 1, 10000000 do t[i] = i end").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "scaffold 'test' exceeded the memory limit of 4194304 bytes during construct"
+        );
+    }
+
+    #[test]
+    fn test_timeout() {
+        let limits = Limits {
+            timeout: Some(Duration::from_millis(50)),
+            ..UNLIMITED
+        };
+        let err = run(limits, "while true do end").unwrap_err();
+        assert!(format!("{err:?}").contains("exceeded the time limit of 50ms during construct"));
+    }
+}
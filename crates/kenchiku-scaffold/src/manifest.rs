@@ -0,0 +1,526 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use eyre::{Context as _, Result};
+use kenchiku_common::{Context, meta::find_value_meta};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+use tracing::info;
+
+use crate::{
+    Scaffold,
+    changes::{ChangeKind, ChangePlan, copy_dir_all, copy_symlink, list_files},
+    conflict::Resolution,
+    transaction::Transaction,
+    update::new_sibling,
+};
+
+/// Location of the manifest, relative to the project root.
+pub const MANIFEST_PATH: &str = ".kenchiku/manifest.json";
+/// Location of the files generated by the last construct or update, relative to the project
+/// root. Used as common ancestor when merging updates.
+pub const BASE_PATH: &str = ".kenchiku/base";
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Records which scaffolds and patches produced the files of a project.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    Construct,
+    Patch,
+    Update,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub kind: EntryKind,
+    pub scaffold: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub patch: Option<String>,
+    /// Path the scaffold was loaded from.
+    pub source: PathBuf,
+    /// Checksum over all files of the scaffold, changes whenever the scaffold changes.
+    pub scaffold_hash: String,
+    pub kenchiku_version: String,
+    /// Seconds since the unix epoch.
+    pub timestamp: u64,
+    /// Values the scaffold requested, either set beforehand or prompted for.
+    pub values: BTreeMap<String, String>,
+    /// Checksums of the generated or patched files, relative to the project root.
+    pub files: BTreeMap<PathBuf, String>,
+    /// Files removed by a patch.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deleted: Vec<PathBuf>,
+}
+
+impl Manifest {
+    /// Loads the manifest of the project in `project_dir`, empty if there is none yet.
+    pub fn load(project_dir: &Path) -> Result<Self> {
+        let path = project_dir.join(MANIFEST_PATH);
+        match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content)
+                .wrap_err(format!("failed to parse manifest {path:?}")),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err).wrap_err(format!("failed to read manifest {path:?}")),
+        }
+    }
+
+    pub fn save(&self, project_dir: &Path) -> Result<()> {
+        let path = project_dir.join(MANIFEST_PATH);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, serde_json::to_string_pretty(self)? + "\n")
+            .wrap_err(format!("failed to write manifest {path:?}"))
+    }
+
+    /// The most recent construct or update, which holds the values to update with.
+    pub fn last_generation(&self) -> Option<&ManifestEntry> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| matches!(entry.kind, EntryKind::Construct | EntryKind::Update))
+    }
+
+    /// Appends `entry` to the manifest of the project in `project_dir`.
+    pub fn append(project_dir: &Path, entry: ManifestEntry) -> Result<()> {
+        let mut manifest = Self::load(project_dir)?;
+        manifest.entries.push(entry);
+        manifest.save(project_dir)
+    }
+
+    /// Like [`Manifest::append`], as part of `transaction`. The entries are only recorded if
+    /// the changes they describe are applied, and the changes are rolled back if writing the
+    /// manifest fails.
+    pub(crate) fn append_in(
+        transaction: &mut Transaction,
+        project_dir: &Path,
+        entries: Vec<ManifestEntry>,
+    ) -> Result<()> {
+        let mut manifest = Self::load(project_dir)?;
+        manifest.entries.extend(entries);
+        let staged = tempfile::tempdir()?;
+        manifest.save(staged.path())?;
+        transaction
+            .move_into(
+                &staged.path().join(MANIFEST_PATH),
+                &project_dir.join(MANIFEST_PATH),
+            )
+            .wrap_err("failed to write manifest")
+    }
+}
+
+impl ManifestEntry {
+    /// Entry for a construct or update run, `generated_dir` contains the files the scaffold
+    /// generated.
+    pub(crate) fn generated(
+        kind: EntryKind,
+        scaffold: &Scaffold,
+        context: &Context,
+        generated_dir: &Path,
+    ) -> Result<Self> {
+        let files = list_files(generated_dir)?
+            .into_iter()
+            .map(|path| Ok((path.clone(), checksum_file(&generated_dir.join(path))?)))
+            .collect::<Result<_>>()?;
+        Self::new(kind, scaffold, None, context, files, vec![])
+    }
+
+    /// Narrows the files of an update entry to the generated files which ended up in the
+    /// project: accepted changes without conflicts and files `staged` (the merged project)
+    /// already contained as generated. Rejected and conflicting changes are dropped.
+    pub(crate) fn retain_updated(
+        &mut self,
+        plan: &ChangePlan,
+        rejected: &BTreeSet<PathBuf>,
+        conflicts: &[PathBuf],
+        staged: &Path,
+    ) -> Result<()> {
+        let changed: BTreeSet<_> = plan.changes.iter().map(|change| &change.path).collect();
+        let mut files = BTreeMap::new();
+        for (path, checksum) in std::mem::take(&mut self.files) {
+            let applied = if changed.contains(&path) {
+                !rejected.contains(&path) && !conflicts.contains(&path)
+            } else {
+                staged.join(&path).is_file() && checksum_file(&staged.join(&path))? == checksum
+            };
+            if applied {
+                files.insert(path, checksum);
+            }
+        }
+        self.files = files;
+        Ok(())
+    }
+
+    /// Adjusts the files of a construct entry to what was actually moved into the output: files
+    /// kept because of a conflict are dropped, files written next to an existing one are
+    /// recorded under the path they were written to.
+    pub(crate) fn retain_moved(
+        &mut self,
+        generated_dir: &Path,
+        resolutions: &[(PathBuf, Resolution)],
+    ) {
+        let resolutions: Vec<_> = resolutions
+            .iter()
+            .filter_map(|(source, resolution)| {
+                Some((source.strip_prefix(generated_dir).ok()?, *resolution))
+            })
+            .collect();
+        self.files = std::mem::take(&mut self.files)
+            .into_iter()
+            .filter_map(|(path, checksum)| {
+                match resolutions
+                    .iter()
+                    .find(|(source, _)| path.starts_with(source))
+                {
+                    Some((_, Resolution::Keep | Resolution::Leave)) => None,
+                    Some((source, Resolution::WriteNew)) => {
+                        let rest = path
+                            .strip_prefix(source)
+                            .expect("path to start with source");
+                        let moved = if rest.as_os_str().is_empty() {
+                            new_sibling(source)
+                        } else {
+                            new_sibling(source).join(rest)
+                        };
+                        Some((moved, checksum))
+                    }
+                    _ => Some((path, checksum)),
+                }
+            })
+            .collect();
+    }
+
+    /// Entry for an applied patch, checksums are taken from the files in `project_dir`.
+    pub(crate) fn patch(
+        scaffold: &Scaffold,
+        name: &str,
+        context: &Context,
+        plan: &ChangePlan,
+        project_dir: &Path,
+    ) -> Result<Self> {
+        let mut files = BTreeMap::new();
+        let mut deleted = Vec::new();
+        for change in &plan.changes {
+            match change.kind {
+                ChangeKind::Created | ChangeKind::Modified => {
+                    let checksum = checksum_file(&project_dir.join(&change.path))?;
+                    files.insert(change.path.clone(), checksum);
+                }
+                ChangeKind::Deleted => deleted.push(change.path.clone()),
+                ChangeKind::Skipped | ChangeKind::Conflict => {}
+            }
+        }
+        Self::new(
+            EntryKind::Patch,
+            scaffold,
+            Some(name.to_string()),
+            context,
+            files,
+            deleted,
+        )
+    }
+
+    fn new(
+        kind: EntryKind,
+        scaffold: &Scaffold,
+        patch: Option<String>,
+        context: &Context,
+        files: BTreeMap<PathBuf, String>,
+        deleted: Vec<PathBuf>,
+    ) -> Result<Self> {
+        // secrets are never persisted, an update asks for them again
+        let values = persisted_values(context);
+        Ok(Self {
+            kind,
+            scaffold: scaffold.name.clone(),
+            patch,
+            source: scaffold
+                .path
+                .canonicalize()
+                .unwrap_or_else(|_| scaffold.path.clone()),
+            scaffold_hash: checksum_dir(&scaffold.path)?,
+            kenchiku_version: VERSION.to_string(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or_default(),
+            values,
+            files,
+            deleted,
+        })
+    }
+}
+
+/// The declared values the scaffold used, without secrets since those are never written to
+/// disk. Values shared with other patches of the same run are left out unless declared.
+pub(crate) fn persisted_values(context: &Context) -> BTreeMap<String, String> {
+    context
+        .resolved_values
+        .lock()
+        .expect("lock to not be poisoned")
+        .iter()
+        .filter(|(id, _)| {
+            find_value_meta(&context.values_meta, id).is_some_and(|meta| !meta.is_secret())
+        })
+        .map(|(id, val)| (id.clone(), val.clone()))
+        .collect()
+}
+
+/// Values of type `secret` the run used. Empty ones are left out, every file contains them.
+pub(crate) fn secret_values(context: &Context) -> Vec<String> {
+    context
+        .resolved_values
+        .lock()
+        .expect("lock to not be poisoned")
+        .iter()
+        .filter(|(id, val)| {
+            !val.is_empty()
+                && find_value_meta(&context.values_meta, id).is_some_and(|meta| meta.is_secret())
+        })
+        .map(|(_, val)| val.clone())
+        .collect()
+}
+
+/// Copies the files a construct generated, to become the base snapshot once they are moved into
+/// the project. Files containing one of the `secrets` are left out, the snapshot is stored in
+/// plain text and often committed.
+pub(crate) fn snapshot_base(generated_dir: &Path, secrets: &[String]) -> Result<TempDir> {
+    let base = tempfile::tempdir()?;
+    copy_dir_all(generated_dir, base.path()).wrap_err("failed to snapshot generated files")?;
+    let mut left_out = Vec::new();
+    for path in list_files(base.path())? {
+        let file = base.path().join(&path);
+        if fs::symlink_metadata(&file)?.is_symlink() {
+            continue;
+        }
+        let content = fs::read(&file)?;
+        let contains = |secret: &String| {
+            content
+                .windows(secret.len())
+                .any(|window| window == secret.as_bytes())
+        };
+        if secrets.iter().any(contains) {
+            fs::remove_file(&file)?;
+            left_out.push(format!("- {}", path.display()));
+        }
+    }
+    if !left_out.is_empty() {
+        info!(
+            "These files contain secret values and are left out of {BASE_PATH}, updates can't \
+             merge changes to them:\n{}",
+            left_out.join("\n")
+        );
+    }
+    Ok(base)
+}
+
+/// Removes the generated files the user did not accept (kept the existing file or had it
+/// written next to it) from the `base` snapshot of a construct.
+pub(crate) fn remove_rejected(
+    base: &Path,
+    generated_dir: &Path,
+    resolutions: &[(PathBuf, Resolution)],
+) -> Result<()> {
+    for (source, resolution) in resolutions {
+        if !matches!(
+            resolution,
+            Resolution::Keep | Resolution::Leave | Resolution::WriteNew
+        ) {
+            continue;
+        }
+        if let Ok(path) = source.strip_prefix(generated_dir) {
+            remove_optional(&base.join(path))?;
+        }
+    }
+    Ok(())
+}
+
+/// Builds the base snapshot after an update: the `generated` files, except for the `rejected`
+/// paths whose changes were not (fully) accepted, those keep their content from the previous
+/// `base`. Files containing `secrets` are left out as in [`snapshot_base`].
+pub(crate) fn updated_base(
+    base: &Path,
+    generated: &Path,
+    rejected: &BTreeSet<PathBuf>,
+    secrets: &[String],
+) -> Result<TempDir> {
+    let snapshot = snapshot_base(generated, secrets)?;
+    for path in rejected {
+        let target = snapshot.path().join(path);
+        remove_optional(&target)?;
+        let previous = base.join(path);
+        let Ok(metadata) = fs::symlink_metadata(&previous) else {
+            continue;
+        };
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if metadata.is_symlink() {
+            copy_symlink(&previous, &target)?;
+        } else {
+            fs::copy(&previous, &target)?;
+        }
+    }
+    Ok(snapshot)
+}
+
+/// Replaces the base snapshot of the project in `project_dir` with `base` as part of
+/// `transaction`, so the previous one is restored if the run fails.
+pub(crate) fn replace_base(
+    transaction: &mut Transaction,
+    project_dir: &Path,
+    base: &Path,
+) -> Result<()> {
+    transaction
+        .move_into(base, &project_dir.join(BASE_PATH))
+        .wrap_err("failed to save base snapshot")
+}
+
+fn remove_optional(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+        Ok(_) => fs::remove_file(path)?,
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err).wrap_err(format!("failed to remove {path:?}")),
+    }
+    Ok(())
+}
+
+/// Checksum of a file's content, or of the target for symlinks.
+pub fn checksum_file(path: &Path) -> Result<String> {
+    let content = if fs::symlink_metadata(path)?.is_symlink() {
+        fs::read_link(path)?
+            .to_string_lossy()
+            .into_owned()
+            .into_bytes()
+    } else {
+        fs::read(path).wrap_err(format!("failed to read {path:?}"))?
+    };
+    Ok(format_digest(Sha256::digest(content)))
+}
+
+/// Checksum over the paths and contents of all files in `dir`.
+pub fn checksum_dir(dir: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for path in list_files(dir)? {
+        hasher.update(path.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update(checksum_file(&dir.join(path))?.as_bytes());
+        hasher.update([0]);
+    }
+    Ok(format_digest(hasher.finalize()))
+}
+
+fn format_digest(digest: impl AsRef<[u8]>) -> String {
+    let hex: String = digest
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("sha256:{hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("a.txt"), "hello")?;
+        assert_eq!(
+            checksum_file(&dir.path().join("a.txt"))?,
+            "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum_dir_changes_with_content() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::create_dir(dir.path().join("sub"))?;
+        fs::write(dir.path().join("sub/a.txt"), "a")?;
+        let before = checksum_dir(dir.path())?;
+        assert_eq!(before, checksum_dir(dir.path())?);
+
+        fs::write(dir.path().join("sub/a.txt"), "b")?;
+        assert_ne!(before, checksum_dir(dir.path())?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_missing_and_append() -> Result<()> {
+        let project = tempfile::tempdir()?;
+        assert!(Manifest::load(project.path())?.entries.is_empty());
+
+        let entry = ManifestEntry {
+            kind: EntryKind::Patch,
+            scaffold: "test".to_string(),
+            patch: Some("edit".to_string()),
+            source: "/scaffolds/test".into(),
+            scaffold_hash: "sha256:abc".to_string(),
+            kenchiku_version: VERSION.to_string(),
+            timestamp: 0,
+            values: BTreeMap::from([("name".to_string(), "value".to_string())]),
+            files: BTreeMap::from([("a.txt".into(), "sha256:def".to_string())]),
+            deleted: vec![],
+        };
+        Manifest::append(project.path(), entry.clone())?;
+        Manifest::append(project.path(), entry)?;
+
+        let manifest = Manifest::load(project.path())?;
+        assert_eq!(manifest.entries.len(), 2);
+        assert_eq!(manifest.entries[1].patch.as_deref(), Some("edit"));
+        assert_eq!(manifest.entries[1].values["name"], "value");
+        Ok(())
+    }
+
+    #[test]
+    fn test_retain_moved() {
+        let mut entry = ManifestEntry {
+            kind: EntryKind::Construct,
+            scaffold: "test".to_string(),
+            patch: None,
+            source: "/scaffolds/test".into(),
+            scaffold_hash: "sha256:abc".to_string(),
+            kenchiku_version: VERSION.to_string(),
+            timestamp: 0,
+            values: BTreeMap::new(),
+            files: ["a.txt", "kept/b.txt", "new.txt", "new_dir/c.txt"]
+                .into_iter()
+                .map(|path| (path.into(), "sha256:def".to_string()))
+                .collect(),
+            deleted: vec![],
+        };
+        let generated = Path::new("/work");
+        entry.retain_moved(
+            generated,
+            &[
+                (generated.join("kept"), Resolution::Keep),
+                (generated.join("new.txt"), Resolution::WriteNew),
+                (generated.join("new_dir"), Resolution::WriteNew),
+            ],
+        );
+        assert_eq!(
+            entry.files.keys().collect::<Vec<_>>(),
+            vec![
+                Path::new("a.txt"),
+                Path::new("new.txt.kenchiku-new"),
+                Path::new("new_dir.kenchiku-new/c.txt"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,502 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+
+use eyre::{Context as _, Result, eyre};
+use kenchiku_common::{Context, meta::ValueMeta};
+use tempfile::TempDir;
+use tracing::{debug, info};
+
+use crate::{
+    PatchStatus, Scaffold,
+    changes::{ChangePlan, copy_project, list_project_files, warn_skipped_dirs},
+    discovery::discover_scaffold,
+    manifest::{Manifest, ManifestEntry, checksum_file},
+    review::review_changes,
+    transaction::Transaction,
+};
+
+/// Patches of one or more scaffolds including the patches they require, run in dependency
+/// order against a single staging copy of the project. Values are shared between them, so a
+/// value two patches declare is only asked for once.
+pub struct PatchSet {
+    scaffolds: Vec<Scaffold>,
+    /// Patches in the order they run, as index into `scaffolds` and patch name.
+    order: Vec<(usize, String)>,
+}
+
+/// Splits `<scaffold>:<patch>`.
+pub fn split_patch_id(id: &str) -> Result<(&str, &str)> {
+    id.split_once(':').ok_or(eyre!(
+        "no patch name found in {id}, did you use the format '<scaffold>:<patch>'?"
+    ))
+}
+
+/// Loads scaffolds required by patches from the scaffold search path, within the limits of
+/// `context`. Relative paths are relative to `context.scaffold_dir`, like for `extends`.
+pub fn load_discovered(name: &str, context: &Context) -> Result<Scaffold> {
+    let path = if name.starts_with('.') {
+        context.scaffold_dir.join(name)
+    } else {
+        discover_scaffold(name.to_string()).ok_or(eyre!("scaffold '{name}' not found"))?
+    };
+    Scaffold::load_invoked(path, context)
+}
+
+impl PatchSet {
+    /// Resolves `patches` (as `<scaffold>:<patch>`) and the patches they require, loading
+    /// scaffolds with `load`.
+    pub fn resolve(
+        patches: &[String],
+        mut load: impl FnMut(&str) -> Result<Scaffold>,
+    ) -> Result<Self> {
+        let mut set = Self {
+            scaffolds: vec![],
+            order: vec![],
+        };
+        for id in patches {
+            let (scaffold, patch) = split_patch_id(id)?;
+            set.visit(scaffold, patch, &mut vec![], &mut load)?;
+        }
+        Ok(set)
+    }
+
+    /// Resolves patch `name` of an already loaded scaffold and the patches it requires.
+    pub fn for_scaffold(
+        scaffold: Scaffold,
+        name: &str,
+        mut load: impl FnMut(&str) -> Result<Scaffold>,
+    ) -> Result<Self> {
+        let scaffold_name = scaffold.name.clone();
+        let mut set = Self {
+            scaffolds: vec![scaffold],
+            order: vec![],
+        };
+        set.visit(&scaffold_name, name, &mut vec![], &mut load)?;
+        Ok(set)
+    }
+
+    fn visit(
+        &mut self,
+        scaffold: &str,
+        name: &str,
+        stack: &mut Vec<String>,
+        load: &mut dyn FnMut(&str) -> Result<Scaffold>,
+    ) -> Result<()> {
+        let index = match self.scaffolds.iter().position(|s| s.name == scaffold) {
+            Some(index) => index,
+            None => {
+                // scaffolds given by path are named after their directory
+                let loaded = load(scaffold)?;
+                match self.scaffolds.iter().position(|s| s.name == loaded.name) {
+                    Some(index) => index,
+                    None => {
+                        self.scaffolds.push(loaded);
+                        self.scaffolds.len() - 1
+                    }
+                }
+            }
+        };
+        let scaffold = self.scaffolds[index].name.clone();
+        let id = format!("{scaffold}:{name}");
+        if stack.contains(&id) {
+            stack.push(id);
+            return Err(eyre!(
+                "patches require each other in a cycle: {}",
+                stack.join(" -> ")
+            ));
+        }
+        if self.order.iter().any(|(i, n)| *i == index && n == name) {
+            return Ok(());
+        }
+        let requires = self.scaffolds[index]
+            .meta
+            .patches
+            .get(name)
+            .ok_or(eyre!(
+                "no patch with name '{name}' found in scaffold '{scaffold}'"
+            ))?
+            .requires
+            .clone();
+
+        stack.push(id);
+        for required in &requires {
+            // patches of the same scaffold can be required by name only
+            let (required_scaffold, required_name) =
+                split_patch_id(required).unwrap_or((&scaffold, required));
+            self.visit(required_scaffold, required_name, stack, load)?;
+        }
+        stack.pop();
+        self.order.push((index, name.to_string()));
+        Ok(())
+    }
+
+    /// The patches in the order they run, as `<scaffold>:<patch>`.
+    pub fn ids(&self) -> Vec<String> {
+        self.order
+            .iter()
+            .map(|(index, name)| format!("{}:{name}", self.scaffolds[*index].name))
+            .collect()
+    }
+
+    /// The values of all patches in the set.
+    pub fn values_meta(&self) -> HashMap<String, ValueMeta> {
+        self.order
+            .iter()
+            .flat_map(|(index, name)| self.scaffolds[*index].meta.patches[name].values.clone())
+            .collect()
+    }
+
+    /// Runs all patches against a copy of the project, without touching the project itself.
+    pub fn plan(&self, context: Context) -> Result<ChangePlan> {
+        let (_staging_dir, plan, _touched) = self.stage(&context)?;
+        Ok(plan)
+    }
+
+    /// Runs all patches against a copy of the project, lets the user review every change and
+    /// applies the accepted ones to the project at once. Returns the applied changes.
+    ///
+    /// Every patch is recorded in the project's manifest with the files it changed.
+    pub fn apply(self, context: Context) -> Result<ChangePlan> {
+        let project_dir = context.output.clone();
+        let (staging_dir, plan, touched) = self.stage(&context)?;
+        let plan = review_changes(plan, staging_dir.path(), &project_dir, &context)?;
+
+        let mut entries = Vec::new();
+        for ((index, name), touched) in self.order.iter().zip(touched) {
+            let Some(touched) = touched else {
+                continue;
+            };
+            let patch_plan = ChangePlan {
+                changes: plan
+                    .changes
+                    .iter()
+                    .filter(|change| touched.contains(&change.path))
+                    .cloned()
+                    .collect(),
+            };
+            if patch_plan.is_empty() {
+                continue;
+            }
+            let scaffold = &self.scaffolds[*index];
+            let patch_context = self.patch_context(&context, *index, name, &project_dir);
+            // the staged files are the ones which get applied
+            entries.push(ManifestEntry::patch(
+                scaffold,
+                name,
+                &patch_context,
+                &patch_plan,
+                staging_dir.path(),
+            )?);
+        }
+        Transaction::run(|transaction| {
+            plan.apply_in(transaction, staging_dir.path(), &project_dir)?;
+            Manifest::append_in(transaction, &project_dir, entries)
+        })?;
+        info!(to = ?project_dir, changes = plan.changes.len(), "Patches applied");
+        Ok(plan)
+    }
+
+    fn patch_context(&self, context: &Context, index: usize, name: &str, dir: &Path) -> Context {
+        let scaffold = &self.scaffolds[index];
+        Context {
+            working_dir: dir.to_path_buf(),
+            output: dir.to_path_buf(),
+            scaffold_dir: scaffold.path.clone(),
+            values_meta: scaffold.meta.patches[name].values.clone(),
+            parent_scaffold_dirs: vec![],
+            ..context.clone()
+        }
+    }
+
+    /// Runs the patches in a staging copy of the project. Returns the staging directory, the
+    /// changes compared to the project and the files every patch touched (`None` for skipped
+    /// patches).
+    #[allow(clippy::type_complexity)]
+    fn stage(
+        &self,
+        context: &Context,
+    ) -> Result<(TempDir, ChangePlan, Vec<Option<BTreeSet<PathBuf>>>)> {
+        let staging_dir = tempfile::tempdir()?;
+        debug!(project = ?context.output, staging = ?staging_dir.path(), "Staging patches");
+        copy_project(&context.output, staging_dir.path())
+            .wrap_err("failed to copy project into staging directory")?;
+        let staging_context = Context {
+            staging: true,
+            ..context.clone()
+        };
+        let touched = self.run_in(staging_dir.path(), &staging_context)?;
+        warn_skipped_dirs(staging_dir.path());
+        let plan = ChangePlan::compute(staging_dir.path(), &context.output, true, true)?;
+        Ok((staging_dir, plan, touched))
+    }
+
+    /// Runs the patches directly in `dir`, for scaffolds invoking patches from Lua where `dir`
+    /// already is a working or staging directory.
+    pub(crate) fn run_in(
+        &self,
+        dir: &Path,
+        context: &Context,
+    ) -> Result<Vec<Option<BTreeSet<PathBuf>>>> {
+        let mut touched = Vec::new();
+        for (index, name) in &self.order {
+            let scaffold = &self.scaffolds[*index];
+            let patch_context = self.patch_context(context, *index, name, dir);
+            // earlier patches already ran in dir, check against it
+            match scaffold.check_patch(name, patch_context.clone())? {
+                PatchStatus::Applicable => {}
+                PatchStatus::NotApplicable => {
+                    return Err(eyre!(
+                        "patch '{}:{name}' does not apply to {}",
+                        scaffold.name,
+                        context.output.display()
+                    ));
+                }
+                PatchStatus::AlreadyApplied => {
+                    info!(
+                        scaffold = scaffold.name,
+                        patch = name,
+                        "Patch was already applied, skipping it"
+                    );
+                    touched.push(None);
+                    continue;
+                }
+            }
+            let before = checksums(dir)?;
+            scaffold.call_patch(name, patch_context)?;
+            let after = checksums(dir)?;
+            let changed = before
+                .keys()
+                .chain(after.keys())
+                .filter(|path| before.get(*path) != after.get(*path))
+                .cloned()
+                .collect();
+            touched.push(Some(changed));
+        }
+        Ok(touched)
+    }
+}
+
+fn checksums(dir: &Path) -> Result<BTreeMap<PathBuf, String>> {
+    list_project_files(dir)?
+        .into_iter()
+        .map(|path| Ok((path.clone(), checksum_file(&dir.join(path))?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write_scaffold(root: &Path, name: &str, lua: &str) -> Result<()> {
+        fs::create_dir_all(root.join(name))?;
+        fs::write(root.join(name).join("scaffold.lua"), lua)?;
+        Ok(())
+    }
+
+    fn loader(root: &Path) -> impl FnMut(&str) -> Result<Scaffold> + '_ {
+        move |name| Scaffold::load(root.join(name))
+    }
+
+    #[test]
+    fn test_required_patches_run_first() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write_scaffold(
+            root.path(),
+            "base",
+            r#"
+                return {
+                    description = "base",
+                    construct = function() end,
+                    patches = {
+                        ci = { description = "ci", run = function() end, requires = { "lint" } },
+                        lint = { description = "lint", run = function() end, requires = { "extra:fmt" } },
+                    },
+                }
+            "#,
+        )?;
+        write_scaffold(
+            root.path(),
+            "extra",
+            r#"
+                return {
+                    description = "extra",
+                    construct = function() end,
+                    patches = { fmt = { description = "fmt", run = function() end } },
+                }
+            "#,
+        )?;
+
+        let set = PatchSet::resolve(
+            &["base:ci".to_string(), "extra:fmt".to_string()],
+            loader(root.path()),
+        )?;
+        assert_eq!(set.ids(), ["extra:fmt", "base:lint", "base:ci"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_patches_requiring_each_other_fail() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write_scaffold(
+            root.path(),
+            "base",
+            r#"
+                return {
+                    description = "base",
+                    construct = function() end,
+                    patches = {
+                        a = { description = "a", run = function() end, requires = { "b" } },
+                        b = { description = "b", run = function() end, requires = { "base:a" } },
+                    },
+                }
+            "#,
+        )?;
+
+        let err = PatchSet::resolve(&["base:a".to_string()], loader(root.path()))
+            .err()
+            .expect("cycle to be detected");
+        assert_eq!(
+            err.to_string(),
+            "patches require each other in a cycle: base:a -> base:b -> base:a"
+        );
+        let err = PatchSet::resolve(&["base:missing".to_string()], loader(root.path()))
+            .err()
+            .expect("missing patch to be reported");
+        assert!(err.to_string().contains("no patch with name 'missing'"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_patches_share_values_and_apply_together() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write_scaffold(
+            root.path(),
+            "base",
+            r##"
+                return {
+                    description = "base",
+                    construct = function() end,
+                    patches = {
+                        readme = {
+                            description = "readme",
+                            values = { name = { type = "string", description = "Name" } },
+                            run = function()
+                                fs.write("README.md", "# " .. values.get("name"))
+                            end,
+                        },
+                    },
+                }
+            "##,
+        )?;
+        write_scaffold(
+            root.path(),
+            "extra",
+            r##"
+                return {
+                    description = "extra",
+                    construct = function() end,
+                    patches = {
+                        license = {
+                            description = "license",
+                            requires = { "base:readme" },
+                            values = { name = { type = "string", description = "Name" } },
+                            run = function()
+                                local readme = fs.read("README.md", { source = "workdir" })
+                                fs.write("README.md", readme .. "\nMIT")
+                                fs.write("LICENSE", "MIT " .. values.get("name"))
+                            end,
+                        },
+                    },
+                }
+            "##,
+        )?;
+        let project_dir = tempfile::tempdir()?;
+        let prompts = std::sync::Arc::new(std::sync::Mutex::new(0));
+        let counter = prompts.clone();
+        let context = Context {
+            output: project_dir.path().to_path_buf(),
+            confirm_all: 1,
+            prompt_value: std::sync::Arc::new(move |_, _, _, _, _, _| {
+                *counter.lock().expect("lock to not be poisoned") += 1;
+                Ok("demo".to_string())
+            }),
+            ..Default::default()
+        };
+
+        let set = PatchSet::resolve(&["extra:license".to_string()], loader(root.path()))?;
+        assert!(set.values_meta().contains_key("name"));
+        let plan = set.apply(context)?;
+        assert_eq!(plan.changes.len(), 2);
+        assert_eq!(*prompts.lock().expect("lock to not be poisoned"), 1);
+        assert_eq!(
+            fs::read_to_string(project_dir.path().join("README.md"))?,
+            "# demo\nMIT"
+        );
+
+        let manifest = Manifest::load(project_dir.path())?;
+        let entries: Vec<_> = manifest
+            .entries
+            .iter()
+            .map(|entry| {
+                (
+                    format!(
+                        "{}:{}",
+                        entry.scaffold,
+                        entry.patch.as_deref().unwrap_or("")
+                    ),
+                    entry.files.keys().cloned().collect::<Vec<_>>(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("base:readme".to_string(), vec![PathBuf::from("README.md")]),
+                (
+                    "extra:license".to_string(),
+                    vec![PathBuf::from("LICENSE"), PathBuf::from("README.md")]
+                ),
+            ]
+        );
+        assert_eq!(manifest.entries[1].values["name"], "demo");
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_skipped_dirs_at_the_root_are_left_out() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        write_scaffold(
+            root.path(),
+            "base",
+            r#"
+                return {
+                    description = "base",
+                    construct = function() end,
+                    patches = {
+                        hooks = {
+                            description = "hooks",
+                            run = function()
+                                fs.mkdir("src/target")
+                                fs.write("src/target/mod.rs", "mod")
+                                fs.mkdir(".git/hooks")
+                                fs.write(".git/hooks/pre-commit", "lint")
+                            end,
+                        },
+                    },
+                }
+            "#,
+        )?;
+        let project_dir = tempfile::tempdir()?;
+        let set = PatchSet::resolve(&["base:hooks".to_string()], loader(root.path()))?;
+        let plan = set.plan(Context {
+            output: project_dir.path().to_path_buf(),
+            ..Default::default()
+        })?;
+        let paths: Vec<_> = plan.changes.iter().map(|change| &change.path).collect();
+        assert_eq!(paths, [&PathBuf::from("src/target/mod.rs")]);
+        Ok(())
+    }
+}
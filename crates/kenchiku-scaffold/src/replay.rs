@@ -0,0 +1,106 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use eyre::{Context as _, Result};
+use kenchiku_common::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::manifest::persisted_values;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Answers given during a construct run, to run it again later with the same values.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Replay {
+    pub scaffold: String,
+    pub kenchiku_version: String,
+    /// Values the scaffold requested, secrets are left out and need to be provided again.
+    pub values: BTreeMap<String, String>,
+}
+
+impl Replay {
+    pub fn new(scaffold: &str, context: &Context) -> Self {
+        Self {
+            scaffold: scaffold.to_string(),
+            kenchiku_version: VERSION.to_string(),
+            values: persisted_values(context),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let content =
+            fs::read_to_string(path).wrap_err(format!("failed to read replay file {path:?}"))?;
+        serde_json::from_str(&content).wrap_err(format!("failed to parse replay file {path:?}"))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)? + "\n")
+            .wrap_err(format!("failed to write replay file {path:?}"))
+    }
+}
+
+/// Whether the user answered any prompts, only then saving a replay is worth offering.
+pub fn was_prompted(context: &Context) -> bool {
+    context
+        .resolved_values
+        .lock()
+        .expect("lock to not be poisoned")
+        .keys()
+        .any(|id| !context.values.contains_key(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Scaffold;
+    use std::{collections::HashMap, sync::Arc};
+
+    #[test]
+    fn test_replay_records_answers_without_secrets() -> Result<()> {
+        let scaffold_dir = tempfile::tempdir()?;
+        fs::write(
+            scaffold_dir.path().join("scaffold.lua"),
+            r#"
+                return {
+                    description = "replay",
+                    values = {
+                        name = { type = "string", description = "Name" },
+                        ports = { type = "list", description = "Ports" },
+                        token = { type = "secret", description = "Token" },
+                    },
+                    construct = function()
+                        values.get("name")
+                        values.get("ports")
+                        values.get("token")
+                    end,
+                }
+            "#,
+        )?;
+        let scaffold = Scaffold::load(scaffold_dir.path().to_path_buf())?;
+        let context = Context {
+            values_meta: scaffold.meta.values.clone(),
+            values: HashMap::from([("name".to_string(), "demo".to_string())]),
+            prompt_value: Arc::new(|id, _, _, _, _, _| {
+                Ok(if id == "ports" { "80,443" } else { "hunter2" }.to_string())
+            }),
+            ..Default::default()
+        };
+        assert!(!was_prompted(&context));
+        scaffold.call_construct(context.clone())?;
+        assert!(was_prompted(&context));
+
+        let path = scaffold_dir.path().join("replay.json");
+        Replay::new(&scaffold.name, &context).save(&path)?;
+        assert!(!fs::read_to_string(&path)?.contains("hunter2"));
+
+        let replay = Replay::load(&path)?;
+        assert_eq!(replay.scaffold, scaffold.name);
+        assert_eq!(
+            replay.values,
+            BTreeMap::from([
+                ("name".to_string(), "demo".to_string()),
+                ("ports".to_string(), "80,443".to_string()),
+            ])
+        );
+        Ok(())
+    }
+}
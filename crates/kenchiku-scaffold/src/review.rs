@@ -0,0 +1,264 @@
+use std::{fs, path::Path};
+
+use eyre::Result;
+use kenchiku_common::Context;
+use similar::{DiffOp, TextDiff};
+use tracing::debug;
+
+use crate::changes::{ChangeKind, ChangePlan, FileChange, read_optional};
+
+/// Number of unchanged lines shown around each hunk.
+const CONTEXT_LINES: usize = 3;
+
+/// Shows a diff for every change and asks the user whether to apply it, first for the whole
+/// file, then (if rejected and the file has multiple hunks) for every single hunk.
+/// Partially accepted files are rewritten in `staged`, so the returned plan can be applied as is.
+pub(crate) fn review_changes(
+    plan: ChangePlan,
+    staged: &Path,
+    dest: &Path,
+    context: &Context,
+) -> Result<ChangePlan> {
+    if context.confirm_all >= 1 {
+        return Ok(plan);
+    }
+
+    let mut accepted = Vec::new();
+    for change in plan.changes {
+        let old = read_optional(&dest.join(&change.path))?;
+        let new = read_optional(&staged.join(&change.path))?;
+
+        let (old_text, new_text) = match (as_text(&old), as_text(&new)) {
+            (Some(old_text), Some(new_text)) => (old_text, new_text),
+            _ => {
+                let message = format!(
+                    "Binary file {} differs\n[patch] Apply changes to '{}'?",
+                    change.path.display(),
+                    change.path.display()
+                );
+                if (context.confirm_fn)(message)? {
+                    accepted.push(change);
+                }
+                continue;
+            }
+        };
+
+        let diff = TextDiff::from_lines(old_text, new_text);
+        let message = format!(
+            "{}[patch] Apply changes to '{}'?",
+            unified_diff(&diff, &change.path),
+            change.path.display()
+        );
+        if (context.confirm_fn)(message)? {
+            accepted.push(change);
+            continue;
+        }
+
+        let hunks = diff.grouped_ops(CONTEXT_LINES);
+        if change.kind != ChangeKind::Modified || hunks.len() < 2 {
+            debug!(path = ?change.path, "Rejected change");
+            continue;
+        }
+
+        let mut accepted_hunks = Vec::new();
+        for (i, hunk) in hunks.iter().enumerate() {
+            let message = format!(
+                "{}[patch] Apply hunk {}/{} to '{}'?",
+                unified_hunk(&diff, hunk, &change.path),
+                i + 1,
+                hunks.len(),
+                change.path.display()
+            );
+            accepted_hunks.push((context.confirm_fn)(message)?);
+        }
+        if !accepted_hunks.contains(&true) {
+            debug!(path = ?change.path, "Rejected all hunks");
+            continue;
+        }
+
+        fs::write(
+            staged.join(&change.path),
+            apply_hunks(&diff, &hunks, &accepted_hunks),
+        )?;
+        accepted.push(FileChange {
+            path: change.path,
+            kind: ChangeKind::Modified,
+        });
+    }
+
+    Ok(ChangePlan { changes: accepted })
+}
+
+/// Missing files count as empty text, so created and deleted files still get a diff.
+fn as_text(content: &Option<Vec<u8>>) -> Option<&str> {
+    match content {
+        Some(content) => std::str::from_utf8(content).ok(),
+        None => Some(""),
+    }
+}
+
+pub(crate) fn unified_diff<'a>(diff: &'a TextDiff<'a, 'a, 'a, str>, path: &Path) -> String {
+    let path = path.display().to_string();
+    diff.unified_diff()
+        .context_radius(CONTEXT_LINES)
+        .header(&format!("a/{path}"), &format!("b/{path}"))
+        .missing_newline_hint(false)
+        .to_string()
+}
+
+fn unified_hunk<'a>(diff: &TextDiff<'a, 'a, 'a, str>, hunk: &[DiffOp], path: &Path) -> String {
+    let path = path.display().to_string();
+    let mut output = format!("--- a/{path}\n+++ b/{path}\n");
+    for op in hunk {
+        for change in diff.iter_changes(op) {
+            output.push_str(&format!("{}{}", change.tag(), change.value()));
+            if change.missing_newline() {
+                output.push('\n');
+            }
+        }
+    }
+    output
+}
+
+/// Rebuilds the new content, only keeping the changes of accepted hunks.
+fn apply_hunks<'a>(
+    diff: &TextDiff<'a, 'a, 'a, str>,
+    hunks: &[Vec<DiffOp>],
+    accepted: &[bool],
+) -> String {
+    let old_lines = diff.old_slices();
+    let new_lines = diff.new_slices();
+    let mut output = String::new();
+    let mut hunk_ops = hunks.iter().zip(accepted).flat_map(|(hunk, accepted)| {
+        hunk.iter()
+            .filter(|op| !matches!(op, DiffOp::Equal { .. }))
+            .map(move |_| *accepted)
+    });
+
+    for op in diff.ops() {
+        let use_new = match op {
+            DiffOp::Equal { .. } => false,
+            _ => hunk_ops.next().unwrap_or(false),
+        };
+        let lines = if use_new {
+            &new_lines[op.new_range()]
+        } else {
+            &old_lines[op.old_range()]
+        };
+        for line in lines {
+            output.push_str(line);
+        }
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn context_answering(answers: Vec<bool>, prompts: Arc<Mutex<Vec<String>>>) -> Context {
+        let answers = Mutex::new(answers.into_iter());
+        Context {
+            confirm_fn: Arc::new(move |message| {
+                prompts.lock().unwrap().push(message);
+                Ok(answers.lock().unwrap().next().unwrap_or(false))
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn numbered_lines(changed: &[usize]) -> String {
+        (1..=20)
+            .map(|i| {
+                if changed.contains(&i) {
+                    format!("line {i} changed\n")
+                } else {
+                    format!("line {i}\n")
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_review_accept_file() -> Result<()> {
+        let staged = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        fs::write(dest.path().join("a.txt"), "hello\n")?;
+        fs::write(staged.path().join("a.txt"), "hello world\n")?;
+
+        let plan = ChangePlan::compute(staged.path(), dest.path(), true, true)?;
+        let prompts = Arc::new(Mutex::new(Vec::new()));
+        let context = context_answering(vec![true], prompts.clone());
+        let reviewed = review_changes(plan, staged.path(), dest.path(), &context)?;
+
+        assert_eq!(reviewed.changes.len(), 1);
+        let prompts = prompts.lock().unwrap();
+        assert_eq!(prompts.len(), 1);
+        assert!(prompts[0].contains("--- a/a.txt"));
+        assert!(prompts[0].contains("-hello\n+hello world\n"));
+        assert!(prompts[0].ends_with("[patch] Apply changes to 'a.txt'?"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_review_reject_created_file() -> Result<()> {
+        let staged = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        fs::write(staged.path().join("new.txt"), "new\n")?;
+
+        let plan = ChangePlan::compute(staged.path(), dest.path(), true, true)?;
+        let prompts = Arc::new(Mutex::new(Vec::new()));
+        let context = context_answering(vec![false], prompts.clone());
+        let reviewed = review_changes(plan, staged.path(), dest.path(), &context)?;
+
+        assert!(reviewed.is_empty());
+        assert_eq!(prompts.lock().unwrap().len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_review_accept_single_hunk() -> Result<()> {
+        let staged = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        fs::write(dest.path().join("a.txt"), numbered_lines(&[]))?;
+        fs::write(staged.path().join("a.txt"), numbered_lines(&[2, 18]))?;
+
+        let plan = ChangePlan::compute(staged.path(), dest.path(), true, true)?;
+        let prompts = Arc::new(Mutex::new(Vec::new()));
+        // reject file, reject first hunk, accept second hunk
+        let context = context_answering(vec![false, false, true], prompts.clone());
+        let reviewed = review_changes(plan, staged.path(), dest.path(), &context)?;
+
+        assert_eq!(reviewed.changes.len(), 1);
+        assert_eq!(reviewed.changes[0].kind, ChangeKind::Modified);
+        assert_eq!(
+            fs::read_to_string(staged.path().join("a.txt"))?,
+            numbered_lines(&[18])
+        );
+        let prompts = prompts.lock().unwrap();
+        assert_eq!(prompts.len(), 3);
+        assert!(prompts[1].ends_with("[patch] Apply hunk 1/2 to 'a.txt'?"));
+        assert!(prompts[2].contains("+line 18 changed"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_review_confirm_all_skips_prompts() -> Result<()> {
+        let staged = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        fs::write(staged.path().join("new.txt"), "new\n")?;
+
+        let plan = ChangePlan::compute(staged.path(), dest.path(), true, true)?;
+        let prompts = Arc::new(Mutex::new(Vec::new()));
+        let context = Context {
+            confirm_all: 1,
+            ..context_answering(vec![], prompts.clone())
+        };
+        let reviewed = review_changes(plan, staged.path(), dest.path(), &context)?;
+
+        assert_eq!(reviewed.changes.len(), 1);
+        assert!(prompts.lock().unwrap().is_empty());
+        Ok(())
+    }
+}
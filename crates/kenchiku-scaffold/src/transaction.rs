@@ -0,0 +1,263 @@
+use std::{
+    ffi::OsString,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Context, Result};
+use tracing::{debug, warn};
+
+use crate::changes::copy_dir_all;
+
+enum Operation {
+    /// `dest` was placed, optionally moved from `source`, replacing `backup` if it existed.
+    Placed {
+        dest: PathBuf,
+        source: Option<PathBuf>,
+        backup: Option<PathBuf>,
+    },
+    /// `dest` was removed and is kept at `backup`.
+    Removed { dest: PathBuf, backup: PathBuf },
+    /// `dir` did not exist before and was created.
+    CreatedDir { dir: PathBuf },
+}
+
+/// Groups file operations on a destination, so they can be undone if one of them fails.
+///
+/// Replaced and removed files are renamed to a hidden backup next to them (same filesystem, so
+/// renaming is atomic) and only deleted once the transaction is committed.
+#[derive(Default)]
+pub(crate) struct Transaction {
+    operations: Vec<Operation>,
+}
+
+impl Transaction {
+    /// Runs `f` in a new transaction, committing if it succeeds and rolling back otherwise.
+    pub fn run<T>(f: impl FnOnce(&mut Transaction) -> Result<T>) -> Result<T> {
+        let mut transaction = Transaction::default();
+        match f(&mut transaction) {
+            Ok(result) => {
+                transaction.commit()?;
+                Ok(result)
+            }
+            Err(err) => {
+                if let Err(rollback_err) = transaction.rollback() {
+                    warn!(?rollback_err, "Failed to roll back changes");
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Moves `source` (file or directory) to `dest`, replacing whatever is at `dest`.
+    pub fn move_into(&mut self, source: &Path, dest: &Path) -> Result<()> {
+        self.create_parent_dirs(dest)?;
+        let temp = sibling(dest, "kenchiku-new");
+        move_path(source, &temp).wrap_err(format!("failed to move file {source:?} to {dest:?}"))?;
+        self.place(&temp, dest, Some(source.to_path_buf()))
+    }
+
+    /// Copies `source` (file, symlink or directory) to `dest`, replacing whatever is at `dest`.
+    pub fn copy_into(&mut self, source: &Path, dest: &Path) -> Result<()> {
+        self.create_parent_dirs(dest)?;
+        let temp = sibling(dest, "kenchiku-new");
+        copy_path(source, &temp).wrap_err(format!("failed to copy {source:?} to {dest:?}"))?;
+        self.place(&temp, dest, None)
+    }
+
+    /// Removes `dest`, keeping a backup until the transaction is committed.
+    pub fn remove(&mut self, dest: &Path) -> Result<()> {
+        let backup = sibling(dest, "kenchiku-backup");
+        fs::rename(dest, &backup).wrap_err(format!("failed to delete {dest:?}"))?;
+        self.operations.push(Operation::Removed {
+            dest: dest.to_path_buf(),
+            backup,
+        });
+        Ok(())
+    }
+
+    fn place(&mut self, temp: &Path, dest: &Path, source: Option<PathBuf>) -> Result<()> {
+        let backup = if fs::symlink_metadata(dest).is_ok() {
+            let backup = sibling(dest, "kenchiku-backup");
+            if let Err(err) = fs::rename(dest, &backup) {
+                match &source {
+                    Some(source) => move_path(temp, source)?,
+                    None => remove_path(temp)?,
+                }
+                return Err(err).wrap_err(format!("failed to replace {dest:?}"));
+            }
+            Some(backup)
+        } else {
+            None
+        };
+        // record before renaming, so a failed rename still restores the backup
+        self.operations.push(Operation::Placed {
+            dest: dest.to_path_buf(),
+            source,
+            backup,
+        });
+        fs::rename(temp, dest).wrap_err(format!("failed to write {dest:?}"))
+    }
+
+    fn create_parent_dirs(&mut self, dest: &Path) -> Result<()> {
+        let Some(parent) = dest.parent() else {
+            return Ok(());
+        };
+        let mut first_missing = None;
+        let mut current = Some(parent);
+        while let Some(dir) = current {
+            if dir.exists() {
+                break;
+            }
+            first_missing = Some(dir.to_path_buf());
+            current = dir.parent();
+        }
+        if let Some(dir) = first_missing {
+            fs::create_dir_all(parent)?;
+            self.operations.push(Operation::CreatedDir { dir });
+        }
+        Ok(())
+    }
+
+    fn commit(self) -> Result<()> {
+        for operation in self.operations {
+            match operation {
+                Operation::Placed {
+                    backup: Some(backup),
+                    ..
+                }
+                | Operation::Removed { backup, .. } => remove_path(&backup)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn rollback(self) -> Result<()> {
+        debug!(operations = self.operations.len(), "Rolling back changes");
+        for operation in self.operations.into_iter().rev() {
+            match operation {
+                Operation::Placed {
+                    dest,
+                    source,
+                    backup,
+                } => {
+                    // the temp file is still there if renaming it to dest failed
+                    let temp = sibling(&dest, "kenchiku-new");
+                    let placed = if fs::symlink_metadata(&temp).is_ok() {
+                        temp
+                    } else {
+                        dest.clone()
+                    };
+                    if fs::symlink_metadata(&placed).is_ok() {
+                        match source {
+                            Some(source) => move_path(&placed, &source)?,
+                            None => remove_path(&placed)?,
+                        }
+                    }
+                    if let Some(backup) = backup {
+                        fs::rename(&backup, &dest)?;
+                    }
+                }
+                Operation::Removed { dest, backup } => fs::rename(&backup, &dest)?,
+                Operation::CreatedDir { dir } => fs::remove_dir_all(&dir)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns a hidden path next to `path`, eg. `dir/.file.txt.kenchiku-backup`.
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn move_path(source: &Path, dest: &Path) -> Result<()> {
+    match fs::rename(source, dest) {
+        Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+            copy_path(source, dest)?;
+            remove_path(source)
+        }
+        result => Ok(result?),
+    }
+}
+
+fn copy_path(source: &Path, dest: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(source)?;
+    if metadata.is_dir() {
+        copy_dir_all(source, dest)
+    } else if metadata.is_symlink() {
+        crate::changes::copy_symlink(source, dest)
+    } else {
+        fs::copy(source, dest)?;
+        Ok(())
+    }
+}
+
+fn remove_path(path: &Path) -> Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)?;
+    } else {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eyre::eyre;
+
+    #[test]
+    fn test_commit_replaces_and_cleans_up() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        fs::write(source.path().join("a.txt"), "new")?;
+        fs::write(dest.path().join("a.txt"), "old")?;
+        fs::write(dest.path().join("b.txt"), "removed")?;
+
+        Transaction::run(|transaction| {
+            transaction.copy_into(&source.path().join("a.txt"), &dest.path().join("a.txt"))?;
+            transaction.remove(&dest.path().join("b.txt"))
+        })?;
+
+        assert_eq!(fs::read_to_string(dest.path().join("a.txt"))?, "new");
+        assert!(!dest.path().join("b.txt").exists());
+        assert_eq!(fs::read_dir(dest.path())?.count(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback_restores_previous_state() -> Result<()> {
+        let source = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        fs::write(source.path().join("a.txt"), "new")?;
+        fs::write(source.path().join("c.txt"), "created")?;
+        fs::write(dest.path().join("a.txt"), "old")?;
+        fs::write(dest.path().join("b.txt"), "removed")?;
+
+        let result: Result<()> = Transaction::run(|transaction| {
+            transaction.move_into(&source.path().join("a.txt"), &dest.path().join("a.txt"))?;
+            transaction.remove(&dest.path().join("b.txt"))?;
+            transaction.copy_into(
+                &source.path().join("c.txt"),
+                &dest.path().join("nested/dir/c.txt"),
+            )?;
+            Err(eyre!("something broke"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(dest.path().join("a.txt"))?, "old");
+        assert_eq!(fs::read_to_string(dest.path().join("b.txt"))?, "removed");
+        assert!(!dest.path().join("nested").exists());
+        assert_eq!(fs::read_dir(dest.path())?.count(), 2);
+        // moved files get moved back
+        assert_eq!(fs::read_to_string(source.path().join("a.txt"))?, "new");
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use eyre::Result;
+use serde::Serialize;
+use tracing::debug;
+
+use crate::changes::{ChangePlan, list_files, read_optional};
+
+/// Changes an update would cause, including the files which could not be merged cleanly.
+#[derive(Debug, Default, Serialize)]
+pub struct UpdatePlan {
+    #[serde(flatten)]
+    pub plan: ChangePlan,
+    /// Files containing conflict markers (or, for binary files, a `.kenchiku-new` sibling).
+    pub conflicts: Vec<PathBuf>,
+}
+
+impl UpdatePlan {
+    pub fn print(&self, writer: &mut dyn std::io::Write) -> std::io::Result<()> {
+        self.plan.print(writer)?;
+        if !self.conflicts.is_empty() {
+            writeln!(writer, "Conflicts, please resolve manually:")?;
+            for path in &self.conflicts {
+                writeln!(writer, "  {}", path.display())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Three-way merges the freshly `generated` files into `project` (a staging copy), using the
+/// output of the previous generation in `base` as common ancestor. Returns the conflicting files.
+///
+/// Files the user did not touch are simply replaced, files the scaffold did not change are kept
+/// as they are. If both changed, text files get merged line by line.
+pub(crate) fn merge_generated(
+    base: &Path,
+    generated: &Path,
+    project: &Path,
+) -> Result<Vec<PathBuf>> {
+    let mut paths = list_files(generated)?;
+    if base.exists() {
+        paths.extend(list_files(base)?);
+    } else {
+        debug!(?base, "No base snapshot found, changed files will conflict");
+    }
+
+    let mut conflicts = Vec::new();
+    for path in paths {
+        let base_content = read_optional(&base.join(&path))?;
+        let current = read_optional(&project.join(&path))?;
+        let new = read_optional(&generated.join(&path))?;
+
+        if new == base_content || new == current {
+            continue;
+        }
+        let target = project.join(&path);
+        match (new, current) {
+            (None, current) => {
+                // removed from the scaffold, only delete if the user did not change it
+                if current.is_some() && current == base_content {
+                    fs::remove_file(&target)?;
+                }
+            }
+            (Some(new), current) if current == base_content => write_file(&target, &new)?,
+            // deleted by the user, keep it that way
+            (Some(_), None) => {}
+            (Some(new), Some(current)) => {
+                let base_content = base_content.unwrap_or_default();
+                match (
+                    std::str::from_utf8(&base_content),
+                    std::str::from_utf8(&current),
+                    std::str::from_utf8(&new),
+                ) {
+                    (Ok(base_text), Ok(current_text), Ok(new_text)) => {
+                        match diffy::merge(base_text, current_text, new_text) {
+                            Ok(merged) => fs::write(&target, merged)?,
+                            Err(conflicted) => {
+                                fs::write(&target, conflicted)?;
+                                conflicts.push(path);
+                            }
+                        }
+                    }
+                    _ => {
+                        write_file(&new_sibling(&target), &new)?;
+                        conflicts.push(path);
+                    }
+                }
+            }
+        }
+    }
+    Ok(conflicts)
+}
+
+fn write_file(path: &Path, content: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, content)?;
+    Ok(())
+}
+
+/// Returns `path` with `.kenchiku-new` appended, eg. `logo.png.kenchiku-new`.
+pub(crate) fn new_sibling(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.file_name().unwrap_or_default());
+    name.push(".kenchiku-new");
+    path.with_file_name(name)
+}
+
+/// Content of the staged file of every change in `plan`, to find out afterwards which changes
+/// the review rejected or only partially accepted.
+pub(crate) fn staged_contents(
+    plan: &ChangePlan,
+    staged: &Path,
+) -> Result<BTreeMap<PathBuf, Option<Vec<u8>>>> {
+    plan.changes
+        .iter()
+        .map(|change| {
+            Ok((
+                change.path.clone(),
+                read_optional(&staged.join(&change.path))?,
+            ))
+        })
+        .collect()
+}
+
+/// Paths from `proposed` (see [`staged_contents`]) which are not part of the `accepted` plan or
+/// whose staged content was rewritten because only some hunks were accepted. Conflicts written
+/// next to the original file count for the original path too.
+pub(crate) fn rejected_paths(
+    proposed: &BTreeMap<PathBuf, Option<Vec<u8>>>,
+    accepted: &ChangePlan,
+    staged: &Path,
+    conflicts: &[PathBuf],
+) -> Result<BTreeSet<PathBuf>> {
+    let accepted: BTreeSet<_> = accepted.changes.iter().map(|change| &change.path).collect();
+    let mut rejected = BTreeSet::new();
+    for (path, content) in proposed {
+        if !accepted.contains(path) || read_optional(&staged.join(path))? != *content {
+            rejected.insert(path.clone());
+        }
+    }
+    for path in conflicts {
+        if rejected.contains(&new_sibling(path)) {
+            rejected.insert(path.clone());
+        }
+    }
+    Ok(rejected)
+}
+
+/// Only keeps the conflicts which are still part of `plan`, eg. after the user rejected some.
+pub(crate) fn retain_conflicts(conflicts: &mut Vec<PathBuf>, plan: &ChangePlan) {
+    let changed: BTreeSet<_> = plan.changes.iter().map(|change| &change.path).collect();
+    conflicts.retain(|path| changed.contains(path) || changed.contains(&new_sibling(path)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Dirs {
+        base: tempfile::TempDir,
+        generated: tempfile::TempDir,
+        project: tempfile::TempDir,
+    }
+
+    fn dirs() -> Result<Dirs> {
+        Ok(Dirs {
+            base: tempfile::tempdir()?,
+            generated: tempfile::tempdir()?,
+            project: tempfile::tempdir()?,
+        })
+    }
+
+    fn merge(dirs: &Dirs) -> Result<Vec<PathBuf>> {
+        merge_generated(dirs.base.path(), dirs.generated.path(), dirs.project.path())
+    }
+
+    #[test]
+    fn test_untouched_files_get_replaced() -> Result<()> {
+        let dirs = dirs()?;
+        fs::write(dirs.base.path().join("a.txt"), "old")?;
+        fs::write(dirs.project.path().join("a.txt"), "old")?;
+        fs::write(dirs.generated.path().join("a.txt"), "new")?;
+        fs::write(dirs.generated.path().join("added.txt"), "added")?;
+
+        assert!(merge(&dirs)?.is_empty());
+        assert_eq!(
+            fs::read_to_string(dirs.project.path().join("a.txt"))?,
+            "new"
+        );
+        assert_eq!(
+            fs::read_to_string(dirs.project.path().join("added.txt"))?,
+            "added"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_changes_are_kept() -> Result<()> {
+        let dirs = dirs()?;
+        fs::write(dirs.base.path().join("a.txt"), "old")?;
+        fs::write(dirs.generated.path().join("a.txt"), "old")?;
+        fs::write(dirs.project.path().join("a.txt"), "mine")?;
+        // removed by the scaffold, but changed by the user
+        fs::write(dirs.base.path().join("b.txt"), "old")?;
+        fs::write(dirs.project.path().join("b.txt"), "mine")?;
+        // removed by the scaffold and untouched
+        fs::write(dirs.base.path().join("c.txt"), "old")?;
+        fs::write(dirs.project.path().join("c.txt"), "old")?;
+
+        assert!(merge(&dirs)?.is_empty());
+        assert_eq!(
+            fs::read_to_string(dirs.project.path().join("a.txt"))?,
+            "mine"
+        );
+        assert_eq!(
+            fs::read_to_string(dirs.project.path().join("b.txt"))?,
+            "mine"
+        );
+        assert!(!dirs.project.path().join("c.txt").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_and_conflict() -> Result<()> {
+        let dirs = dirs()?;
+        fs::write(dirs.base.path().join("clean.txt"), "a\nb\nc\nd\ne\n")?;
+        fs::write(dirs.project.path().join("clean.txt"), "a\nmine\nc\nd\ne\n")?;
+        fs::write(dirs.generated.path().join("clean.txt"), "a\nb\nc\nd\nnew\n")?;
+        fs::write(dirs.base.path().join("conflict.txt"), "a\n")?;
+        fs::write(dirs.project.path().join("conflict.txt"), "mine\n")?;
+        fs::write(dirs.generated.path().join("conflict.txt"), "new\n")?;
+
+        assert_eq!(merge(&dirs)?, vec![PathBuf::from("conflict.txt")]);
+        assert_eq!(
+            fs::read_to_string(dirs.project.path().join("clean.txt"))?,
+            "a\nmine\nc\nd\nnew\n"
+        );
+        let conflicted = fs::read_to_string(dirs.project.path().join("conflict.txt"))?;
+        assert!(conflicted.contains("<<<<<<<"));
+        assert!(conflicted.contains("mine\n"));
+        assert!(conflicted.contains("new\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_binary_conflict_writes_sibling() -> Result<()> {
+        let dirs = dirs()?;
+        fs::write(dirs.base.path().join("logo.png"), [0, 159, 1])?;
+        fs::write(dirs.project.path().join("logo.png"), [0, 159, 2])?;
+        fs::write(dirs.generated.path().join("logo.png"), [0, 159, 3])?;
+
+        assert_eq!(merge(&dirs)?, vec![PathBuf::from("logo.png")]);
+        assert_eq!(fs::read(dirs.project.path().join("logo.png"))?, [0, 159, 2]);
+        assert_eq!(
+            fs::read(dirs.project.path().join("logo.png.kenchiku-new"))?,
+            [0, 159, 3]
+        );
+        Ok(())
+    }
+}
@@ -1,12 +1,28 @@
-use std::{fs, io::ErrorKind, path::PathBuf};
-
-use eyre::Context;
-
-pub(crate) fn move_files_to_destination(
-    source_dir: &PathBuf,
-    dest_dir: &PathBuf,
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    changes::files_equal,
+    conflict::{Resolution, write_merged},
+    transaction::Transaction,
+    update::new_sibling,
+};
+
+/// Decides what happens with a source path whose destination already exists.
+pub(crate) type ResolveFn<'a> = dyn FnMut(&Path, &Path) -> eyre::Result<Resolution> + 'a;
+
+/// Moves all files from `source_dir` to `dest_dir` as part of `transaction`, returning the paths
+/// which were skipped because they already exist and `resolve` decided to leave them for the
+/// user. Identical files and files the user decided to keep are skipped silently. If the transaction is rolled back, all moved files are moved back
+/// and replaced files are restored, so the destination never ends up with a mix of old and new.
+pub(crate) fn move_files(
+    transaction: &mut Transaction,
+    source_dir: &Path,
+    dest_dir: &Path,
     merge_directories: bool,
-    overwrite: bool,
+    resolve: &mut ResolveFn,
 ) -> eyre::Result<Vec<PathBuf>> {
     let mut skipped_paths = Vec::new();
 
@@ -17,32 +33,31 @@ pub(crate) fn move_files_to_destination(
         let dest_path = dest_dir.join(file_name);
 
         if source_path.is_dir() && dest_path.exists() && dest_path.is_dir() && merge_directories {
-            let skipped =
-                move_files_to_destination(&source_path, &dest_path, merge_directories, overwrite)?;
+            let skipped = move_files(
+                transaction,
+                &source_path,
+                &dest_path,
+                merge_directories,
+                resolve,
+            )?;
             skipped_paths.extend(skipped);
-        } else if dest_path.exists() && !overwrite {
-            skipped_paths.push(source_path);
+        } else if !dest_path.exists() {
+            transaction.move_into(&source_path, &dest_path)?;
+        } else if dest_path.is_file() && files_equal(&source_path, &dest_path)? {
+            continue;
         } else {
-            // If overwrite is true and destination exists, remove destination first
-            if dest_path.exists() && overwrite {
-                if dest_path.is_dir() {
-                    fs::remove_dir_all(&dest_path)?;
-                } else {
-                    fs::remove_file(&dest_path)?;
+            match resolve(&source_path, &dest_path)? {
+                Resolution::Overwrite => transaction.move_into(&source_path, &dest_path)?,
+                Resolution::Keep => {}
+                Resolution::Leave => skipped_paths.push(source_path),
+                Resolution::WriteNew => {
+                    transaction.move_into(&source_path, &new_sibling(&dest_path))?
+                }
+                Resolution::Merge => {
+                    write_merged(&source_path, &dest_path)?;
+                    transaction.move_into(&source_path, &dest_path)?;
                 }
             }
-            fs::rename(&source_path, &dest_path)
-                .or_else(|e| {
-                    if e.kind() == ErrorKind::CrossesDevices {
-                        fs::copy(&source_path, &dest_path)?;
-                        fs::remove_file(&source_path)
-                    } else {
-                        Err(e)
-                    }
-                })
-                .wrap_err(format!(
-                    "failed to move file {source_path:?} to {dest_path:?}"
-                ))?;
         }
     }
 
@@ -54,6 +69,31 @@ mod tests {
     use super::*;
     use std::{fs, path::Path};
 
+    fn move_files_to_destination(
+        source_dir: &Path,
+        dest_dir: &Path,
+        merge_directories: bool,
+        resolve: &mut ResolveFn,
+    ) -> eyre::Result<Vec<PathBuf>> {
+        Transaction::run(|transaction| {
+            move_files(
+                transaction,
+                source_dir,
+                dest_dir,
+                merge_directories,
+                resolve,
+            )
+        })
+    }
+
+    fn leave(_: &Path, _: &Path) -> eyre::Result<Resolution> {
+        Ok(Resolution::Leave)
+    }
+
+    fn overwrite(_: &Path, _: &Path) -> eyre::Result<Resolution> {
+        Ok(Resolution::Overwrite)
+    }
+
     fn create_dummy_file(path: &Path, content: &str) -> std::io::Result<()> {
         std::fs::write(path, content)?;
         Ok(())
@@ -69,12 +109,8 @@ mod tests {
 
         let dest_file_path = dest_dir.path().join("test.txt");
 
-        let skipped = move_files_to_destination(
-            &source_dir.path().to_path_buf(),
-            &dest_dir.path().to_path_buf(),
-            false,
-            false,
-        )?;
+        let skipped =
+            move_files_to_destination(source_dir.path(), dest_dir.path(), false, &mut leave)?;
         assert_eq!(skipped.len(), 0);
         assert!(dest_file_path.exists());
         assert!(!source_file_path.exists());
@@ -94,12 +130,8 @@ mod tests {
         let dest_file_path = dest_dir.path().join("test.txt");
         create_dummy_file(&dest_file_path, "destination content")?;
 
-        let skipped = move_files_to_destination(
-            &source_dir.path().to_path_buf(),
-            &dest_dir.path().to_path_buf(),
-            false,
-            false,
-        )?;
+        let skipped =
+            move_files_to_destination(source_dir.path(), dest_dir.path(), false, &mut leave)?;
         assert_eq!(skipped.len(), 1);
         assert_eq!(skipped[0], source_file_path);
         assert!(source_file_path.exists());
@@ -121,12 +153,8 @@ mod tests {
         fs::create_dir(&dest_subdir)?;
         create_dummy_file(&dest_subdir.join("dest.txt"), "dest file")?;
 
-        let skipped = move_files_to_destination(
-            &source_dir.path().to_path_buf(),
-            &dest_dir.path().to_path_buf(),
-            true,
-            false,
-        )?;
+        let skipped =
+            move_files_to_destination(source_dir.path(), dest_dir.path(), true, &mut leave)?;
         assert_eq!(skipped.len(), 0);
 
         assert!(dest_subdir.join("source.txt").exists());
@@ -141,12 +169,8 @@ mod tests {
         let source_dir = tempfile::tempdir()?;
         let dest_dir = tempfile::tempdir()?;
 
-        let skipped = move_files_to_destination(
-            &source_dir.path().to_path_buf(),
-            &dest_dir.path().to_path_buf(),
-            false,
-            false,
-        )?;
+        let skipped =
+            move_files_to_destination(source_dir.path(), dest_dir.path(), false, &mut leave)?;
 
         assert_eq!(skipped.len(), 0);
         Ok(())
@@ -164,12 +188,8 @@ mod tests {
         let dest_dir_path = dest_dir.path().join(existing_dir_name);
         fs::create_dir(&dest_dir_path)?;
 
-        let skipped = move_files_to_destination(
-            &source_dir.path().to_path_buf(),
-            &dest_dir.path().to_path_buf(),
-            false,
-            false,
-        )?;
+        let skipped =
+            move_files_to_destination(source_dir.path(), dest_dir.path(), false, &mut leave)?;
 
         assert_eq!(skipped.len(), 1);
         assert_eq!(skipped[0], source_dir_path);
@@ -190,12 +210,8 @@ mod tests {
         let dest_file_path = dest_dir.path().join("test.txt");
         create_dummy_file(&dest_file_path, "destination content")?;
 
-        let skipped = move_files_to_destination(
-            &source_dir.path().to_path_buf(),
-            &dest_dir.path().to_path_buf(),
-            false,
-            true,
-        )?;
+        let skipped =
+            move_files_to_destination(source_dir.path(), dest_dir.path(), false, &mut overwrite)?;
         assert_eq!(skipped.len(), 0);
         assert!(dest_file_path.exists());
         assert!(!source_file_path.exists());
@@ -217,12 +233,8 @@ mod tests {
         fs::create_dir(&dest_subdir)?;
         create_dummy_file(&dest_subdir.join("dest.txt"), "dest file")?;
 
-        let skipped = move_files_to_destination(
-            &source_dir.path().to_path_buf(),
-            &dest_dir.path().to_path_buf(),
-            true,
-            true,
-        )?;
+        let skipped =
+            move_files_to_destination(source_dir.path(), dest_dir.path(), true, &mut overwrite)?;
         assert_eq!(skipped.len(), 0);
 
         assert!(dest_subdir.join("source.txt").exists());
@@ -231,4 +243,49 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_conflict_resolutions() -> eyre::Result<()> {
+        let source_dir = tempfile::tempdir()?;
+        let dest_dir = tempfile::tempdir()?;
+
+        for name in ["same.txt", "new.txt", "merge.txt"] {
+            create_dummy_file(&dest_dir.path().join(name), "a\nb\n")?;
+        }
+        create_dummy_file(&source_dir.path().join("same.txt"), "a\nb\n")?;
+        create_dummy_file(&source_dir.path().join("new.txt"), "a\nc\n")?;
+        create_dummy_file(&source_dir.path().join("merge.txt"), "a\nc\n")?;
+
+        let mut asked = Vec::new();
+        let skipped = move_files_to_destination(
+            source_dir.path(),
+            dest_dir.path(),
+            true,
+            &mut |source: &Path, _: &Path| {
+                let name = source.file_name().unwrap().to_string_lossy().to_string();
+                asked.push(name.clone());
+                Ok(match name.as_str() {
+                    "new.txt" => Resolution::WriteNew,
+                    _ => Resolution::Merge,
+                })
+            },
+        )?;
+        asked.sort();
+
+        assert!(skipped.is_empty());
+        assert_eq!(asked, vec!["merge.txt", "new.txt"]);
+        assert_eq!(
+            fs::read_to_string(dest_dir.path().join("new.txt"))?,
+            "a\nb\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dest_dir.path().join("new.txt.kenchiku-new"))?,
+            "a\nc\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dest_dir.path().join("merge.txt"))?,
+            "a\n<<<<<<< existing\nb\n=======\nc\n>>>>>>> scaffold\n"
+        );
+        Ok(())
+    }
 }
@@ -46,23 +46,57 @@ re = nil
 ---@type values_global
 values = nil
 
+---@class kenchiku_global
+---@field construct fun(scaffold: string, values: table<string, any>?) Runs the construct function of another scaffold in the working dir.
+---@field patch fun(patch: string, values: table<string, any>?) Runs a patch ("<scaffold>:<patch>") and the patches it requires in the working dir.
+---@field construct_parent fun() Runs the construct function of the scaffold this one extends.
+
+---@type kenchiku_global
+kenchiku = nil
+
 ---@param msg string Log a warning.
 function warn(msg) end
 
 ---@class Value
 ---@field description string Description of value.
----@field type "string"|"number"|"enum"|"bool" Type of the value.
----@field default? string|number|boolean|nil Default value.
----@field choices? table<string> Enum values to select from.
----@field validate? fun(val: any): string|boolean Validation for user input. Return string for error message, otherwise bool.
+---@field type "string"|"number"|"integer"|"float"|"enum"|"bool"|"list"|"multiselect"|"object"|"secret" Type of the value.
+---@field default? string|number|boolean|table<string>|fun(values: table<string, any>): any Default value, a table for list and multiselect, or a function computing it from other values.
+---@field choices? table<string> Enum/multiselect values to select from.
+---@field validate? fun(val: any): string|boolean Validation for user input (for lists every item). Return string for error message, otherwise bool.
+---@field fields? table<string, Value> Nested values of an object.
+---@field min? number Smallest allowed number.
+---@field max? number Largest allowed number.
+---@field step? number Allowed increments, starting at min (or 0).
+---@field when? string|fun(values: table<string, any>): boolean Only relevant if true, either a Lua expression over other values or a function.
 
 ---@class Patch
 ---@field description string Description of what the patch does.
 ---@field run fun() Function which executes the patch.
+---@field applies (fun(): boolean)? Read-only check whether the patch makes sense for the project.
+---@field applied (fun(): boolean)? Read-only check whether the patch was already applied to the project.
+---@field requires table<string>? Patches which run before this one, as "<scaffold>:<patch>" or the name of a patch of the same scaffold.
 ---@field values table<string, Value>? Values this patch requires.
+---@field eager boolean? Ask for all values before run is called.
+---@field order table<string>? Order in which values are asked for up front, the rest follows alphabetically.
+
+---@class CapabilitiesFs
+---@field write table<string>? Directories outside of the output which may be written to (absolute or starting with `~/`).
+
+---@class Capabilities
+---@field exec table<string>? Programs `exec.run` may execute (without a shell).
+---@field fs CapabilitiesFs? Filesystem access outside of the output.
 
 ---@class Scaffold
 ---@field description string Description of what the scaffold does.
----@field construct fun() Function which executes the scaffold.
+---@field version string? Version of the scaffold.
+---@field authors table<string>? Authors of the scaffold.
+---@field tags table<string>? Tags, shown in `kenchiku list`.
+---@field homepage string? Where to find the scaffold.
+---@field requires_kenchiku string? Semver range of Kenchiku versions the scaffold works with (eg. ">=0.2").
+---@field extends string? Scaffold to inherit values, patches and files from, by name or relative path.
+---@field construct fun()? Function which executes the scaffold, only optional when extending another scaffold.
 ---@field patches table<string, Patch>? Patches this scaffold exposes.
 ---@field values table<string, Value>? Values this scaffold requires.
+---@field capabilities Capabilities? Restricts the scaffold to what's declared here.
+---@field eager boolean? Ask for all values before construct is called.
+---@field order table<string>? Order in which values are asked for up front, the rest follows alphabetically.